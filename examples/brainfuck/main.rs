@@ -11,14 +11,14 @@ OP     -> (comma)
 OP     -> (left)
 OP     -> (right)
 
->lb    -> '\['
->rb    -> '\]'
->pluss -> '\+'
+>lb    -> '['
+>rb    -> ']'
+>pluss -> '+'
 >minus -> '-'
->dot   -> '\.'
+>dot   -> '.'
 >comma -> ','
->left  -> '[<]'
->right -> '[>]'
+>left  -> r'[<]'
+>right -> r'[>]'
 "#;
 
 fn main() {
@@ -34,11 +34,13 @@ fn main() {
     };
 
     let g = get_parsing_grammar();
-    let ast = g.parse(&GRAMMAR.into()).unwrap();
-    let gp = parse_ast_grammar(ast).with_options(ParseOptions {
+    let ast = g.parse(GRAMMAR).unwrap();
+    let gp = parse_ast_grammar(ast).unwrap().with_options(ParseOptions {
         ignore_newline: true,
         ignore_whitespace: true,
         bubble_intermediate: true,
+        flatten_repeated: true,
+        ..ParseOptions::default()
     });
     let ast = gp.parse(&input).unwrap();
     Interpreter::run(256, &ast);
@@ -55,15 +57,15 @@ impl Interpreter {
             tape: vec![0; width],
             ptr: 0,
         }
-        .interpret(&ast);
+        .interpret(ast);
     }
     fn interpret(&mut self, ast: &AST) {
         match ast {
-            AST::Node { t, children } => {
+            AST::Node { t, children, .. } => {
                 let mut children = children.iter();
                 match t.as_str() {
                     "START" => {
-                        while let Some(next) = children.next() {
+                        for next in children {
                             self.interpret(next);
                         }
                     }
@@ -0,0 +1,97 @@
+use gtp::*;
+use std::env;
+
+const GRAMMAR: &str = r#"
+START     -> ( SUM )
+SUM       -> ( PRODUCT ( OPA PRODUCT )* )
+PRODUCT   -> ( NUMBER ( OPB NUMBER )* )
+NUMBER    -> ( num )
+NUMBER    -> ( minus num )
+OPA       -> ( ( pluss ) | ( minus ) )
+OPB       -> ( ( multiply ) | ( divide ) )
+
+>pluss    -> '+'
+>minus    -> '-'
+>multiply -> 'x'
+>divide   -> '/'
+>num      -> r'\d+'
+"#;
+
+fn main() {
+    let mut args = env::args();
+    args.next();
+    let input = args.next().unwrap_or_else(|| "1+2x3-10/2".into());
+
+    let g = Grammar::compile(GRAMMAR)
+        .unwrap()
+        .with_options(ParseOptions {
+            ignore_whitespace: true,
+            ..ParseOptions::default()
+        });
+    let result = g
+        .parse(&input)
+        .map_err(|e| e.located(&input).to_string())
+        .and_then(|ast| evaluate(&ast));
+    match result {
+        Ok(value) => println!("{}", value),
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Value of the expression in `ast`, a node of the grammar above without bubbled nodes
+fn evaluate(ast: &AST) -> Result<i64, String> {
+    let (t, children) = match ast {
+        AST::Node { t, children, .. } => (t.as_str(), children),
+        AST::Leaf { t, .. } => return Err(format!("unexpected leaf '{}'", t)),
+    };
+    match (t, children.as_slice()) {
+        ("START", [sum]) => evaluate(sum),
+        // the first operand, followed by pairs of operators and operands
+        ("SUM", [first, rest @ ..]) | ("PRODUCT", [first, rest @ ..]) => {
+            let mut value = evaluate(first)?;
+            for pair in rest.chunks(2) {
+                let operand = evaluate(&pair[1])?;
+                value = apply(operator(&pair[0])?, value, operand)?;
+            }
+            Ok(value)
+        }
+        ("NUMBER", [num]) => number(num),
+        ("NUMBER", [_minus, num]) => number(num).map(|n| -n),
+        (t, _) => Err(format!("unexpected node '{}'", t)),
+    }
+}
+
+/// Type and text of the leaf in an `OPA` or `OPB` node
+fn operator(ast: &AST) -> Result<(&str, &str), String> {
+    match ast {
+        AST::Node { children, .. } => match children.as_slice() {
+            [AST::Leaf { t, raw, .. }] => Ok((t, raw)),
+            _ => Err("expected a single operator".into()),
+        },
+        ast => Err(format!("expected an operator, found '{}'", ast.get_t())),
+    }
+}
+
+fn apply((operator, raw): (&str, &str), a: i64, b: i64) -> Result<i64, String> {
+    let value = match operator {
+        "pluss" => a.checked_add(b),
+        "minus" => a.checked_sub(b),
+        "multiply" => a.checked_mul(b),
+        "divide" if b == 0 => return Err(format!("division by zero in {} {} {}", a, raw, b)),
+        "divide" => a.checked_div(b),
+        op => return Err(format!("unknown operator '{}'", op)),
+    };
+    value.ok_or_else(|| format!("overflow in {} {} {}", a, raw, b))
+}
+
+fn number(ast: &AST) -> Result<i64, String> {
+    match ast {
+        AST::Leaf { raw, .. } => raw
+            .parse()
+            .map_err(|_| format!("number '{}' is too large", raw)),
+        ast => Err(format!("expected a number, found '{}'", ast.get_t())),
+    }
+}
@@ -0,0 +1,482 @@
+use crate::parsing::*;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+
+/// Version of the cache file format, files with another version are recompiled
+const FORMAT: u64 = 1;
+
+impl Grammar {
+    /// Like `compile`, but reuses the grammar compiled from the same source in an earlier call,
+    /// stored as a json file in `cache_dir` named after an FNV-1a hash of the source, which is
+    /// the same across builds and platforms. Returns the
+    /// grammar and whether it was loaded from the cache. Cache files that can't be read or were
+    /// written for another source or version of the crate are replaced, and failing to write
+    /// the cache only logs a warning.
+    pub fn compile_cached<P: AsRef<Path>>(
+        source: &str,
+        cache_dir: P,
+    ) -> ParseResult<(Grammar, bool)> {
+        let path = cache_dir
+            .as_ref()
+            .join(format!("{:016x}.json", fnv1a(source.as_bytes())));
+
+        let cached = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .filter(|value| {
+                value["format"] == FORMAT
+                    && value["version"] == env!("CARGO_PKG_VERSION")
+                    && value["source"] == source
+            });
+        if let Some(value) = cached {
+            match from_json(&value["grammar"]) {
+                Ok(grammar) => return Ok((grammar, true)),
+                Err(err) => log::warn!("ignoring grammar cache {}: {}", path.display(), err),
+            }
+        }
+
+        let grammar = Grammar::compile(source)?;
+        let written = to_json(&grammar).and_then(|g| {
+            let value = json!({
+                "format": FORMAT,
+                "version": env!("CARGO_PKG_VERSION"),
+                "source": source,
+                "grammar": g,
+            });
+            // written next to the cache file and renamed over it, so concurrent compiles
+            // never read a partially written file
+            let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+            fs::create_dir_all(cache_dir.as_ref())
+                .and_then(|_| fs::write(&tmp, value.to_string()))
+                .and_then(|_| fs::rename(&tmp, &path))
+                .map_err(|e| {
+                    let _ = fs::remove_file(&tmp);
+                    e.to_string()
+                })
+        });
+        if let Err(err) = written {
+            log::warn!("could not cache grammar in {}: {}", path.display(), err);
+        }
+        Ok((grammar, false))
+    }
+}
+
+/// 64 bit FNV-1a, unlike `DefaultHasher` its output is stable between Rust versions
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn to_json(g: &Grammar) -> Result<Value, String> {
+    let atoms = g
+        .atoms
+        .iter()
+        .map(|atom| match atom {
+            Atom::Simple { name } => Ok(json!({ "name": name, "simple": true })),
+            Atom::Matched { name, m } => match m.pattern() {
+                Some(pattern) => Ok(json!({ "name": name, "regex": pattern })),
+                None => Err(format!("atom '{}' has a custom matcher", name)),
+            },
+            Atom::Integer { name, min, max } => Ok(json!({
+                "name": name,
+                "integer": [min.to_string(), max.to_string()],
+            })),
+            Atom::Balanced { name, open, close } => {
+                Ok(json!({ "name": name, "balanced": [open, close] }))
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let rules = g
+        .rules
+        .iter()
+        .map(|r| {
+            json!({
+                "name": r.name,
+                "order": r.order,
+                "label": r.label,
+                "production": symbol_to_json(&r.production),
+            })
+        })
+        .collect::<Vec<_>>();
+    let modes = g
+        .modes
+        .iter()
+        .map(|mode| {
+            let atoms = mode
+                .atoms
+                .iter()
+                .map(|a| match &a.action {
+                    None => json!([a.name]),
+                    Some(ModeAction::Push(m)) => json!([a.name, "push", m]),
+                    Some(ModeAction::Pop) => json!([a.name, "pop"]),
+                })
+                .collect::<Vec<_>>();
+            json!({ "name": mode.name, "atoms": atoms })
+        })
+        .collect::<Vec<_>>();
+    let skip = g
+        .skip
+        .iter()
+        .map(|s| match s {
+            Skip::Matched(re) => json!({ "regex": re.as_str() }),
+            Skip::Delimited { start, end } => json!({ "delimited": [start, end] }),
+        })
+        .collect::<Vec<_>>();
+    let folds = g
+        .folds
+        .iter()
+        .map(|(rule, fold)| {
+            let fold = match fold {
+                Fold::Left => "left",
+                Fold::Right => "right",
+            };
+            json!([rule, fold])
+        })
+        .collect::<Vec<_>>();
+    let o = &g.options;
+    Ok(json!({
+        "rules": rules,
+        "atoms": atoms,
+        "modes": modes,
+        "skip": skip,
+        "aliases": g.aliases,
+        "decode_escapes": g.decode_escapes,
+        "lexing": match g.lexing {
+            Lexing::FirstMatch => "first",
+            Lexing::LongestMatch => "longest",
+        },
+        "transparent": g.transparent,
+        "hidden": g.hidden,
+        "recover": g.recover,
+        "counted": g.counted,
+        "trailing_separators": g.trailing_separators,
+        "folds": folds,
+        "options": {
+            "ignore_whitespace": o.ignore_whitespace,
+            "ignore_newline": o.ignore_newline,
+            "bubble_intermediate": o.bubble_intermediate,
+            "lookahead": o.lookahead,
+            "indentation": o.indentation,
+            "record_trivia": o.record_trivia,
+            "ignore_chars": o.ignore_chars,
+            "flatten_repeated": o.flatten_repeated,
+            "max_input_bytes": o.max_input_bytes,
+            "tab_width": o.tab_width,
+            "newlines": o.newlines,
+            "record_production_index": o.record_production_index,
+        },
+    }))
+}
+
+fn symbol_to_json(s: &SymbolType) -> Value {
+    match s {
+        SymbolType::Symbol(Symbol::Lexem { t, include_raw }) => {
+            json!({ "lexem": t, "raw": include_raw })
+        }
+        SymbolType::Symbol(Symbol::AST(rule)) => json!({ "rule": rule }),
+        SymbolType::Symbol(Symbol::EOF) => json!("$"),
+        SymbolType::Symbol(Symbol::Any) => json!("_"),
+        SymbolType::Group(g) => Value::Array(g.iter().map(symbol_to_json).collect()),
+        SymbolType::Optional(o) => json!({ "optional": symbol_to_json(o) }),
+        SymbolType::Repeated(r) => json!({ "repeated": symbol_to_json(r) }),
+        SymbolType::Switch(a, b) => json!({ "switch": [symbol_to_json(a), symbol_to_json(b)] }),
+    }
+}
+
+fn from_json(v: &Value) -> ParseResult<Grammar> {
+    let invalid = |what: &str| ParseError::Grammar(format!("invalid cached {}", what));
+    let string = |v: &Value, what: &str| v.as_str().map(String::from).ok_or_else(|| invalid(what));
+    let array = |v: &Value, what: &str| v.as_array().cloned().ok_or_else(|| invalid(what));
+    let strings = |v: &Value, what: &str| {
+        array(v, what)?
+            .iter()
+            .map(|s| string(s, what))
+            .collect::<ParseResult<Vec<_>>>()
+    };
+    let pair = |v: &Value, what: &str| match strings(v, what)?.as_slice() {
+        [a, b] => Ok((a.clone(), b.clone())),
+        _ => Err(invalid(what)),
+    };
+    let regex =
+        |v: &Value, what: &str| -> ParseResult<Regex> { Ok(Regex::new(&string(v, what)?)?) };
+
+    let mut g = Grammar::default();
+    for r in array(&v["rules"], "rules")? {
+        g.rules.push(Rule {
+            name: string(&r["name"], "rule name")?,
+            order: r["order"].as_u64().ok_or_else(|| invalid("rule order"))? as usize,
+            label: match &r["label"] {
+                Value::Null => None,
+                label => Some(string(label, "rule label")?),
+            },
+            production: symbol_from_json(&r["production"])?,
+        });
+    }
+    for a in array(&v["atoms"], "atoms")? {
+        let name = string(&a["name"], "atom name")?;
+        g.atoms.push(if a.get("simple").is_some() {
+            Atom::Simple { name }
+        } else if let Some(pattern) = a.get("regex") {
+            Atom::Matched {
+                name,
+                m: Box::new(regex(pattern, "atom regex")?),
+            }
+        } else if let Some(range) = a.get("integer") {
+            let (min, max) = pair(range, "integer range")?;
+            let parse = |n: String| n.parse().map_err(|_| invalid("integer range"));
+            Atom::Integer {
+                name,
+                min: parse(min)?,
+                max: parse(max)?,
+            }
+        } else {
+            let (open, close) = pair(&a["balanced"], "atom")?;
+            Atom::Balanced { name, open, close }
+        });
+    }
+    for m in array(&v["modes"], "modes")? {
+        let mut atoms = Vec::new();
+        for a in array(&m["atoms"], "mode atoms")? {
+            let atom = strings(&a, "mode atom")?;
+            let action = match atom.get(1).map(String::as_str) {
+                None => None,
+                Some("pop") => Some(ModeAction::Pop),
+                Some("push") => Some(ModeAction::Push(
+                    atom.get(2).cloned().ok_or_else(|| invalid("mode action"))?,
+                )),
+                Some(_) => return Err(invalid("mode action")),
+            };
+            atoms.push(ModeAtom {
+                name: atom[0].clone(),
+                action,
+            });
+        }
+        g.modes.push(LexerMode {
+            name: string(&m["name"], "mode name")?,
+            atoms,
+        });
+    }
+    for s in array(&v["skip"], "skip")? {
+        g.skip.push(match s.get("regex") {
+            Some(pattern) => Skip::Matched(regex(pattern, "skip regex")?),
+            None => {
+                let (start, end) = pair(&s["delimited"], "skip")?;
+                Skip::Delimited { start, end }
+            }
+        });
+    }
+    for (atom, alias) in v["aliases"].as_object().unwrap_or(&Map::new()) {
+        g.aliases.insert(atom.clone(), string(alias, "alias")?);
+    }
+    g.decode_escapes = strings(&v["decode_escapes"], "decode_escapes")?;
+    g.lexing = match v["lexing"].as_str() {
+        Some("first") => Lexing::FirstMatch,
+        Some("longest") => Lexing::LongestMatch,
+        _ => return Err(invalid("lexing")),
+    };
+    g.transparent = strings(&v["transparent"], "transparent")?;
+    g.hidden = strings(&v["hidden"], "hidden")?;
+    for r in array(&v["recover"], "recover")? {
+        g.recover.push(pair(&r, "recover")?);
+    }
+    g.counted = strings(&v["counted"], "counted")?;
+    g.trailing_separators = strings(&v["trailing_separators"], "trailing_separators")?;
+    for f in array(&v["folds"], "folds")? {
+        let (rule, fold) = pair(&f, "fold")?;
+        let fold = match fold.as_str() {
+            "left" => Fold::Left,
+            "right" => Fold::Right,
+            _ => return Err(invalid("fold")),
+        };
+        g.folds.push((rule, fold));
+    }
+
+    let o = &v["options"];
+    let flag = |name: &str| o[name].as_bool().ok_or_else(|| invalid(name));
+    let number = |name: &str| {
+        o[name]
+            .as_u64()
+            .map(|n| n as usize)
+            .ok_or_else(|| invalid(name))
+    };
+    g.options = ParseOptions {
+        ignore_whitespace: flag("ignore_whitespace")?,
+        ignore_newline: flag("ignore_newline")?,
+        bubble_intermediate: flag("bubble_intermediate")?,
+        lookahead: number("lookahead")?,
+        indentation: flag("indentation")?,
+        record_trivia: flag("record_trivia")?,
+        ignore_chars: strings(&o["ignore_chars"], "ignore_chars")?
+            .iter()
+            .filter_map(|c| c.chars().next())
+            .collect(),
+        flatten_repeated: flag("flatten_repeated")?,
+        max_input_bytes: number("max_input_bytes")?,
+        tab_width: number("tab_width")?,
+        newlines: flag("newlines")?,
+        record_production_index: flag("record_production_index")?,
+    };
+    Ok(g)
+}
+
+fn symbol_from_json(v: &Value) -> ParseResult<SymbolType> {
+    let invalid = || ParseError::Grammar("invalid cached production".into());
+    let name = |v: &Value| v.as_str().map(String::from).ok_or_else(invalid);
+    Ok(match v {
+        Value::String(s) if s == "$" => SymbolType::Symbol(Symbol::EOF),
+        Value::String(s) if s == "_" => SymbolType::any(),
+        Value::Array(g) => SymbolType::Group(
+            g.iter()
+                .map(symbol_from_json)
+                .collect::<ParseResult<Vec<_>>>()?,
+        ),
+        Value::Object(o) => {
+            if let Some(t) = o.get("lexem") {
+                SymbolType::Symbol(Symbol::Lexem {
+                    t: name(t)?,
+                    include_raw: o.get("raw").and_then(Value::as_bool).unwrap_or(true),
+                })
+            } else if let Some(rule) = o.get("rule") {
+                SymbolType::Symbol(Symbol::AST(name(rule)?))
+            } else if let Some(s) = o.get("optional") {
+                SymbolType::Optional(Box::new(symbol_from_json(s)?))
+            } else if let Some(s) = o.get("repeated") {
+                SymbolType::Repeated(Box::new(symbol_from_json(s)?))
+            } else {
+                match o.get("switch").and_then(Value::as_array).map(Vec::as_slice) {
+                    Some([a, b]) => SymbolType::Switch(
+                        Box::new(symbol_from_json(a)?),
+                        Box::new(symbol_from_json(b)?),
+                    ),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_cached_reuses_grammar() {
+        let dir = std::env::temp_dir().join(format!("gtp-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let source = r#"
+            @options ignore_whitespace
+            @fold left SUM
+            START -> ( SUM $ )
+            SUM -> ( NUMBER ( ( ( pluss ) | ( minus ) ) NUMBER )* )
+            NUMBER -> ( num )
+            ANY -> ( _ )?
+            >pluss -> '+'
+            >minus -> '-'
+            >num -> r'\d+'
+        "#;
+
+        let (compiled, cached) = Grammar::compile_cached(source, &dir).unwrap();
+        assert!(!cached);
+        let (loaded, cached) = Grammar::compile_cached(source, &dir).unwrap();
+        assert!(cached);
+        assert!(loaded.structurally_equal(&compiled));
+        assert!(loaded.options.ignore_whitespace);
+        let input = "1 + 2 - 3".to_string();
+        assert_eq!(
+            serde_json::to_string(&loaded.parse(&input).unwrap()).unwrap(),
+            serde_json::to_string(&compiled.parse(&input).unwrap()).unwrap()
+        );
+
+        let changed = source.replace("'+'", "'*'");
+        let (recompiled, cached) = Grammar::compile_cached(&changed, &dir).unwrap();
+        assert!(!cached);
+        assert!(!recompiled.structurally_equal(&compiled));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let source = r#"
+            @options ignore_whitespace ignore_newline bubble record_trivia indentation
+            @options flatten_repeated newlines record_production_index lookahead=2
+            @options ignore_chars=;
+            @skip '/*' '*/'
+            @skip '#[^\n]*'
+            @decode string
+            @transparent ITEM
+            @count LIST
+            @trailing_separators LIST
+            @recover lb rb
+            @fold left SUM
+            @fold right POW
+            @lexing longest-match
+            START -> ( ( ( LIST ) | ( SUM ) | ( POW ) | ( BLOCK ) )? $ )
+            LIST -> ( lb ( ITEM comma )* rb )
+            ITEM -> ( ( string ) | ( _ ) )
+            SUM -> sum: ( num ( pluss num )* )
+            POW -> ( num ( pow num )* )
+            BLOCK -> ( block small )
+            >lb -> '['
+            >rb -> ']'
+            >comma -> ','
+            >pluss as plus -> '+'
+            >pow -> '^'
+            >num -> r'\d+'
+            >string -> r'"[^"]*"'
+            >comment hidden -> r'//[^\n]*'
+            >block -> balanced('{ }')
+            >small -> int('-5..=5')
+        "#;
+        let mut g = Grammar::compile(source).unwrap();
+        g.atoms.push(Atom::Simple { name: "x".into() });
+        g.rules.push(Rule {
+            name: "RAW".into(),
+            order: g.rules.len(),
+            label: None,
+            production: SymbolType::Symbol(Symbol::Lexem {
+                t: "num".into(),
+                include_raw: false,
+            }),
+        });
+        g.modes = vec![
+            LexerMode {
+                name: "default".into(),
+                atoms: vec![
+                    ModeAtom {
+                        name: "lb".into(),
+                        action: Some(ModeAction::Push("list".into())),
+                    },
+                    ModeAtom {
+                        name: "num".into(),
+                        action: None,
+                    },
+                ],
+            },
+            LexerMode {
+                name: "list".into(),
+                atoms: vec![ModeAtom {
+                    name: "rb".into(),
+                    action: Some(ModeAction::Pop),
+                }],
+            },
+        ];
+        g.options.max_input_bytes = 1024;
+        g.options.tab_width = 4;
+
+        let loaded = from_json(&to_json(&g).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", g));
+        assert_eq!(loaded.to_string(), g.to_string());
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}
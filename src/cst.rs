@@ -0,0 +1,146 @@
+use crate::parsing::*;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Concrete syntax tree, keeping every character of the input. Unlike `AST`, all lexems are
+/// kept, and the characters ignored between them are kept as trivia.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CST {
+    Node {
+        t: String,
+        children: Vec<CST>,
+    },
+    /// A lexem, also the ones not included in an `AST`
+    Token {
+        t: String,
+        raw: String,
+    },
+    /// Characters ignored between lexems, like whitespace and skipped comments
+    Trivia {
+        raw: String,
+    },
+}
+
+impl CST {
+    /// The input the tree was parsed from
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        let mut stack = alloc::vec![self];
+        while let Some(cst) = stack.pop() {
+            match cst {
+                CST::Node { children, .. } => stack.extend(children.iter().rev()),
+                CST::Token { raw, .. } | CST::Trivia { raw } => source.push_str(raw),
+            }
+        }
+        source
+    }
+}
+
+struct CstBuilder {
+    /// Types and children of the nodes being built, innermost last
+    stack: Vec<(String, Vec<CST>)>,
+    root: Option<CST>,
+}
+
+impl CstBuilder {
+    fn push(&mut self, cst: CST) {
+        match self.stack.last_mut() {
+            Some((_, children)) => children.push(cst),
+            None => self.root = Some(cst),
+        }
+    }
+}
+
+impl ParseCallbacks for CstBuilder {
+    fn on_enter(&mut self, rule: &str) {
+        self.stack.push((rule.into(), Vec::new()));
+    }
+    fn on_lexem(&mut self, lexem: &Lexem) {
+        let trivia = |t: &Option<Trivia>| t.as_ref().map(|t| CST::Trivia { raw: t.raw.clone() });
+        let token = CST::Token {
+            t: lexem.t.clone(),
+            raw: lexem.raw.clone(),
+        };
+        for cst in trivia(&lexem.leading)
+            .into_iter()
+            .chain(Some(token))
+            .chain(trivia(&lexem.trailing))
+        {
+            self.push(cst);
+        }
+    }
+    fn on_exit(&mut self, _rule: &str) {
+        let (t, children) = self.stack.pop().unwrap();
+        self.push(CST::Node { t, children });
+    }
+}
+
+impl Grammar {
+    /// Parse the input into a concrete syntax tree, where `to_source` gives back the input
+    /// exactly, like for formatters. Nodes aren't bubbled or flattened.
+    pub fn parse_cst(&self, input: &str) -> ParseResult<CST> {
+        let mut builder = CstBuilder {
+            stack: Vec::new(),
+            root: None,
+        };
+        self.parse_with_trivia(input, &mut builder)?;
+        Ok(builder.root.unwrap())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless_round_trip() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all
+            @skip '/*' '*/'
+            START -> ( LIST )*
+            LIST -> ( lpar ( num ( comma num )* )? rpar )
+            >lpar -> '('
+            >rpar -> ')'
+            >comma -> ','
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        let inputs = [
+            "(1,2)",
+            "  ( 1 , 2 )  ",
+            "\n(1,\n  2)\n\n(3)\n",
+            "/* lists */ (1 /* one */, 22)(  )",
+        ];
+        for input in inputs.iter() {
+            assert_eq!(g.parse_cst(input).unwrap().to_source(), *input);
+        }
+
+        let token = |t: &str, raw: &str| CST::Token {
+            t: t.into(),
+            raw: raw.into(),
+        };
+        let trivia = |raw: &str| CST::Trivia { raw: raw.into() };
+        assert_eq!(
+            g.parse_cst(" (1, 2) ").unwrap(),
+            CST::Node {
+                t: "START".into(),
+                children: vec![CST::Node {
+                    t: "LIST".into(),
+                    children: vec![
+                        trivia(" "),
+                        token("lpar", "("),
+                        token("num", "1"),
+                        token("comma", ","),
+                        trivia(" "),
+                        token("num", "2"),
+                        token("rpar", ")"),
+                        trivia(" "),
+                    ]
+                }]
+            }
+        );
+        assert!(g.parse_cst("(1 2)").is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::grammar::{atom_definition, production_text};
+use crate::parsing::*;
+use std::fmt;
+
+/// Difference of an item between two grammars
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change<T> {
+    Added(T),
+    Removed(T),
+    Changed { old: T, new: T },
+}
+
+/// Rules and atoms that differ between two grammars, see `Grammar::diff`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GrammarDiff {
+    /// Rule names with their productions, as written in a text grammar
+    pub rules: Vec<(String, Change<Vec<String>>)>,
+    /// Atom names with what they match, as written in a text grammar
+    pub atoms: Vec<(String, Change<String>)>,
+}
+
+impl GrammarDiff {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.atoms.is_empty()
+    }
+}
+
+impl Grammar {
+    /// Rules and atoms added, removed or changed in `other` compared to this grammar. Regex
+    /// atoms are compared by their source. Options, skipped patterns and lexer modes are not
+    /// compared.
+    pub fn diff(&self, other: &Grammar) -> GrammarDiff {
+        let productions = |g: &Grammar, name: &str| {
+            g.rules_named(name)
+                .iter()
+                .map(|r| production_text(r))
+                .collect::<Vec<_>>()
+        };
+        let mut names: Vec<&String> = Vec::new();
+        for name in self.rules.iter().chain(other.rules.iter()).map(|r| &r.name) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        let rules = names
+            .into_iter()
+            .filter_map(|name| {
+                change(productions(self, name), productions(other, name))
+                    .map(|change| (name.clone(), change))
+            })
+            .collect();
+
+        let definition = |g: &Grammar, name: &str| {
+            g.atoms
+                .iter()
+                .find(|a| a.name() == name)
+                .map(atom_definition)
+        };
+        let mut atoms = Vec::new();
+        let names = self
+            .atoms
+            .iter()
+            .chain(other.atoms.iter())
+            .map(|a| a.name());
+        for name in names {
+            if atoms.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            let change = match (definition(self, name), definition(other, name)) {
+                (Some(old), Some(new)) if old != new => Change::Changed { old, new },
+                (Some(old), None) => Change::Removed(old),
+                (None, Some(new)) => Change::Added(new),
+                _ => continue,
+            };
+            atoms.push((name.clone(), change));
+        }
+
+        GrammarDiff { rules, atoms }
+    }
+}
+
+/// How a rule changed from the productions `old` to `new`, if it did
+fn change(old: Vec<String>, new: Vec<String>) -> Option<Change<Vec<String>>> {
+    match (old.is_empty(), new.is_empty()) {
+        _ if old == new => None,
+        (true, _) => Some(Change::Added(new)),
+        (_, true) => Some(Change::Removed(old)),
+        _ => Some(Change::Changed { old, new }),
+    }
+}
+
+/// Lines of `old` and `new` prefixed with ' ' if they are in both, '-' if they are only in
+/// `old` and '+' if they are only in `new`, using their longest common subsequence
+fn diff_lines<'a>(old: &'a [String], new: &'a [String]) -> Vec<(char, &'a String)> {
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', &old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || i < old.len() && common[i + 1][j] >= common[i][j + 1] {
+            lines.push(('-', &old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', &new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Written like `git diff` of the text grammars, with `-` before removed lines and `+`
+/// before added ones. Unchanged productions of changed rules are included for context.
+impl fmt::Display for GrammarDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, change) in self.rules.iter() {
+            let lines = match change {
+                Change::Added(new) => diff_lines(&[], new),
+                Change::Removed(old) => diff_lines(old, &[]),
+                Change::Changed { old, new } => diff_lines(old, new),
+            };
+            for (sign, production) in lines {
+                writeln!(f, "{}{:<15} -> {}", sign, name, production)?;
+            }
+        }
+        for (name, change) in self.atoms.iter() {
+            match change {
+                Change::Added(new) => writeln!(f, "+>{:<14} -> {}", name, new)?,
+                Change::Removed(old) => writeln!(f, "->{:<14} -> {}", name, old)?,
+                Change::Changed { old, new } => {
+                    writeln!(f, "->{:<14} -> {}", name, old)?;
+                    writeln!(f, "+>{:<14} -> {}", name, new)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::RAW_GRAMMAR_SUM;
+
+    #[test]
+    fn diff_added_operator() {
+        let old = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        assert!(old.diff(&old).is_empty());
+
+        let new = Grammar::compile(
+            &RAW_GRAMMAR_SUM
+                .replace("( divide ) )", "( divide ) | ( modulo ) )")
+                .replace(">num -> r'\\d+'", ">num -> r'\\d+'\n>modulo -> '%'"),
+        )
+        .unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.rules,
+            vec![(
+                "OPB".to_string(),
+                Change::Changed {
+                    old: vec!["( ( multiply ) | ( divide ) )".to_string()],
+                    new: vec!["( ( multiply ) | ( divide ) | ( modulo ) )".to_string()],
+                }
+            )]
+        );
+        assert_eq!(
+            diff.atoms,
+            vec![("modulo".to_string(), Change::Added("'%'".to_string()))]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "-OPB             -> ( ( multiply ) | ( divide ) )\n\
+             +OPB             -> ( ( multiply ) | ( divide ) | ( modulo ) )\n\
+             +>modulo         -> '%'\n"
+        );
+    }
+    #[test]
+    fn diff_rules_and_atoms() {
+        let old = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        let new = Grammar::compile(
+            &RAW_GRAMMAR_SUM
+                .replace("NUMBER -> ( minus num )\n", "NUMBER -> ( pluss num )\n")
+                .replace("OPA -> ( ( pluss ) | ( minus ) )", "")
+                .replace("r'\\d+'", "r'[0-9]+'")
+                .replace("OPA", "pluss"),
+        )
+        .unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.to_string(),
+            "-SUM             -> ( PRODUCT ( OPA PRODUCT )* )\n\
+             +SUM             -> ( PRODUCT ( pluss PRODUCT )* )\n \
+             NUMBER          -> ( num )\n\
+             -NUMBER          -> ( minus num )\n\
+             +NUMBER          -> ( pluss num )\n\
+             -OPA             -> ( ( pluss ) | ( minus ) )\n\
+             ->num            -> r'\\d+'\n\
+             +>num            -> r'[0-9]+'\n"
+        );
+    }
+}
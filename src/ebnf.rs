@@ -0,0 +1,327 @@
+use crate::grammar::{balanced_delimiters, int_range, unescape_literal};
+use crate::parsing::*;
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Text grammar for the EBNF dialect read by `Grammar::from_ebnf`
+const EBNF_GRAMMAR: &str = r#"
+    @options ignore_all
+    @skip '(*' '*)'
+    START -> ( DEF )*
+    DEF -> ( name eq ALTS semi )
+    ALTS -> ( ALT ( bar ALT )* )
+    ALT -> ( TERM ( ( comma )? TERM )* )
+    TERM -> ( name )
+    TERM -> ( string )
+    TERM -> ( special )
+    TERM -> optional: ( lbrack ALTS rbrack )
+    TERM -> repeated: ( lbrace ALTS rbrace )
+    TERM -> group: ( lparen ALTS rparen )
+
+    >eq -> '='
+    >semi -> ';'
+    >bar -> '|'
+    >comma -> ','
+    >lbrack -> '['
+    >rbrack -> ']'
+    >lbrace -> '{'
+    >rbrace -> '}'
+    >lparen -> '('
+    >rparen -> ')'
+    >name -> r'[\p{Alphabetic}_][\p{Alphabetic}\d_]*'
+    >string -> r'"[^"]*"|\x27[^\x27]*\x27'
+    >special -> r'\?[^?]*\?'
+"#;
+
+impl Grammar {
+    /// Parse a grammar written in EBNF. Like in text grammars parsing starts with the rule
+    /// `START`. Definitions are rules, with one production for each top level alternative,
+    /// except definitions of a single terminal string or special sequence, which are atoms.
+    /// Terminal strings used in rules become atoms named by their text. Special sequences are
+    /// `? regex PATTERN ?`, `? integer MIN..=MAX ?`, `? balanced OPEN CLOSE ?`,
+    /// `? end of input ?` and `? any token ?`, the last two only in rules.
+    ///
+    /// Atoms matching a fixed text are tried longest first, before all other atoms.
+    pub fn from_ebnf(source: &str) -> ParseResult<Grammar> {
+        static META: OnceLock<Grammar> = OnceLock::new();
+        let meta = META.get_or_init(|| Grammar::compile(EBNF_GRAMMAR).unwrap());
+        let ast = meta.parse(source)?;
+        let defs = children(ast)
+            .into_iter()
+            .map(|def| {
+                let mut c = children(def).into_iter();
+                let name = leaf(c.next().unwrap());
+                (name, alternatives(c.nth(1).unwrap()))
+            })
+            .collect::<Vec<_>>();
+
+        let mut atoms = Vec::new();
+        let mut bodies = Vec::new();
+        for (name, alts) in defs {
+            match single_terminal(&alts) {
+                Some(term) => atoms.push(terminal_atom(name, term)?),
+                None => bodies.push((name, alts)),
+            }
+        }
+
+        let mut grammar = Grammar {
+            atoms,
+            ..Grammar::default()
+        };
+        for (name, alts) in bodies {
+            for alt in alts {
+                let production = sequence(&mut grammar, alt)?;
+                grammar.rules.push(Rule {
+                    name: name.clone(),
+                    production,
+                    order: grammar.rules.len(),
+                    label: None,
+                });
+            }
+        }
+        // literals before patterns, and longer literals before their prefixes
+        grammar.atoms.sort_by_key(|a| match literal_text(a) {
+            Some(text) => -(text.len() as isize),
+            None => 1,
+        });
+        Ok(grammar)
+    }
+}
+
+fn children(ast: AST) -> Vec<AST> {
+    match ast {
+        AST::Node { children, .. } => children,
+        AST::Leaf { .. } => vec![ast],
+    }
+}
+
+fn leaf(ast: AST) -> String {
+    match ast {
+        AST::Leaf { raw, .. } => raw,
+        AST::Node { t, .. } => panic!("expected a leaf, found node '{}'", t),
+    }
+}
+
+/// The `ALT` nodes of an `ALTS` node, without the `|` between them
+fn alternatives(ast: AST) -> Vec<AST> {
+    children(ast)
+        .into_iter()
+        .filter(|a| a.get_t() != "bar")
+        .collect()
+}
+
+/// The only term of a definition, if it is a terminal string or special sequence
+fn single_terminal(alts: &[AST]) -> Option<&AST> {
+    match alts {
+        [AST::Node { children, .. }] => match children.as_slice() {
+            [AST::Node { t, children, .. }] if t == "TERM" => match children.as_slice() {
+                [term @ AST::Leaf { t, raw, .. }]
+                    if t == "string"
+                        || t == "special" && !is_end_of_input(raw) && !is_any_token(raw) =>
+                {
+                    Some(term)
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_end_of_input(special: &str) -> bool {
+    unquote(special).trim() == "end of input"
+}
+
+fn is_any_token(special: &str) -> bool {
+    unquote(special).trim() == "any token"
+}
+
+/// Text of a quoted terminal string or special sequence
+fn unquote(raw: &str) -> &str {
+    &raw[1..raw.len() - 1]
+}
+
+fn terminal_atom(name: String, term: &AST) -> ParseResult<Atom> {
+    let (t, raw) = match term {
+        AST::Leaf { t, raw, .. } => (t, raw),
+        AST::Node { .. } => unreachable!(),
+    };
+    if t == "string" {
+        return Ok(Atom::Matched {
+            m: Box::new(Regex::new(&regex::escape(unquote(raw))).unwrap()),
+            name,
+        });
+    }
+    let special = unquote(raw).trim();
+    match special.split_once(' ') {
+        Some(("regex", pattern)) => Ok(Atom::Matched {
+            m: Box::new(Regex::new(pattern.trim()).map_err(|e| {
+                ParseError::Grammar(format!("invalid regex in atom '{}': {}", name, e))
+            })?),
+            name,
+        }),
+        Some(("integer", range)) => {
+            let (min, max) = int_range(range.trim())?;
+            Ok(Atom::Integer { name, min, max })
+        }
+        Some(("balanced", delimiters)) => {
+            let (open, close) = balanced_delimiters(&name, delimiters)?;
+            Ok(Atom::Balanced { name, open, close })
+        }
+        _ => Err(ParseError::Grammar(format!(
+            "unknown special sequence '{}' in atom '{}'",
+            raw, name
+        ))),
+    }
+}
+
+/// The fixed text matched by an atom, if it only matches one text
+fn literal_text(atom: &Atom) -> Option<String> {
+    match atom {
+        Atom::Simple { name } => Some(name.clone()),
+        Atom::Matched { m, .. } => m.pattern().and_then(unescape_literal),
+        Atom::Integer { .. } | Atom::Balanced { .. } => None,
+    }
+}
+
+/// Production for the `ALT` node `ast`, a sequence of terms
+fn sequence(grammar: &mut Grammar, ast: AST) -> ParseResult<SymbolType> {
+    let terms = children(ast)
+        .into_iter()
+        .filter(|a| a.get_t() != "comma")
+        .map(|term| term_symbol(grammar, term))
+        .collect::<ParseResult<Vec<_>>>()?;
+    Ok(SymbolType::Group(terms))
+}
+
+/// Productions for the `ALTS` node `ast`, joined with `Switch`
+fn switch(grammar: &mut Grammar, ast: AST) -> ParseResult<SymbolType> {
+    let mut alts = alternatives(ast)
+        .into_iter()
+        .map(|alt| sequence(grammar, alt))
+        .collect::<ParseResult<Vec<_>>>()?;
+    let mut s = alts.pop().unwrap();
+    while let Some(alt) = alts.pop() {
+        s = SymbolType::switch(alt, s);
+    }
+    Ok(s)
+}
+
+fn term_symbol(grammar: &mut Grammar, ast: AST) -> ParseResult<SymbolType> {
+    let (t, children) = match ast {
+        AST::Node { t, children, .. } => (t, children),
+        AST::Leaf { .. } => unreachable!(),
+    };
+    let mut c = children.into_iter();
+    match t.as_str() {
+        "optional" => Ok(SymbolType::opt(switch(grammar, c.nth(1).unwrap())?)),
+        "repeated" => Ok(SymbolType::rep(switch(grammar, c.nth(1).unwrap())?)),
+        "group" => switch(grammar, c.nth(1).unwrap()),
+        _ => match c.next().unwrap() {
+            AST::Leaf { t, raw, .. } if t == "name" => {
+                if grammar.atoms.iter().any(|a| *a.name() == raw) {
+                    Ok(SymbolType::leaf(raw))
+                } else {
+                    Ok(SymbolType::rule(raw))
+                }
+            }
+            AST::Leaf { t, raw, .. } if t == "string" => {
+                let text = unquote(&raw);
+                // reuse an atom defined with the same text
+                let existing = grammar
+                    .atoms
+                    .iter()
+                    .find(|a| literal_text(a).as_deref() == Some(text));
+                let name = match existing {
+                    Some(atom) => atom.name().clone(),
+                    None => {
+                        grammar.atoms.push(Atom::Simple { name: text.into() });
+                        text.into()
+                    }
+                };
+                Ok(SymbolType::leaf(name))
+            }
+            AST::Leaf { raw, .. } if is_end_of_input(&raw) => Ok(SymbolType::eof()),
+            AST::Leaf { raw, .. } if is_any_token(&raw) => Ok(SymbolType::any()),
+            other => Err(ParseError::Grammar(format!(
+                "special sequence {} can only define an atom",
+                leaf(other)
+            ))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::RAW_GRAMMAR_SUM;
+
+    fn same_parses(a: &Grammar, b: &Grammar, inputs: &[&str]) {
+        for input in inputs {
+            let parse = |g: &Grammar| {
+                g.parse(input)
+                    .map(|ast| serde_json::to_string(&ast).unwrap())
+                    .ok()
+            };
+            assert_eq!(parse(a), parse(b), "different parses of '{}'", input);
+        }
+    }
+
+    #[test]
+    fn ebnf_matches_native_grammar() {
+        let native = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        let ebnf = Grammar::from_ebnf(
+            r#"
+            (* sums of products *)
+            START = SUM ;
+            SUM = PRODUCT , { OPA , PRODUCT } ;
+            PRODUCT = NUMBER { OPB NUMBER } ;
+            NUMBER = num | minus , num ;
+            OPA = ( pluss | minus ) ;
+            OPB = multiply | divide ;
+            pluss = "+" ;
+            minus = '-' ;
+            multiply = "x" ;
+            divide = "/" ;
+            num = ? regex \d+ ? ;
+            "#,
+        )
+        .unwrap();
+        assert!(ebnf.validate().is_empty());
+        let inputs = ["1", "1+2x3", "-1x-2/3-4", "1+", "+1", "1x2x", ""];
+        same_parses(&native, &ebnf, &inputs);
+
+        let exported = Grammar::from_ebnf(&native.to_ebnf()).unwrap();
+        same_parses(&native, &exported, &inputs);
+    }
+    #[test]
+    fn ebnf_inline_terminals() {
+        let g = Grammar::from_ebnf(
+            r#"
+            START = LIST , ? end of input ? ;
+            LIST = "[" , [ ITEM , { "," , ITEM } ] , "]" ;
+            ITEM = num | "==" | "=" | LIST ;
+            num = ? integer u8 ? ;
+            "#,
+        )
+        .unwrap();
+        assert_eq!(g.atom_names(), vec!["==", "[", ",", "]", "=", "num"]);
+        let ast = g.parse("[1,==,=,[]]").unwrap();
+        assert_eq!(
+            ast.leaves().map(|(_, raw)| raw).collect::<Vec<_>>(),
+            vec!["[", "1", ",", "==", ",", "=", ",", "[", "]", "]"]
+        );
+        assert!(g.parse("[256]").is_err());
+
+        assert!(matches!(
+            Grammar::from_ebnf("A = ? regex ( ? ;"),
+            Err(ParseError::Grammar(_))
+        ));
+        assert!(matches!(
+            Grammar::from_ebnf("A = B ? regex x ? ;"),
+            Err(ParseError::Grammar(_))
+        ));
+        assert!(Grammar::from_ebnf("A = B").is_err());
+    }
+}
@@ -1,90 +1,502 @@
 use crate::parsing::*;
 use regex::Regex;
 use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::fmt;
 
 impl fmt::Display for Grammar {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = [
+            ("ignore_whitespace", self.options.ignore_whitespace),
+            ("ignore_newline", self.options.ignore_newline),
+            ("bubble", self.options.bubble_intermediate),
+            ("record_trivia", self.options.record_trivia),
+            ("indentation", self.options.indentation),
+            ("flatten_repeated", self.options.flatten_repeated),
+            ("newlines", self.options.newlines),
+            (
+                "record_production_index",
+                self.options.record_production_index,
+            ),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>();
+        if self.options.lookahead > 1 {
+            options.push(format!("lookahead={}", self.options.lookahead));
+        }
+        if !self.options.ignore_chars.is_empty() {
+            let chars = self.options.ignore_chars.iter().collect::<String>();
+            options.push(format!("ignore_chars={}", chars));
+        }
+        if !options.is_empty() {
+            writeln!(f, "@options {}", options.join(" "))?;
+        }
+        for skip in self.skip.iter() {
+            match skip {
+                Skip::Matched(m) => writeln!(f, "@skip '{}'", m.as_str())?,
+                Skip::Delimited { start, end } => writeln!(f, "@skip '{}' '{}'", start, end)?,
+            }
+        }
+        if !self.decode_escapes.is_empty() {
+            writeln!(f, "@decode {}", self.decode_escapes.join(" "))?;
+        }
+        if !self.transparent.is_empty() {
+            writeln!(f, "@transparent {}", self.transparent.join(" "))?;
+        }
+        if !self.counted.is_empty() {
+            writeln!(f, "@count {}", self.counted.join(" "))?;
+        }
+        if !self.trailing_separators.is_empty() {
+            let rules = self.trailing_separators.join(" ");
+            writeln!(f, "@trailing_separators {}", rules)?;
+        }
+        if !self.recover.is_empty() {
+            let pairs = self
+                .recover
+                .iter()
+                .map(|(open, close)| format!("{} {}", open, close))
+                .collect::<Vec<_>>();
+            writeln!(f, "@recover {}", pairs.join(" "))?;
+        }
+        for (fold, name) in [(Fold::Left, "left"), (Fold::Right, "right")].iter() {
+            let rules = self
+                .folds
+                .iter()
+                .filter(|(_, f)| f == fold)
+                .map(|(rule, _)| rule.as_str())
+                .collect::<Vec<_>>();
+            if !rules.is_empty() {
+                writeln!(f, "@fold {} {}", name, rules.join(" "))?;
+            }
+        }
+        if self.lexing == Lexing::LongestMatch {
+            writeln!(f, "@lexing longest-match")?;
+        }
+        if !options.is_empty()
+            || !self.skip.is_empty()
+            || !self.decode_escapes.is_empty()
+            || !self.transparent.is_empty()
+            || !self.counted.is_empty()
+            || !self.trailing_separators.is_empty()
+            || !self.recover.is_empty()
+            || !self.folds.is_empty()
+            || self.lexing != Lexing::default()
+        {
+            writeln!(f)?;
+        }
         for rule in self.rules.iter() {
-            write!(f, "{:<15} -> {}", rule.name, rule.production)?;
-
-            write!(f, "\n")?;
+            writeln!(f, "{:<15} -> {}", rule.name, production_text(rule))?;
         }
-        write!(f, "\n")?;
+        writeln!(f)?;
         for atom in self.atoms.iter() {
-            match atom {
-                Atom::Simple { name } => {
-                    write!(f, ">{:<14} -> '{}'", name, name)?;
-                }
-                Atom::Matched { name, m } => {
-                    write!(f, ">{:<14} -> '{:?}'", name, m)?;
-                }
+            let mut label = match self.aliases.get(atom.name()) {
+                Some(alias) => format!("{} as {}", atom.name(), alias),
+                None => atom.name().clone(),
+            };
+            if self.hidden.contains(atom.name()) {
+                label.push_str(" hidden");
             }
-
-            write!(f, "\n")?;
+            writeln!(f, ">{:<14} -> {}", label, atom_definition(atom))?;
         }
 
         Ok(())
     }
 }
 
-impl fmt::Display for SymbolType {
+/// The production of `rule` as written in a text grammar, with its label
+pub(crate) fn production_text(rule: &Rule) -> String {
+    let label = match &rule.label {
+        Some(label) => format!("{}: ", label),
+        None => String::new(),
+    };
+    match rule.production {
+        SymbolType::Optional(_) | SymbolType::Repeated(_) => {
+            format!("{}{}", label, rule.production)
+        }
+        _ => format!("{}{}", label, AsGroup(&rule.production)),
+    }
+}
+
+/// What `atom` matches, as written in a text grammar
+pub(crate) fn atom_definition(atom: &Atom) -> String {
+    match atom {
+        Atom::Simple { name } => quoted(&escape_literal(name)),
+        Atom::Matched { m, .. } => match (m.pattern(), m.pattern().and_then(unescape_literal)) {
+            (_, Some(literal)) => quoted(&escape_literal(&literal)),
+            (Some(pattern), None) => format!("r{}", quoted(pattern)),
+            (None, None) => "<custom matcher>".into(),
+        },
+        Atom::Integer { min, max, .. } => format!("int('{}..={}')", min, max),
+        Atom::Balanced { open, close, .. } => format!("balanced('{} {}')", open, close),
+    }
+}
+
+/// `text` as an atom literal, in double quotes if it contains a single quote. Text with both
+/// quotes has its single quotes written as `\x27`, which literals and regexes both decode.
+fn quoted(text: &str) -> String {
+    if !text.contains('\'') {
+        return format!("'{}'", text);
+    }
+    if !text.contains('"') {
+        return format!("\"{}\"", text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    let mut backslash = false;
+    for c in text.chars() {
+        if c == '\'' {
+            // an escaped quote already has its backslash
+            escaped.push_str(if backslash { "x27" } else { "\\x27" });
+            backslash = false;
+        } else {
+            backslash = c == '\\' && !backslash;
+            escaped.push(c);
+        }
+    }
+    format!("'{}'", escaped)
+}
+
+/// `literal` with backslashes and control characters escaped, the inverse of decoding the
+/// escapes of an atom literal
+fn escape_literal(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The literal string matched by `regex`, if it matches nothing else
+pub(crate) fn unescape_literal(regex: &str) -> Option<String> {
+    let mut literal = String::new();
+    let mut chars = regex.chars();
+    while let Some(c) = chars.next() {
+        literal.push(if c == '\\' { chars.next()? } else { c });
+    }
+    Some(literal).filter(|l| regex::escape(l) == regex)
+}
+
+impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            SymbolType::Symbol(i) => {
-                write!(
-                    f,
-                    "{}",
-                    match i {
-                        Symbol::Lexem { t, .. } => t,
-                        Symbol::AST(t) => t,
-                    }
-                )?;
+            ParseError::Lexem(pos, msg)
+            | ParseError::Input(pos, msg)
+            | ParseError::Token {
+                start: pos, msg, ..
+            } => write!(f, "{} (at byte {})", msg, pos),
+            ParseError::NoMatch(msg) | ParseError::Grammar(msg) | ParseError::Convert(msg) => {
+                write!(f, "{}", msg)
+            }
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::Regex(e) => write!(f, "{}", e),
+            ParseError::InputTooLarge { size, max } => write!(
+                f,
+                "input is {} bytes, more than the maximum of {} bytes",
+                size, max
+            ),
+            ParseError::File {
+                path,
+                position: Some((line, column)),
+                error,
+            } => match &**error {
+                ParseError::Lexem(_, msg)
+                | ParseError::Input(_, msg)
+                | ParseError::Token { msg, .. } => {
+                    write!(f, "{}:{}:{}: {}", path, line, column, msg)
+                }
+                error => write!(f, "{}:{}:{}: {}", path, line, column, error),
+            },
+            ParseError::File { path, error, .. } => write!(f, "{}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(&**e),
+            ParseError::Regex(e) => Some(e),
+            // the message of `error` is part of this one, so skip to its source
+            ParseError::File { error, .. } => error.source(),
+            _ => None,
+        }
+    }
+}
+
+impl ParseError {
+    /// Display the error as `line:column: message` if it has a position in `input`, like
+    /// errors in files
+    pub fn located<'a>(&'a self, input: &'a str) -> LocatedError<'a> {
+        LocatedError { error: self, input }
+    }
+}
+
+/// `ParseError` with the input it was found in, see `ParseError::located`
+pub struct LocatedError<'a> {
+    error: &'a ParseError,
+    input: &'a str,
+}
+
+impl fmt::Display for LocatedError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error {
+            ParseError::Lexem(pos, msg)
+            | ParseError::Input(pos, msg)
+            | ParseError::Token {
+                start: pos, msg, ..
+            } => {
+                let (line, column) = line_column(self.input, *pos);
+                write!(f, "{}:{}: {}", line, column, msg)
             }
+            error => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> Self {
+        ParseError::Io(std::sync::Arc::new(e))
+    }
+}
+
+impl From<regex::Error> for ParseError {
+    fn from(e: regex::Error) -> Self {
+        ParseError::Regex(e)
+    }
+}
+
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolType::Symbol(i) => match i {
+                Symbol::Lexem { t, .. } => write!(f, "{}", t)?,
+                Symbol::AST(t) => write!(f, "{}", t)?,
+                Symbol::EOF => write!(f, "$")?,
+                Symbol::Any => write!(f, "_")?,
+            },
             SymbolType::Group(g) => {
                 write!(f, "( ")?;
                 for p in g.iter() {
-                    write!(f, "{} ", p)?;
+                    match p {
+                        SymbolType::Switch(..) if g.len() > 1 => write!(f, "( {} ) ", p)?,
+                        _ => write!(f, "{} ", p)?,
+                    }
                 }
                 write!(f, ")")?;
             }
             SymbolType::Optional(o) => {
-                write!(f, "{}?", o)?;
+                write!(f, "{}?", AsGroup(o))?;
             }
             SymbolType::Repeated(m) => {
-                write!(f, "{}*", m)?;
-            }
-            SymbolType::Switch(a, b) => {
-                write!(f, "{} | {}", a, b)?;
+                write!(f, "{}*", AsGroup(m))?;
             }
+            // switches are parsed left to right, so only the right side needs parentheses
+            SymbolType::Switch(a, b) => match **a {
+                SymbolType::Switch(..) => write!(f, "{} | {}", a, AsGroup(b))?,
+                _ => write!(f, "{} | {}", AsGroup(a), AsGroup(b))?,
+            },
         }
         Ok(())
     }
 }
 
+/// Displays a symbol type in parentheses unless it already is a group, as text grammars only
+/// allow groups in some places
+struct AsGroup<'a>(&'a SymbolType);
+
+impl fmt::Display for AsGroup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            SymbolType::Group(_) => write!(f, "{}", self.0),
+            s => write!(f, "( {} )", s),
+        }
+    }
+}
+
+/// EBNF for a symbol type, alternatives are parenthesized when they are part of a sequence
+fn ebnf(s: &SymbolType, in_sequence: bool) -> String {
+    match s {
+        SymbolType::Symbol(Symbol::Lexem { t, .. }) | SymbolType::Symbol(Symbol::AST(t)) => {
+            t.clone()
+        }
+        SymbolType::Symbol(Symbol::EOF) => "? end of input ?".into(),
+        SymbolType::Symbol(Symbol::Any) => "? any token ?".into(),
+        SymbolType::Group(g) if in_sequence && g.len() > 1 => format!("( {} )", ebnf(s, false)),
+        SymbolType::Group(g) => g
+            .iter()
+            .map(|s| ebnf(s, g.len() > 1))
+            .collect::<Vec<_>>()
+            .join(" , "),
+        SymbolType::Switch(..) if in_sequence => format!("( {} )", ebnf(s, false)),
+        SymbolType::Switch(a, b) => format!("{} | {}", ebnf(a, false), ebnf(b, false)),
+        SymbolType::Optional(o) => format!("[ {} ]", ebnf(o, false)),
+        SymbolType::Repeated(r) => format!("{{ {} }}", ebnf(r, false)),
+    }
+}
+
+/// EBNF terminal string for `text`, quoted with whichever quote it doesn't contain
+fn ebnf_terminal(text: &str) -> String {
+    if text.contains('"') {
+        format!("'{}'", text)
+    } else {
+        format!("\"{}\"", text)
+    }
+}
+
 impl Serialize for AST {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         match self {
-            AST::Node { t, children } => {
-                let mut map = serializer.serialize_map(Some(2))?;
+            AST::Node {
+                t,
+                children,
+                count,
+                production,
+            } => {
+                let len = 2 + count.iter().count() + production.iter().count();
+                let mut map = serializer.serialize_map(Some(len))?;
                 map.serialize_entry("type", t)?;
                 map.serialize_entry("children", children)?;
+                if let Some(count) = count {
+                    map.serialize_entry("count", count)?;
+                }
+                if let Some(production) = production {
+                    map.serialize_entry("production", production)?;
+                }
                 map.end()
             }
-            AST::Leaf { t, raw } => {
-                let mut map = serializer.serialize_map(Some(2))?;
+            AST::Leaf { t, raw, captures } => {
+                let len = if captures.is_empty() { 2 } else { 3 };
+                let mut map = serializer.serialize_map(Some(len))?;
                 map.serialize_entry("type", t)?;
                 map.serialize_entry("raw", raw)?;
+                if !captures.is_empty() {
+                    map.serialize_entry("captures", captures)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SerializeOptions {
+    /// Types of leaves serialized as just their raw string
+    pub compact_leaves: Vec<String>,
+    /// Serialize all leaves as just their raw string
+    pub compact_all: bool,
+}
+
+/// Serializes the `AST` like its own `Serialize` impl, but using `options`
+pub struct SerializeWith<'a> {
+    pub ast: &'a AST,
+    pub options: &'a SerializeOptions,
+}
+
+impl Serialize for SerializeWith<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.ast {
+            AST::Node {
+                t,
+                children,
+                count,
+                production,
+            } => {
+                let children = children
+                    .iter()
+                    .map(|ast| SerializeWith {
+                        ast,
+                        options: self.options,
+                    })
+                    .collect::<Vec<_>>();
+                let len = 2 + count.iter().count() + production.iter().count();
+                let mut map = serializer.serialize_map(Some(len))?;
+                map.serialize_entry("type", t)?;
+                map.serialize_entry("children", &children)?;
+                if let Some(count) = count {
+                    map.serialize_entry("count", count)?;
+                }
+                if let Some(production) = production {
+                    map.serialize_entry("production", production)?;
+                }
                 map.end()
             }
+            AST::Leaf { t, raw, .. }
+                if self.options.compact_all || self.options.compact_leaves.contains(t) =>
+            {
+                serializer.serialize_str(raw)
+            }
+            AST::Leaf { .. } => self.ast.serialize(serializer),
+        }
+    }
+}
+
+/// Tokens of the text grammar syntax that can be replaced, to write grammars in a familiar
+/// style like `SUM ::= ...`, see `Grammar::compile_with_syntax`
+#[derive(Debug, Clone)]
+pub struct MetaSyntax {
+    /// Separates names from their definition, `->` by default
+    pub arrow: String,
+    /// Starts atom definitions, `>` by default
+    pub atom: String,
+    /// Separates alternatives, `|` by default
+    pub alternative: String,
+    /// Opens a group, `(` by default
+    pub open: String,
+    /// Closes a group, `)` by default
+    pub close: String,
+    /// Repeats a group, `*` by default
+    pub repeat: String,
+    /// Makes a group optional, `?` by default
+    pub optional: String,
+}
+
+impl Default for MetaSyntax {
+    fn default() -> Self {
+        MetaSyntax {
+            arrow: "->".into(),
+            atom: ">".into(),
+            alternative: "|".into(),
+            open: "(".into(),
+            close: ")".into(),
+            repeat: "*".into(),
+            optional: "?".into(),
+        }
+    }
+}
+
+/// Atom named `name` matching `text`
+fn meta_token(name: &str, text: &str) -> Atom {
+    if name == text {
+        Atom::Simple { name: name.into() }
+    } else {
+        Atom::Matched {
+            name: name.into(),
+            m: Box::new(Regex::new(&regex::escape(text)).unwrap()),
         }
     }
 }
 
 pub fn get_parsing_grammar() -> Grammar {
+    get_parsing_grammar_with(&MetaSyntax::default())
+}
+
+/// The grammar of text grammars, with the tokens in `syntax`
+pub fn get_parsing_grammar_with(syntax: &MetaSyntax) -> Grammar {
     macro_rules! L {
         ( $t:expr ) => {
             Symbol::Lexem {
@@ -106,49 +518,93 @@ pub fn get_parsing_grammar() -> Grammar {
             ignore_newline: true,
             ignore_whitespace: true,
             bubble_intermediate: false,
+            ..ParseOptions::default()
         },
         rules: vec![
             Rule {
                 name: "START".into(),
+                order: 0,
+                label: None,
                 production: ST::Symbol(S::AST("DOC".into())),
             },
             Rule {
                 name: "DOC".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Switch(
                         Box::new(ST::Symbol(S::AST("EXP".into()))),
-                        Box::new(ST::Symbol(S::AST("ATOM".into()))),
+                        Box::new(ST::Switch(
+                            Box::new(ST::Symbol(S::AST("ATOM".into()))),
+                            Box::new(ST::Symbol(L!("DIRECTIVE".into(), true))),
+                        )),
                     ),
                     ST::Repeated(Box::new(ST::Switch(
                         Box::new(ST::Symbol(S::AST("EXP".into()))),
-                        Box::new(ST::Symbol(S::AST("ATOM".into()))),
+                        Box::new(ST::Switch(
+                            Box::new(ST::Symbol(S::AST("ATOM".into()))),
+                            Box::new(ST::Symbol(L!("DIRECTIVE".into(), true))),
+                        )),
                     ))),
                 ]),
             },
             Rule {
                 name: "ATOM".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Symbol(L!(">".into())),
                     ST::Symbol(L!("ALPHA".into(), true)),
-                    ST::Symbol(L!("->".into())),
-                    ST::Symbol(L!("'".into())),
+                    ST::Repeated(Box::new(ST::Symbol(L!("ALPHA".into(), true)))),
+                    ST::Symbol(L!("->".into(), true)),
+                    ST::Optional(Box::new(ST::Symbol(L!("ALPHA".into(), true)))),
                     ST::Switch(
-                        Box::new(ST::Symbol(L!("ALPHA".into(), true))),
-                        Box::new(ST::Symbol(L!("LITERAL".into(), true))),
+                        Box::new(ST::Symbol(S::AST("ATOM_ARG".into()))),
+                        Box::new(ST::Switch(
+                            Box::new(ST::Group(vec![
+                                ST::Symbol(L!("'".into())),
+                                ST::Symbol(L!("LITERAL".into(), true)),
+                                ST::Symbol(L!("'".into())),
+                            ])),
+                            Box::new(ST::Group(vec![
+                                ST::Symbol(L!("\"".into())),
+                                ST::Symbol(L!("DLITERAL".into(), true)),
+                                ST::Symbol(L!("\"".into())),
+                            ])),
+                        )),
                     ),
+                ]),
+            },
+            Rule {
+                name: "ATOM_ARG".into(),
+                order: 0,
+                label: None,
+                production: ST::Group(vec![
+                    ST::Symbol(L!("(".into())),
+                    ST::Symbol(L!("'".into())),
+                    ST::Symbol(L!("LITERAL".into(), true)),
                     ST::Symbol(L!("'".into())),
+                    ST::Symbol(L!(")".into())),
                 ]),
             },
             Rule {
                 name: "EXP".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Symbol(L!("ALPHA".into(), true)),
                     ST::Symbol(L!("->".into())),
+                    ST::Optional(Box::new(ST::Group(vec![
+                        ST::Symbol(L!("ALPHA".into(), true)),
+                        ST::Symbol(L!(":".into())),
+                    ]))),
                     ST::Symbol(S::AST("PROD_GROUP".into())),
                 ]),
             },
             Rule {
                 name: "PROD".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Symbol(Symbol::AST("PROD_TERM".into())),
                     ST::Repeated(Box::new(ST::Switch(
@@ -159,174 +615,910 @@ pub fn get_parsing_grammar() -> Grammar {
             },
             Rule {
                 name: "PROD".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Symbol(Symbol::AST("PROD_GROUP".into())),
-                    ST::Repeated(Box::new(ST::Group(vec![
-                        ST::Symbol(L!("|".into(), true)),
-                        ST::Symbol(S::AST("PROD_GROUP".into())),
-                    ]))),
+                    ST::Switch(
+                        Box::new(ST::Repeated(Box::new(ST::Group(vec![
+                            ST::Symbol(L!("|".into(), true)),
+                            ST::Symbol(S::AST("PROD_GROUP".into())),
+                        ])))),
+                        Box::new(ST::Repeated(Box::new(ST::Switch(
+                            Box::new(ST::Symbol(S::AST("PROD_TERM".into()))),
+                            Box::new(ST::Symbol(S::AST("PROD_GROUP".into()))),
+                        )))),
+                    ),
                 ]),
             },
             Rule {
                 name: "PROD_TERM".into(),
-                production: ST::Symbol(L!("ALPHA".into(), true)),
+                order: 0,
+                label: None,
+                production: ST::Switch(
+                    Box::new(ST::Symbol(L!("ALPHA".into(), true))),
+                    Box::new(ST::Symbol(L!("$".into(), true))),
+                ),
             },
             Rule {
                 name: "PROD_GROUP".into(),
+                order: 0,
+                label: None,
                 production: ST::Group(vec![
                     ST::Symbol(L!("(".into())),
                     ST::Symbol(S::AST("PROD".into())),
                     ST::Symbol(L!(")".into())),
                     ST::Optional(Box::new(ST::Switch(
                         Box::new(ST::Symbol(L!("*".into(), true))),
-                        Box::new(ST::Symbol(L!("?".into(), true))),
+                        Box::new(ST::Switch(
+                            Box::new(ST::Symbol(L!("?".into(), true))),
+                            Box::new(ST::Symbol(S::AST("QUANTIFIER".into()))),
+                        )),
                     ))),
                 ]),
             },
+            Rule {
+                name: "QUANTIFIER".into(),
+                order: 0,
+                label: None,
+                production: ST::Group(vec![
+                    ST::Symbol(L!("{".into())),
+                    ST::Symbol(L!("NUMBER".into(), true)),
+                    ST::Optional(Box::new(ST::Group(vec![
+                        ST::Symbol(L!(",".into(), true)),
+                        ST::Optional(Box::new(ST::Symbol(L!("NUMBER".into(), true)))),
+                    ]))),
+                    ST::Symbol(L!("}".into())),
+                ]),
+            },
         ],
         atoms: vec![
-            Atom::Simple { name: "|".into() },
-            Atom::Simple { name: "(".into() },
-            Atom::Simple { name: ")".into() },
-            Atom::Simple { name: "*".into() },
-            Atom::Simple { name: "?".into() },
-            Atom::Simple { name: "->".into() },
-            Atom::Simple { name: ">".into() },
+            meta_token("|", &syntax.alternative),
+            meta_token("(", &syntax.open),
+            meta_token(")", &syntax.close),
+            meta_token("*", &syntax.repeat),
+            meta_token("?", &syntax.optional),
+            meta_token("->", &syntax.arrow),
+            meta_token(">", &syntax.atom),
             Atom::Simple { name: "'".into() },
+            Atom::Simple { name: "\"".into() },
+            Atom::Simple { name: "$".into() },
+            Atom::Simple { name: ":".into() },
+            Atom::Simple { name: "{".into() },
+            Atom::Simple { name: "}".into() },
+            Atom::Simple { name: ",".into() },
+            Atom::Matched {
+                name: "DIRECTIVE".into(),
+                m: Box::new(Regex::new(r"@[^\n]*").unwrap()),
+            },
             Atom::Matched {
                 name: "NUMBER".into(),
-                m: Regex::new(r"\d+").unwrap(),
+                m: Box::new(Regex::new(r"\d+").unwrap()),
             },
             Atom::Matched {
                 name: "ALPHA".into(),
-                m: Regex::new(r"\p{Alphabetic}+").unwrap(),
+                m: Box::new(Regex::new(r"[\p{Alphabetic}_]+").unwrap()),
             },
             Atom::Matched {
                 name: "LITERAL".into(),
-                m: Regex::new(r"[^']+").unwrap(),
+                m: Box::new(Regex::new(r"[^']+").unwrap()),
+            },
+            Atom::Matched {
+                name: "DLITERAL".into(),
+                m: Box::new(Regex::new(r#"[^"]+"#).unwrap()),
+            },
+        ],
+        // quoted literals are lexed in their own mode, so they can contain any other token
+        modes: vec![
+            LexerMode {
+                name: "default".into(),
+                atoms: [
+                    "|",
+                    "(",
+                    ")",
+                    "*",
+                    "?",
+                    "->",
+                    ">",
+                    "$",
+                    ":",
+                    "{",
+                    "}",
+                    ",",
+                    "DIRECTIVE",
+                    "NUMBER",
+                    "ALPHA",
+                ]
+                .iter()
+                .map(|a| ModeAtom {
+                    name: a.to_string(),
+                    action: None,
+                })
+                .chain(vec![
+                    ModeAtom {
+                        name: "'".into(),
+                        action: Some(ModeAction::Push("literal".into())),
+                    },
+                    ModeAtom {
+                        name: "\"".into(),
+                        action: Some(ModeAction::Push("dliteral".into())),
+                    },
+                ])
+                .collect(),
+            },
+            LexerMode {
+                name: "literal".into(),
+                atoms: vec![
+                    ModeAtom {
+                        name: "'".into(),
+                        action: Some(ModeAction::Pop),
+                    },
+                    ModeAtom {
+                        name: "LITERAL".into(),
+                        action: None,
+                    },
+                ],
+            },
+            LexerMode {
+                name: "dliteral".into(),
+                atoms: vec![
+                    ModeAtom {
+                        name: "\"".into(),
+                        action: Some(ModeAction::Pop),
+                    },
+                    ModeAtom {
+                        name: "DLITERAL".into(),
+                        action: None,
+                    },
+                ],
             },
         ],
+        ..Grammar::default()
     }
 }
 
 impl AST {
-    fn assume_node(self) -> (String, Vec<AST>) {
+    fn assume_node(self) -> ParseResult<(String, Vec<AST>)> {
         match self {
-            AST::Node { t, children } => (t, children),
-            _ => panic!(),
+            AST::Node { t, children, .. } => Ok((t, children)),
+            AST::Leaf { t, .. } => Err(invalid_tree(&format!("a node, found leaf '{}'", t))),
         }
     }
-    fn assume_leaf(self) -> (String, String) {
+    fn assume_leaf(self) -> ParseResult<(String, String)> {
         match self {
-            AST::Leaf { t, raw } => (t, raw),
-            _ => panic!(),
+            AST::Leaf { t, raw, .. } => Ok((t, raw)),
+            AST::Node { t, .. } => Err(invalid_tree(&format!("a leaf, found node '{}'", t))),
+        }
+    }
+}
+
+/// Error for a syntax tree not given by the grammar of text grammars, which
+/// `parse_ast_grammar` can't convert
+fn invalid_tree(expected: &str) -> ParseError {
+    ParseError::Grammar(format!(
+        "invalid grammar syntax tree, expected {}",
+        expected
+    ))
+}
+
+/// The next child of a node in a grammar syntax tree
+fn next_child<I: Iterator<Item = AST>>(children: &mut I) -> ParseResult<AST> {
+    children
+        .next()
+        .ok_or_else(|| invalid_tree("another child node"))
+}
+
+impl Grammar {
+    /// Parse and compile a grammar from its text representation. Fails at the first rule or
+    /// atom used in a production that isn't declared.
+    pub fn compile(source: &str) -> ParseResult<Grammar> {
+        Grammar::compile_with_syntax(source, &MetaSyntax::default())
+    }
+    /// Like `compile`, for a grammar written with other tokens than the default ones
+    pub fn compile_with_syntax(source: &str, syntax: &MetaSyntax) -> ParseResult<Grammar> {
+        if source.trim().is_empty() {
+            return Err(ParseError::Grammar(
+                "empty grammar, expected at least one rule".into(),
+            ));
+        }
+        let meta = get_parsing_grammar_with(syntax);
+        let ast = meta.parse(source)?;
+        let grammar = parse_ast_grammar(ast)?;
+        resolve_references(&grammar, &meta, source)?;
+        for problem in grammar.anchored_atoms() {
+            log::warn!("{}", problem);
+        }
+        Ok(grammar)
+    }
+    /// Export the grammar as EBNF, with one definition per rule and atom. Atoms matching a
+    /// fixed text become terminal strings, other atoms are described in special sequences.
+    pub fn to_ebnf(&self) -> String {
+        let mut out = String::new();
+        for name in self.rule_names() {
+            let alternatives = self
+                .rules_named(name)
+                .iter()
+                .map(|r| ebnf(&r.production, false))
+                .collect::<Vec<_>>();
+            out.push_str(&format!("{} = {} ;\n", name, alternatives.join(" | ")));
+        }
+        for atom in self.atoms.iter() {
+            let definition = match atom {
+                Atom::Simple { name } => ebnf_terminal(name),
+                Atom::Matched { m, .. } => {
+                    match (m.pattern(), m.pattern().and_then(unescape_literal)) {
+                        (_, Some(literal)) => ebnf_terminal(&literal),
+                        (Some(pattern), None) => format!("? regex {} ?", pattern),
+                        (None, None) => "? custom matcher ?".into(),
+                    }
+                }
+                Atom::Integer { min, max, .. } => format!("? integer {}..={} ?", min, max),
+                Atom::Balanced { open, close, .. } => format!("? balanced {} {} ?", open, close),
+            };
+            out.push_str(&format!("{} = {} ;\n", atom.name(), definition));
+        }
+        out
+    }
+    /// Describe the syntax trees of the grammar. For every rule there is a list with the node
+    /// type and possible children of each production, where children are
+    /// `{"leaf": type}`, `{"rule": name}`, `{"optional": [children]}`,
+    /// `{"repeated": [children]}` or `{"one_of": [[children], [children]]}`. Lexems left out of
+    /// the tree aren't included, and options changing the tree like `bubble` are ignored.
+    pub fn tree_schema(&self) -> Value {
+        let mut schema = Map::new();
+        for name in self.rule_names() {
+            let productions = self
+                .rules_named(name)
+                .iter()
+                .map(|r| {
+                    json!({
+                        "type": r.label.as_deref().unwrap_or(name),
+                        "children": self.schema_children(&r.production),
+                    })
+                })
+                .collect();
+            schema.insert(name.into(), Value::Array(productions));
+        }
+        Value::Object(schema)
+    }
+    fn schema_children(&self, s: &SymbolType) -> Vec<Value> {
+        match s {
+            SymbolType::Symbol(Symbol::Lexem { t, include_raw }) if *include_raw => {
+                vec![json!({ "leaf": self.aliases.get(t).unwrap_or(t) })]
+            }
+            SymbolType::Symbol(Symbol::AST(rule)) => vec![json!({ "rule": rule })],
+            SymbolType::Symbol(Symbol::Any) => vec![json!({ "leaf": "_" })],
+            SymbolType::Symbol(_) => vec![],
+            SymbolType::Group(g) => g.iter().flat_map(|s| self.schema_children(s)).collect(),
+            SymbolType::Optional(o) => vec![json!({ "optional": self.schema_children(o) })],
+            SymbolType::Repeated(m) => vec![json!({ "repeated": self.schema_children(m) })],
+            SymbolType::Switch(..) => {
+                let mut alternatives = Vec::new();
+                let mut s = s;
+                while let SymbolType::Switch(a, b) = s {
+                    alternatives.push(self.schema_children(a));
+                    s = b;
+                }
+                alternatives.push(self.schema_children(s));
+                vec![json!({ "one_of": alternatives })]
+            }
+        }
+    }
+    /// Names of all atoms in the order they are defined, each followed by its alias if it has
+    /// one. These are the types lexems and leaves can have.
+    pub fn token_types(&self) -> Vec<String> {
+        let mut types = Vec::new();
+        for name in self.atom_names() {
+            for t in std::iter::once(name).chain(self.aliases.get(name).map(String::as_str)) {
+                if !types.iter().any(|other| other == t) {
+                    types.push(t.to_string());
+                }
+            }
+        }
+        types
+    }
+    /// Classify the token type `t`, an atom name or alias. Atoms matching a single fixed text
+    /// are keywords if the text is a word like an identifier, of at least two characters, and
+    /// operators otherwise, like `+` or `x`. Other atoms are literals.
+    pub fn token_class(&self, t: &str) -> Option<TokenClass> {
+        let atom = self
+            .atoms
+            .iter()
+            .find(|a| a.name() == t || self.aliases.get(a.name()).map(String::as_str) == Some(t))?;
+        let text = match atom {
+            Atom::Simple { name } => Some(name.clone()),
+            Atom::Matched { m, .. } => m.pattern().and_then(unescape_literal),
+            Atom::Integer { .. } | Atom::Balanced { .. } => None,
+        };
+        Some(match text {
+            Some(text) if is_keyword(&text) => TokenClass::Keyword,
+            Some(_) => TokenClass::Operator,
+            None => TokenClass::Literal,
+        })
+    }
+}
+
+/// If the fixed text of an atom is a word like an identifier, longer than a single letter
+fn is_keyword(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && text.chars().count() > 1
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Kind of token produced by an atom, see `Grammar::token_class`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenClass {
+    Keyword,
+    Operator,
+    Literal,
+}
+
+/// Check that every rule and atom used in a production of `grammar` is declared, failing at
+/// the first one that isn't. `indent`, `dedent` and `newline` are always accepted, as the options
+/// emitting them can be set after compiling.
+fn resolve_references(grammar: &Grammar, meta: &Grammar, source: &str) -> ParseResult<()> {
+    for rule in grammar.rules.iter() {
+        let mut undefined = None;
+        rule.production.walk(&mut |s| match s {
+            _ if undefined.is_some() => {}
+            SymbolType::Symbol(Symbol::AST(name))
+                if !grammar.rules.iter().any(|r| r.name == *name) =>
+            {
+                let msg = format!("rule '{}' references undefined rule '{}'", rule.name, name);
+                undefined = Some((name, msg));
+            }
+            SymbolType::Symbol(Symbol::Lexem { t, .. })
+                if !grammar.atoms.iter().any(|a| a.name() == t)
+                    && !["indent", "dedent", "newline"].contains(&t.as_str()) =>
+            {
+                let msg = format!("rule '{}' references undefined atom '{}'", rule.name, t);
+                undefined = Some((t, msg));
+            }
+            _ => {}
+        });
+        if let Some((name, msg)) = undefined {
+            return Err(reference_error(meta, source, name, msg));
+        }
+    }
+    Ok(())
+}
+
+/// Error `msg` at the first use of `name` in a production in `source`. The syntax tree has no
+/// positions, so `source` is lexed again to find it.
+fn reference_error(meta: &Grammar, source: &str, name: &str, msg: String) -> ParseError {
+    let mut depth = 0usize;
+    for lexem in meta.tokenize(source).unwrap_or_default() {
+        match lexem.t.as_str() {
+            "(" => depth += 1,
+            ")" => depth = depth.saturating_sub(1),
+            // names in productions are the only words inside parentheses
+            "ALPHA" if depth > 0 && lexem.raw == name => {
+                return ParseError::Token {
+                    start: lexem.start,
+                    len: lexem.raw.len(),
+                    msg,
+                }
+            }
+            _ => {}
         }
     }
+    ParseError::Grammar(msg)
 }
 
-pub fn parse_ast_grammar(ast: AST) -> Grammar {
+/// Build a grammar from the syntax tree of a text grammar. Fails on invalid regexes,
+/// directives and atom definitions.
+pub fn parse_ast_grammar(ast: AST) -> ParseResult<Grammar> {
     let mut rules = Vec::new();
     let mut atoms = Vec::new();
+    let mut options = ParseOptions::default();
+    let mut skip = Vec::new();
+    let mut aliases = BTreeMap::new();
+    let mut decode_escapes = Vec::new();
+    let mut transparent = Vec::new();
+    let mut hidden = Vec::new();
+    let mut counted = Vec::new();
+    let mut trailing_separators = Vec::new();
+    let mut recover = Vec::new();
+    let mut folds = Vec::new();
+    let mut fragments = Vec::new();
+    let mut lexing = Lexing::default();
 
-    assert_eq!(ast.get_t(), "START");
-    let (_, children) = ast.assume_node();
-    assert_eq!(children.len(), 1);
-    let ast = children.into_iter().nth(0).unwrap();
-    assert_eq!(ast.get_t(), "DOC");
-
-    let (_, children) = ast.assume_node();
+    let (t, children) = ast.assume_node()?;
+    let mut children = children.into_iter();
+    let ast = match (t.as_str(), children.next(), children.next()) {
+        ("START", Some(doc), None) if doc.get_t() == "DOC" => doc,
+        _ => return Err(invalid_tree("a 'START' node with a single 'DOC' node")),
+    };
 
-    let mut doc = children.into_iter().peekable();
+    let (_, children) = ast.assume_node()?;
 
-    loop {
-        let (t, children) = doc.next().unwrap().assume_node();
+    for item in children {
+        if let AST::Leaf { raw, .. } = item {
+            let mut args = raw[1..].split_whitespace();
+            match args.next() {
+                Some("options") => {
+                    for a in args {
+                        set_option(&mut options, a)?;
+                    }
+                }
+                Some("skip") => skip.push(parse_skip(&raw)?),
+                Some("decode") => decode_escapes.extend(args.map(String::from)),
+                Some("transparent") => transparent.extend(args.map(String::from)),
+                Some("count") => counted.extend(args.map(String::from)),
+                Some("trailing_separators") => trailing_separators.extend(args.map(String::from)),
+                Some("recover") => {
+                    let args = args.collect::<Vec<_>>();
+                    if args.len() % 2 != 0 {
+                        return Err(ParseError::Grammar(
+                            "expected pairs of opening and closing atoms after @recover".into(),
+                        ));
+                    }
+                    recover.extend(args.chunks(2).map(|p| (p[0].into(), p[1].into())));
+                }
+                Some("fragment") => fragments.extend(args.map(String::from)),
+                Some("fold") => {
+                    let fold = match args.next() {
+                        Some("left") => Fold::Left,
+                        Some("right") => Fold::Right,
+                        d => {
+                            return Err(ParseError::Grammar(format!(
+                                "expected 'left' or 'right' after @fold, found '{}'",
+                                d.unwrap_or_default()
+                            )))
+                        }
+                    };
+                    folds.extend(args.map(|rule| (rule.to_string(), fold)));
+                }
+                Some("lexing") => {
+                    lexing = match args.next() {
+                        Some("first-match") => Lexing::FirstMatch,
+                        Some("longest-match") => Lexing::LongestMatch,
+                        l => {
+                            return Err(ParseError::Grammar(format!(
+                                "expected 'first-match' or 'longest-match' after @lexing, found \
+                                 '{}'",
+                                l.unwrap_or_default()
+                            )))
+                        }
+                    }
+                }
+                d => {
+                    return Err(ParseError::Grammar(format!(
+                        "unknown directive '@{}'",
+                        d.unwrap_or_default()
+                    )))
+                }
+            }
+            continue;
+        }
+        let (t, children) = item.assume_node()?;
 
         let mut c = children.into_iter();
         if t == "EXP" {
-            let (_, name) = c.next().unwrap().assume_leaf();
-            let production = parse_production(c.next().unwrap());
-            rules.push(Rule { name, production });
-        } else if t == "ATOM" {
-            let (_, name) = c.next().unwrap().assume_leaf();
-            let (_, literal) = c.next().unwrap().assume_leaf();
-            atoms.push(Atom::Matched {
+            let (_, name) = next_child(&mut c)?.assume_leaf()?;
+            let label = if c.len() == 2 {
+                Some(next_child(&mut c)?.assume_leaf()?.1)
+            } else {
+                None
+            };
+            let production = parse_production(next_child(&mut c)?)?;
+            rules.push(Rule {
                 name,
-                m: Regex::new(&literal).unwrap(),
+                production,
+                order: rules.len(),
+                label,
             });
+        } else if t == "ATOM" {
+            let mut words = c.collect::<Vec<_>>();
+            let value = words.pop().ok_or_else(|| invalid_tree("an atom value"))?;
+            let words = words
+                .into_iter()
+                .map(AST::assume_leaf)
+                .collect::<ParseResult<Vec<_>>>()?;
+            // modifiers are between the name and the arrow, a marker or function after it
+            let arrow = words
+                .iter()
+                .skip(1)
+                .position(|(t, _)| t == "->")
+                .ok_or_else(|| invalid_tree("'->' after the atom name"))?
+                + 1;
+            let mut words = words.into_iter().map(|(_, raw)| raw);
+            let name = words.next().ok_or_else(|| invalid_tree("an atom name"))?;
+            let modifiers = words.by_ref().take(arrow - 1).collect::<Vec<_>>();
+            let words = words.skip(1).collect::<Vec<_>>();
+            let mut modifiers = modifiers.into_iter();
+            while let Some(modifier) = modifiers.next() {
+                match modifier.as_str() {
+                    "hidden" => hidden.push(name.clone()),
+                    "as" => {
+                        let alias = modifiers.next().ok_or_else(|| {
+                            ParseError::Grammar(format!("expected an alias of atom '{}'", name))
+                        })?;
+                        aliases.insert(name.clone(), alias);
+                    }
+                    modifier => {
+                        return Err(ParseError::Grammar(format!(
+                            "unknown modifier '{}' of atom '{}', expected 'as' or 'hidden'",
+                            modifier, name
+                        )))
+                    }
+                }
+            }
+            let atom = match value {
+                // literals are matched as is after decoding escapes like `\t`, unless marked
+                // as a regex with `r'...'`
+                AST::Leaf { raw: literal, .. } => AtomDef::Pattern {
+                    regex: match words.first().map(String::as_str) {
+                        Some("r") => literal,
+                        None => regex::escape(&crate::parsing::decode_escapes(&literal)),
+                        Some(marker) => {
+                            return Err(ParseError::Grammar(format!(
+                                "unknown atom marker '{}', expected 'r'",
+                                marker
+                            )))
+                        }
+                    },
+                    name,
+                },
+                AST::Node { children, .. } => {
+                    let (_, arg) = next_child(&mut children.into_iter())?.assume_leaf()?;
+                    match words.first().map(String::as_str) {
+                        Some("any_except") => AtomDef::Atom(Atom::Matched {
+                            name,
+                            m: Box::new(any_except(&arg)?),
+                        }),
+                        Some("int") => {
+                            let (min, max) = int_range(&arg)?;
+                            AtomDef::Atom(Atom::Integer { name, min, max })
+                        }
+                        Some("balanced") => {
+                            let (open, close) = balanced_delimiters(&name, &arg)?;
+                            AtomDef::Atom(Atom::Balanced { name, open, close })
+                        }
+                        f => {
+                            return Err(ParseError::Grammar(format!(
+                                "unknown atom function '{}'",
+                                f.unwrap_or_default()
+                            )))
+                        }
+                    }
+                }
+            };
+            atoms.push(atom);
         } else {
-            panic!();
+            return Err(invalid_tree(&format!("a rule or atom, found '{}'", t)));
         }
-        if doc.peek().is_none() {
-            break;
+    }
+
+    for fragment in fragments.iter() {
+        if !atoms.iter().any(|a| a.name() == fragment) {
+            return Err(ParseError::Grammar(format!(
+                "unknown fragment atom '{}'",
+                fragment
+            )));
         }
     }
+    let patterns = atoms
+        .iter()
+        .map(|a| match a {
+            AtomDef::Pattern { name, .. } => {
+                expand_fragments(&atoms, name, &mut Vec::new()).map(Some)
+            }
+            AtomDef::Atom(_) => Ok(None),
+        })
+        .collect::<ParseResult<Vec<_>>>()?;
+    let atoms = atoms
+        .into_iter()
+        .zip(patterns)
+        .filter(|(a, _)| !fragments.iter().any(|f| f == a.name()))
+        .map(|(a, pattern)| match a {
+            AtomDef::Pattern { name, .. } => {
+                let m = Regex::new(&pattern.unwrap()).map_err(|e| {
+                    ParseError::Grammar(format!("invalid regex in atom '{}': {}", name, e))
+                })?;
+                Ok(Atom::Matched {
+                    name,
+                    m: Box::new(m),
+                })
+            }
+            AtomDef::Atom(atom) => Ok(atom),
+        })
+        .collect::<ParseResult<_>>()?;
 
-    Grammar {
-        options: ParseOptions::default(),
+    Ok(Grammar {
+        options,
         rules,
         atoms,
+        modes: vec![],
+        skip,
+        aliases,
+        decode_escapes,
+        lexing,
+        transparent,
+        hidden,
+        recover,
+        folds,
+        counted,
+        trailing_separators,
+        ..Grammar::default()
+    })
+}
+
+/// An atom whose regex can still reference other atoms as `{NAME}`
+enum AtomDef {
+    Pattern { name: String, regex: String },
+    Atom(Atom),
+}
+
+impl AtomDef {
+    fn name(&self) -> &str {
+        match self {
+            AtomDef::Pattern { name, .. } => name,
+            AtomDef::Atom(atom) => atom.name(),
+        }
     }
 }
 
-fn parse_production(ast: AST) -> SymbolType {
-    match ast {
-        AST::Node { t, children } => {
-            let mut c = children.into_iter().peekable();
-            if t == "PROD" {
-                let mut children = vec![parse_production(c.next().unwrap())];
-                while let Some(p) = c.next() {
-                    if p.get_t() == "|" {
-                        assert!(children.len() == 1);
-                        let rhs = parse_production(c.next().unwrap());
-                        children = vec![SymbolType::Switch(
-                            Box::new(children.into_iter().next().unwrap()),
-                            Box::new(rhs),
-                        )];
-                    } else {
-                        children.push(parse_production(p));
-                    }
-                }
-                SymbolType::Group(children)
-            } else if t == "PROD_TERM" {
-                parse_production(c.next().unwrap())
-            } else if t == "PROD_GROUP" {
-                let mut ast = parse_production(c.next().unwrap());
-                if c.peek().is_some() {
-                    let a = c.next().unwrap();
-                    let t = a.get_t();
-                    if t == "*" {
-                        ast = SymbolType::Repeated(Box::new(ast));
-                    } else if t == "?" {
-                        ast = SymbolType::Optional(Box::new(ast));
+/// Regex of the atom `name` with every `{NAME}` replaced by the regex of the atom `NAME`.
+/// `visiting` holds the atoms currently being expanded, to detect cycles.
+fn expand_fragments(
+    atoms: &[AtomDef],
+    name: &str,
+    visiting: &mut Vec<String>,
+) -> ParseResult<String> {
+    if visiting.iter().any(|v| v == name) {
+        visiting.push(name.into());
+        return Err(ParseError::Grammar(format!(
+            "cycle in atom fragments: {}",
+            visiting.join(" -> ")
+        )));
+    }
+    let regex = match atoms.iter().find(|a| a.name() == name) {
+        Some(AtomDef::Pattern { regex, .. }) => regex,
+        Some(AtomDef::Atom(Atom::Matched { m, .. })) if m.pattern().is_some() => {
+            return Ok(m.pattern().unwrap().into())
+        }
+        Some(_) => {
+            return Err(ParseError::Grammar(format!(
+                "atom '{}' can't be used as a fragment",
+                name
+            )))
+        }
+        None => {
+            return Err(ParseError::Grammar(format!(
+                "unknown atom '{}' used as a fragment",
+                name
+            )))
+        }
+    };
+    visiting.push(name.into());
+    let mut expanded = String::new();
+    let mut chars = regex.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                expanded.push(c);
+                if let Some((j, escaped)) = chars.next() {
+                    expanded.push(escaped);
+                    // braces of escapes like `\p{Alphabetic}` and `\x{e9}` aren't fragments
+                    let rest = &regex[j + 1..];
+                    if "pPxuU".contains(escaped) && rest.starts_with('{') {
+                        let end = rest.find('}').map(|e| e + 1).unwrap_or(rest.len());
+                        expanded.push_str(&rest[..end]);
+                        chars.nth(rest[..end].chars().count() - 1);
                     }
                 }
-                ast
-            } else {
-                todo!("{}", t);
             }
-        }
-        AST::Leaf { t, raw } => {
-            if t == "ALPHA" {
-                if raw.to_ascii_uppercase() == raw {
-                    SymbolType::Symbol(Symbol::AST(raw))
-                } else {
-                    SymbolType::Symbol(Symbol::Lexem {
-                        t: raw,
-                        include_raw: true,
-                    })
+            '{' => {
+                let rest = &regex[i + 1..];
+                let reference = rest
+                    .find('}')
+                    .map(|end| &rest[..end])
+                    .filter(|r| !r.is_empty() && r.chars().all(|c| c.is_alphabetic() || c == '_'));
+                match reference {
+                    Some(reference) => {
+                        let inner = expand_fragments(atoms, reference, visiting)?;
+                        expanded.push_str(&format!("(?:{})", inner));
+                        chars.nth(reference.chars().count());
+                    }
+                    None => expanded.push(c),
                 }
-            } else {
-                todo!();
             }
+            _ => expanded.push(c),
         }
     }
+    visiting.pop();
+    Ok(expanded)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Regex matching a single character not in `chars`
+fn any_except(chars: &str) -> ParseResult<Regex> {
+    if chars.is_empty() {
+        return Err(ParseError::Grammar(
+            "any_except needs at least one character to exclude".into(),
+        ));
+    }
+    Ok(Regex::new(&format!("[^{}]", regex::escape(chars))).unwrap())
+}
+
+/// Bounds of an integer atom, either an integer type like `u32` or an inclusive range like
+/// `-10..=10`
+pub(crate) fn int_range(arg: &str) -> ParseResult<(i128, i128)> {
+    let error = |msg: String| Err(ParseError::Grammar(msg));
+    Ok(match arg {
+        "u8" => (0, u8::MAX.into()),
+        "u16" => (0, u16::MAX.into()),
+        "u32" => (0, u32::MAX.into()),
+        "u64" => (0, u64::MAX.into()),
+        "i8" => (i8::MIN.into(), i8::MAX.into()),
+        "i16" => (i16::MIN.into(), i16::MAX.into()),
+        "i32" => (i32::MIN.into(), i32::MAX.into()),
+        "i64" => (i64::MIN.into(), i64::MAX.into()),
+        _ => {
+            let (min, max) = match arg.split_once("..=") {
+                Some(bounds) => bounds,
+                None => {
+                    return error(format!(
+                        "expected an integer type or range, found '{}'",
+                        arg
+                    ))
+                }
+            };
+            let bound = |b: &str| {
+                b.trim()
+                    .parse::<i128>()
+                    .map_err(|_| ParseError::Grammar(format!("invalid integer bound '{}'", b)))
+            };
+            let (min, max) = (bound(min)?, bound(max)?);
+            if min > max {
+                return error(format!("empty integer range '{}'", arg));
+            }
+            (min, max)
+        }
+    })
+}
+
+/// Parse `@skip 'regex'` or `@skip 'start' 'end'`
+fn parse_skip(directive: &str) -> ParseResult<Skip> {
+    let args = directive
+        .split('\'')
+        .skip(1)
+        .step_by(2)
+        .map(String::from)
+        .collect::<Vec<_>>();
+    match args.as_slice() {
+        [m] => Regex::new(m)
+            .map(Skip::Matched)
+            .map_err(|e| ParseError::Grammar(format!("invalid regex in @skip: {}", e))),
+        [start, end] => Ok(Skip::Delimited {
+            start: start.clone(),
+            end: end.clone(),
+        }),
+        _ => Err(ParseError::Grammar(
+            "expected one or two quoted arguments to @skip".into(),
+        )),
+    }
+}
+
+/// Opening and closing delimiter of a balanced atom, written as `'open close'`
+pub(crate) fn balanced_delimiters(name: &str, arg: &str) -> ParseResult<(String, String)> {
+    match arg.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [open, close] => Ok((open.to_string(), close.to_string())),
+        _ => Err(ParseError::Grammar(format!(
+            "expected an opening and a closing delimiter in atom '{}', found '{}'",
+            name, arg
+        ))),
+    }
+}
+
+fn set_option(options: &mut ParseOptions, name: &str) -> ParseResult<()> {
+    match name {
+        "ignore_all" => {
+            options.ignore_whitespace = true;
+            options.ignore_newline = true;
+        }
+        "ignore_whitespace" => options.ignore_whitespace = true,
+        "ignore_newline" => options.ignore_newline = true,
+        "bubble" => options.bubble_intermediate = true,
+        "record_trivia" => options.record_trivia = true,
+        "indentation" => options.indentation = true,
+        "flatten_repeated" => options.flatten_repeated = true,
+        "newlines" => options.newlines = true,
+        "record_production_index" => options.record_production_index = true,
+        _ => match name.split_once('=') {
+            Some(("lookahead", n)) => {
+                options.lookahead = n
+                    .parse()
+                    .map_err(|_| ParseError::Grammar(format!("invalid lookahead '{}'", n)))?
+            }
+            Some(("ignore_chars", chars)) => options.ignore_chars.extend(chars.chars()),
+            _ => return Err(ParseError::Grammar(format!("unknown option '{}'", name))),
+        },
+    }
+    Ok(())
+}
+
+/// Parse `{n}`, `{n,}` or `{n,m}` into the minimum and maximum number of repetitions
+fn parse_quantifier(ast: AST) -> ParseResult<(usize, Option<usize>)> {
+    let (_, children) = ast.assume_node()?;
+    let bounds = children
+        .into_iter()
+        .map(|c| c.assume_leaf().map(|(_, raw)| raw))
+        .collect::<ParseResult<Vec<_>>>()?;
+    let number = |n: &String| {
+        n.parse::<usize>()
+            .map_err(|_| ParseError::Grammar(format!("quantifier bound '{}' too large", n)))
+    };
+    let (min, max) = match bounds.as_slice() {
+        [n] => (number(n)?, Some(number(n)?)),
+        [n, _] => (number(n)?, None),
+        [n, _, m] => (number(n)?, Some(number(m)?)),
+        _ => return Err(invalid_tree("one or two quantifier bounds")),
+    };
+    if max.map(|max| min > max).unwrap_or(false) {
+        return Err(ParseError::Grammar(format!(
+            "quantifier minimum {} is larger than maximum",
+            min
+        )));
+    }
+    Ok((min, max))
+}
+
+fn parse_production(ast: AST) -> ParseResult<SymbolType> {
+    Ok(match ast {
+        AST::Node { t, children, .. } => {
+            let mut c = children.into_iter().peekable();
+            if t == "PROD" {
+                let mut children = vec![parse_production(next_child(&mut c)?)?];
+                while let Some(p) = c.next() {
+                    if p.get_t() == "|" {
+                        let lhs = match children.pop() {
+                            Some(lhs) if children.is_empty() => lhs,
+                            _ => return Err(invalid_tree("a single group before '|'")),
+                        };
+                        let rhs = parse_production(next_child(&mut c)?)?;
+                        children = vec![SymbolType::Switch(Box::new(lhs), Box::new(rhs))];
+                    } else {
+                        children.push(parse_production(p)?);
+                    }
+                }
+                SymbolType::Group(children)
+            } else if t == "PROD_TERM" {
+                parse_production(next_child(&mut c)?)?
+            } else if t == "PROD_GROUP" {
+                let mut ast = parse_production(next_child(&mut c)?)?;
+                if let Some(a) = c.next() {
+                    let t = a.get_t();
+                    if t == "*" {
+                        ast = SymbolType::Repeated(Box::new(ast));
+                    } else if t == "?" {
+                        ast = SymbolType::Optional(Box::new(ast));
+                    } else if t == "QUANTIFIER" {
+                        let (min, max) = parse_quantifier(a)?;
+                        ast = SymbolType::bounded(ast, min, max);
+                    }
+                }
+                ast
+            } else {
+                return Err(invalid_tree(&format!("a production, found node '{}'", t)));
+            }
+        }
+        AST::Leaf { t, raw, .. } => {
+            if t == "ALPHA" {
+                SymbolType::named(raw)
+            } else if t == "$" {
+                SymbolType::Symbol(Symbol::EOF)
+            } else {
+                return Err(invalid_tree(&format!("a production, found leaf '{}'", t)));
+            }
+        }
+    })
+}
 
-    const RAW_GRAMMAR_SUM: &str = &r#"
+/// Sums of products, the grammar most tests in the crate use
+#[cfg(test)]
+pub(crate) const RAW_GRAMMAR_SUM: &str = r#"
             START -> ( SUM )
             SUM -> ( PRODUCT ( OPA PRODUCT )* )
             PRODUCT -> ( NUMBER ( OPB NUMBER )* )
@@ -335,53 +1527,1766 @@ mod tests {
             OPA -> ( ( pluss ) | ( minus ) )
             OPB -> ( ( multiply ) | ( divide ) )
 
-            >pluss -> '\+'
+            >pluss -> '+'
             >minus -> '-'
             >multiply -> 'x'
             >divide -> '/'
-            >num -> '\d+'
+            >num -> r'\d+'
             "#;
-    const RAW_GRAMMAR_FILES: &str = &r#"
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW_GRAMMAR_FILES: &str = r#"
             START -> ( FILE )*
             FILE -> (alpha (dot alpha)?)
 
-            >alpha -> '\w+'
-            >dot -> '\.'
+            >alpha -> r'\w+'
+            >dot -> '.'
             "#;
     #[test]
+    fn tree_schema() {
+        let g = Grammar::compile(RAW_GRAMMAR_FILES).unwrap();
+        assert_eq!(
+            g.tree_schema(),
+            json!({
+                "START": [{ "type": "START", "children": [{ "repeated": [{ "rule": "FILE" }] }] }],
+                "FILE": [{
+                    "type": "FILE",
+                    "children": [
+                        { "leaf": "alpha" },
+                        { "optional": [{ "leaf": "dot" }, { "leaf": "alpha" }] },
+                    ],
+                }],
+            })
+        );
+
+        let g = Grammar::compile(
+            r#"
+            START -> ( ( VALUE )* $ )
+            VALUE -> number: ( num )
+            VALUE -> ( ( lp ( VALUE )* rp ) | ( word ) )
+            >lp -> '('
+            >rp -> ')'
+            >num as n -> r'\d+'
+            >word -> r'[a-z]+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            g.tree_schema()["VALUE"],
+            json!([
+                { "type": "number", "children": [{ "leaf": "n" }] },
+                {
+                    "type": "VALUE",
+                    "children": [{
+                        "one_of": [
+                            [{ "leaf": "lp" }, { "repeated": [{ "rule": "VALUE" }] }, { "leaf": "rp" }],
+                            [{ "leaf": "word" }],
+                        ],
+                    }],
+                },
+            ])
+        );
+    }
+    #[test]
+    fn export_ebnf() {
+        let g = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        let ebnf = g.to_ebnf();
+        let lines = ebnf.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "START = SUM ;",
+                "SUM = PRODUCT , { OPA , PRODUCT } ;",
+                "PRODUCT = NUMBER , { OPB , NUMBER } ;",
+                "NUMBER = num | minus , num ;",
+                "OPA = pluss | minus ;",
+                "OPB = multiply | divide ;",
+                "pluss = \"+\" ;",
+                "minus = \"-\" ;",
+                "multiply = \"x\" ;",
+                "divide = \"/\" ;",
+                r"num = ? regex \d+ ? ;",
+            ]
+        );
+
+        type ST = SymbolType;
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                production: ST::seq(vec![
+                    ST::switch(
+                        ST::lexem("a"),
+                        ST::seq(vec![ST::lexem("b"), ST::lexem("c")]),
+                    ),
+                    ST::opt(ST::lexem("d")),
+                    ST::eof(),
+                ]),
+                order: 0,
+                label: None,
+            }],
+            ..Grammar::default()
+        };
+        assert_eq!(
+            g.to_ebnf(),
+            "START = ( a | b , c ) , [ d ] , ? end of input ? ;\n"
+        );
+    }
+    #[test]
+    fn token_classes() {
+        let g = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        assert_eq!(
+            g.token_types(),
+            vec!["pluss", "minus", "multiply", "divide", "num"]
+        );
+        assert_eq!(g.token_class("pluss"), Some(TokenClass::Operator));
+        assert_eq!(g.token_class("minus"), Some(TokenClass::Operator));
+        assert_eq!(g.token_class("divide"), Some(TokenClass::Operator));
+        assert_eq!(g.token_class("multiply"), Some(TokenClass::Operator));
+        assert_eq!(g.token_class("num"), Some(TokenClass::Literal));
+        assert_eq!(g.token_class("SUM"), None);
+
+        let g = Grammar::compile("START -> ( a b )\n>a as word -> 'if_1'\n>b -> '1a'").unwrap();
+        assert_eq!(g.token_types(), vec!["a", "word", "b"]);
+        assert_eq!(g.token_class("word"), Some(TokenClass::Keyword));
+        assert_eq!(g.token_class("b"), Some(TokenClass::Operator));
+    }
+    #[test]
     fn parse_simple_grammar() {
         let g = get_parsing_grammar();
-        assert!(g.parse(&RAW_GRAMMAR_SUM.into()).is_ok());
+        assert!(g.parse(RAW_GRAMMAR_SUM).is_ok());
+    }
+    #[test]
+    fn compile_empty_grammar() {
+        for source in ["", "\n\n", "  \n "].iter() {
+            match Grammar::compile(source) {
+                Err(ParseError::Grammar(msg)) => assert!(msg.contains("empty grammar")),
+                r => panic!("expected empty grammar error, got {:?}", r.map(|_| ())),
+            }
+        }
+        assert!(Grammar::compile(RAW_GRAMMAR_SUM).is_ok());
     }
     #[test]
     fn parse_ast() {
         let g = get_parsing_grammar();
-        let ast = g.parse(&RAW_GRAMMAR_SUM.into()).unwrap();
-        let gp = parse_ast_grammar(ast);
-        assert!(gp.parse(&"1".into()).is_ok());
-        assert!(gp.parse(&"1+2x3".into()).is_ok());
-        assert!(gp.parse(&"1x2+3x4".into()).is_ok());
+        let ast = g.parse(RAW_GRAMMAR_SUM).unwrap();
+        let gp = parse_ast_grammar(ast).unwrap();
+        assert!(gp.parse("1").is_ok());
+        assert!(gp.parse("1+2x3").is_ok());
+        assert!(gp.parse("1x2+3x4").is_ok());
+    }
+    #[test]
+    fn grammar_options_directive() {
+        let g = get_parsing_grammar();
+        let ast = g
+            .parse(
+                r#"
+            @options ignore_whitespace
+            START -> ( lp num rp )
+            >lp -> '('
+            >rp -> ')'
+            >num -> r'\d+'
+            "#,
+            )
+            .unwrap();
+        let gp = parse_ast_grammar(ast).unwrap();
+        assert!(gp.options.ignore_whitespace);
+        assert!(!gp.options.ignore_newline);
+        assert!(gp.parse("( 1 )").is_ok());
+        assert!(gp.parse("(\n1)").is_err());
+    }
+    #[test]
+    fn ignore_chars() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_chars=;
+            START -> ( num )*
+            >semi -> ';'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(g.options.ignore_chars, vec![';']);
+        let tokens = g.tokenize("1;2;;3;").unwrap();
+        assert_eq!(
+            tokens.iter().map(|l| l.raw.as_str()).collect::<Vec<_>>(),
+            vec!["1", "2", "3"]
+        );
+        assert!(g.parse("1 2").is_err());
+        assert_eq!(
+            Grammar::compile(&g.to_string())
+                .unwrap()
+                .options
+                .ignore_chars,
+            vec![';']
+        );
+    }
+    #[test]
+    fn lexing_policy() {
+        let source = |lexing: &str| {
+            format!(
+                r#"
+                {}
+                START -> ( TOKEN )*
+                TOKEN -> ( eq )
+                TOKEN -> ( eqeq )
+                TOKEN -> ( if )
+                TOKEN -> ( ident )
+                >eq -> '='
+                >eqeq -> '=='
+                >if -> 'if'
+                >ident -> r'[a-z]+'
+                "#,
+                lexing
+            )
+        };
+        let tokens = |g: &Grammar| {
+            g.tokenize("iffy==if")
+                .unwrap()
+                .into_iter()
+                .map(|l| l.t)
+                .collect::<Vec<_>>()
+        };
+        let first = Grammar::compile(&source("")).unwrap();
+        assert_eq!(first.lexing, Lexing::FirstMatch);
+        assert_eq!(tokens(&first), vec!["if", "ident", "eq", "eq", "if"]);
+        assert_eq!(
+            tokens(&Grammar::compile(&source("@lexing first-match")).unwrap()),
+            tokens(&first)
+        );
+
+        let longest = Grammar::compile(&source("@lexing longest-match")).unwrap();
+        assert_eq!(longest.lexing, Lexing::LongestMatch);
+        assert_eq!(tokens(&longest), vec!["ident", "eqeq", "if"]);
+        let printed = Grammar::compile(&longest.to_string()).unwrap();
+        assert_eq!(printed.lexing, Lexing::LongestMatch);
+    }
+    #[test]
+    fn parse_with_callbacks() {
+        fn count(ast: &AST) -> usize {
+            match ast {
+                AST::Node { children, .. } => 1 + children.iter().map(count).sum::<usize>(),
+                AST::Leaf { .. } => 1,
+            }
+        }
+        #[derive(Default)]
+        struct Counter {
+            nodes: usize,
+            depth: usize,
+        }
+        impl ParseCallbacks for Counter {
+            fn on_enter(&mut self, _rule: &str) {
+                self.nodes += 1;
+                self.depth += 1;
+            }
+            fn on_leaf(&mut self, _t: &str, _raw: &str) {
+                self.nodes += 1;
+            }
+            fn on_exit(&mut self, _rule: &str) {
+                self.depth -= 1;
+            }
+        }
+
+        let g = get_parsing_grammar();
+        let gp = parse_ast_grammar(g.parse(RAW_GRAMMAR_SUM).unwrap()).unwrap();
+        let input = "1x2+3x4-5";
+        let mut counter = Counter::default();
+        gp.parse_with_callbacks(input, &mut counter).unwrap();
+        assert_eq!(counter.depth, 0);
+        assert_eq!(counter.nodes, count(&gp.parse(input).unwrap()));
+    }
+    #[test]
+    fn explicit_eof() {
+        let gp = Grammar::compile(
+            r#"
+            START -> ( LIST $ )
+            LIST -> ( lb ( num )* rb )
+            >lb -> '['
+            >rb -> ']'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap()
+        .with_options(ParseOptions {
+            ignore_whitespace: true,
+            ..ParseOptions::default()
+        });
+        assert!(gp.parse("[1 2]").is_ok());
+        match gp.parse("[1 2] 3") {
+            Err(ParseError::Token { start, len, msg }) => {
+                assert_eq!((start, len), (6, 1));
+                assert_eq!(msg, "expected EOF found 'num'");
+            }
+            r => panic!("expected input error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn explain_token() {
+        let gp = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        let input = "1+2x3";
+
+        let info = gp.explain_token(input, 4).unwrap().unwrap();
+        assert_eq!(info.lexem.t, "num");
+        assert_eq!(info.lexem.raw, "3");
+        assert_eq!(info.rules, vec!["START", "SUM", "PRODUCT", "NUMBER"]);
+
+        let info = gp.explain_token(input, 3).unwrap().unwrap();
+        assert_eq!(info.lexem.t, "multiply");
+        assert_eq!(info.rules, vec!["START", "SUM", "PRODUCT", "OPB"]);
+
+        assert!(gp.explain_token(input, 5).unwrap().is_none());
+    }
+    #[test]
+    fn bounded_repetition() {
+        // NUMBER in the meta-grammar is only used for the bounds of quantifiers
+        let g = Grammar::compile(
+            r#"
+            START -> ( s ( a ){2,3} ( b ){1,} ( c ){2} ( d ){0,1} )
+            >s -> 's'
+            >a -> 'a'
+            >b -> 'b'
+            >c -> 'c'
+            >d -> 'd'
+            "#,
+        )
+        .unwrap();
+        for input in ["saabcc", "saaabbbccd"].iter() {
+            assert!(g.parse(input).is_ok(), "{}", input);
+        }
+        for input in ["sabcc", "saaaabcc", "saacc", "saabc", "saabccdd"].iter() {
+            assert!(g.parse(input).is_err(), "{}", input);
+        }
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn literals_containing_tokens() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( ( one ) | ( comma ) | ( brace ) | ( star ) )*
+            >one -> '1'
+            >comma -> ','
+            >brace -> '{'
+            >star -> '*'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(g.tokenize("1,{*").unwrap().len(), 4);
+    }
+    #[test]
+    fn literal_atoms() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( ( dot ) | ( any ) )*
+            >dot -> '.'
+            >any -> r'.'
+            "#,
+        )
+        .unwrap();
+        let lexems = g.tokenize(".x").unwrap();
+        assert_eq!(lexems[0].t, "dot");
+        assert_eq!(lexems[1].t, "any");
+
+        let g = Grammar::compile(">dot -> '.'").unwrap();
+        assert!(g.tokenize("x").is_err());
+        assert_eq!(g.to_string().trim(), ">dot            -> '.'");
+    }
+    #[test]
+    fn balanced_atoms() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all
+            START -> ( ( name ) | ( block ) )*
+            >block -> balanced('{ }')
+            >name -> r'[a-z]+'
+            "#,
+        )
+        .unwrap();
+        let ast = g.parse("x { a { b } c } y {}").unwrap();
+        assert_eq!(
+            ast.leaves().collect::<Vec<_>>(),
+            vec![
+                ("name", "x"),
+                ("block", "{ a { b } c }"),
+                ("name", "y"),
+                ("block", "{}")
+            ]
+        );
+        match g.parse("x { a { b }") {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 2);
+                assert_eq!(msg, "unterminated '{', expected '}'");
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+        assert!(Grammar::from_ebnf(&g.to_ebnf())
+            .unwrap()
+            .atoms
+            .contains(&g.atoms[0]));
+        assert!(Grammar::compile(">block -> balanced('{')").is_err());
+    }
+    #[test]
+    fn double_quoted_literals() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( ( apos ) | ( quote ) | ( word ) )*
+            >apos -> "'"
+            >quote -> '"'
+            >word -> r"[a-z']+"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            g.tokenize("'\"it's")
+                .unwrap()
+                .iter()
+                .map(|l| l.t.as_str())
+                .collect::<Vec<_>>(),
+            vec!["apos", "quote", "word"]
+        );
+        assert!(g.parse("'").is_ok());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+        assert!(g.to_string().contains(r#">apos           -> "'""#));
+        assert!(g.to_string().contains(r#">word           -> r"[a-z']+""#));
+
+        assert!(Grammar::compile(r#">empty -> """#).is_err());
+
+        // neither quote can delimit text containing both
+        let g = Grammar::compile(
+            r#"
+            START -> ( both )
+            >both -> '"\x27\\'
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse(r#""'\"#).is_ok());
+        assert!(g.to_string().contains(r#">both           -> '"\x27\\'"#));
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+        assert_eq!(quoted(r#"["\'x']"#), r#"'["\x27x\x27]'"#);
+    }
+    #[test]
+    fn any_except_atom() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( quote ( text )* quote )
+            >quote -> '"'
+            >text -> any_except('"\')
+            "#,
+        )
+        .unwrap();
+        let (_, children) = g.parse(r#""a-]^ b""#).unwrap().assume_node().unwrap();
+        let text = children
+            .into_iter()
+            .map(|c| c.assume_leaf().unwrap())
+            .filter(|(t, _)| t == "text")
+            .map(|(_, raw)| raw)
+            .collect::<String>();
+        assert_eq!(text, "a-]^ b");
+        assert!(g.parse(r#""a\b""#).is_err());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn production_starting_with_optional() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( X ( Y )? )
+            X -> ( ( a )? b )
+            Y -> ( ( ( c )* ) ( ( d )? ) e )
+            >a -> 'a'
+            >b -> 'b'
+            >c -> 'c'
+            >d -> 'd'
+            >e -> 'e'
+            "#,
+        )
+        .unwrap();
+        for input in ["b", "ab", "be", "bcce", "bde", "abccde"].iter() {
+            assert!(g.parse(input).is_ok(), "{}", input);
+        }
+        assert!(g.parse("a").is_err());
+        assert!(g.parse("bcd").is_err());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn integer_atoms() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_whitespace
+            START -> ( num ( small )? )
+            >num -> int('u32')
+            >small -> int('-5..=5')
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse("4294967295").is_ok());
+        assert!(g.parse("0 -5").is_ok());
+
+        let input = "12 99999999999999999999".to_string();
+        match g.tokenize(&input) {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 3);
+                assert_eq!(
+                    msg,
+                    "integer '99999999999999999999' overflows atom 'num', expected a value in 0..=4294967295"
+                );
+            }
+            r => panic!("expected lexer error, got {:?}", r),
+        }
+        match g.parse("007") {
+            Err(ParseError::Lexem(0, msg)) => assert_eq!(msg, "leading zeros in integer '007'"),
+            r => panic!("expected lexer error, got {:?}", r),
+        }
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn canonicalize() {
+        let source = r#"
+            START -> ( ( ( ( a ) ) ) ( ( ( b )? )? ) ( ( c ) )* ( c )* ( X ) )
+            X -> ( ( ( ( d ) ) | ( e ) ) )
+            >a -> 'a'
+            >b -> 'b'
+            >c -> 'c'
+            >d -> 'd'
+            >e -> 'e'
+            "#;
+        let g = Grammar::compile(source).unwrap();
+        let mut canonical = Grammar::compile(source).unwrap();
+        canonical.canonicalize();
+
+        type ST = SymbolType;
+        assert_eq!(
+            canonical.rules[0].production,
+            ST::seq(vec![
+                ST::leaf("a"),
+                ST::opt(ST::leaf("b")),
+                ST::rep(ST::leaf("c")),
+                ST::rule("X"),
+            ])
+        );
+        assert_eq!(
+            canonical.rules[1].production,
+            ST::switch(ST::leaf("d"), ST::leaf("e"))
+        );
+        for input in ["ad", "abcce", "ace"].iter() {
+            assert_eq!(
+                serde_json::to_string(&g.parse(input).unwrap()).unwrap(),
+                serde_json::to_string(&canonical.parse(input).unwrap()).unwrap()
+            );
+        }
+        let mut reloaded = Grammar::compile(&canonical.to_string()).unwrap();
+        reloaded.canonicalize();
+        assert!(canonical.structurally_equal(&reloaded));
+    }
+    #[test]
+    fn serialize_compact_leaves() {
+        let g = Grammar::compile(RAW_GRAMMAR_FILES).unwrap();
+        let ast = g.parse("main.rs").unwrap();
+        let options = SerializeOptions {
+            compact_leaves: vec!["alpha".into()],
+            ..SerializeOptions::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&SerializeWith {
+                ast: &ast,
+                options: &options
+            })
+            .unwrap(),
+            r#"{"type":"START","children":[{"type":"FILE","children":["main",{"type":"dot","raw":"."},"rs"]}]}"#
+        );
+        let options = SerializeOptions {
+            compact_all: true,
+            ..SerializeOptions::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&SerializeWith {
+                ast: &ast,
+                options: &options
+            })
+            .unwrap(),
+            r#"{"type":"START","children":[{"type":"FILE","children":["main",".","rs"]}]}"#
+        );
+    }
+    #[test]
+    fn named_capture_groups() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( DATE ( comma DATE )* )
+            DATE -> ( date )
+
+            >comma -> ','
+            >date -> r'(?P<year>\d{4})-(?P<month>\d\d)-(?P<day>\d\d)(T\d\d)?'
+            "#,
+        )
+        .unwrap();
+        let ast = g.parse("2024-03-15,1999-12-31T23").unwrap();
+        let dates = ast
+            .descendants()
+            .filter_map(|a| match a {
+                AST::Leaf { t, captures, .. } if t == "date" => Some(captures),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(dates.len(), 2);
+        assert_eq!(dates[0]["year"], "2024");
+        assert_eq!(dates[0]["month"], "03");
+        assert_eq!(dates[0]["day"], "15");
+        assert_eq!(
+            dates[1].keys().collect::<Vec<_>>(),
+            vec!["day", "month", "year"]
+        );
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            r#"{"type":"START","children":[{"type":"DATE","children":[{"type":"date","raw":"2024-03-15","captures":{"day":"15","month":"03","year":"2024"}}]},{"type":"comma","raw":","},{"type":"DATE","children":[{"type":"date","raw":"1999-12-31T23","captures":{"day":"31","month":"12","year":"1999"}}]}]}"#
+        );
+    }
+    #[test]
+    fn bnf_style_syntax() {
+        let syntax = MetaSyntax {
+            arrow: "::=".into(),
+            atom: "%".into(),
+            ..MetaSyntax::default()
+        };
+        let g = Grammar::compile_with_syntax(
+            r#"
+            START ::= ( SUM )
+            SUM ::= ( num ( pluss num )* )
+            %pluss ::= '+'
+            %num ::= r'\d+'
+            "#,
+            &syntax,
+        )
+        .unwrap();
+        assert!(g.parse("1+2+3").is_ok());
+        assert!(g.parse("1+").is_err());
+        assert!(Grammar::compile_with_syntax("START -> ( a )", &syntax).is_err());
+    }
+    #[test]
+    fn flatten_repeated() {
+        let source = r#"
+            @options bubble
+            START -> ( OP ( START )? )
+            OP -> ( lb START rb )
+            OP -> ( pluss )
+            OP -> ( minus )
+            >lb -> '['
+            >rb -> ']'
+            >pluss -> '+'
+            >minus -> '-'
+            "#;
+        let children = |g: &Grammar, input: &str| match g.parse(input).unwrap() {
+            AST::Node { children, .. } => children,
+            ast => panic!("expected a node, got {:?}", ast),
+        };
+        let g = Grammar::compile(source).unwrap();
+        assert_eq!(children(&g, "++-+").len(), 2);
+
+        let g = Grammar::compile(&format!("@options flatten_repeated\n{}", source)).unwrap();
+        assert!(g.options.flatten_repeated);
+        let ops = children(&g, "++-+[-+]");
+        assert_eq!(ops.len(), 5);
+        match &ops[4] {
+            AST::Node { t, children, .. } => {
+                assert_eq!(t, "OP");
+                assert_eq!(children[1].get_t(), "START");
+            }
+            ast => panic!("expected a node, got {:?}", ast),
+        }
+
+        // nodes of the rule that aren't the last child keep their nesting
+        let source = "START -> ( EXPR )\nEXPR -> ( lp EXPR rp )\nEXPR -> ( num )\n\
+                      >lp -> '('\n>rp -> ')'\n>num -> r'\\d+'";
+        let nested = Grammar::compile(source).unwrap().parse("((1))").unwrap();
+        let g = Grammar::compile(&format!("@options flatten_repeated\n{}", source)).unwrap();
+        assert_eq!(g.parse("((1))").unwrap(), nested);
+    }
+    #[test]
+    fn build_ast() {
+        let ast = AST::node(
+            "FILE",
+            vec![
+                AST::leaf("alpha", "main"),
+                AST::leaf("dot", '.'),
+                AST::node(String::from("EXT"), vec![AST::leaf("alpha", "rs")]),
+            ],
+        );
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            r#"{"type":"FILE","children":[{"type":"alpha","raw":"main"},{"type":"dot","raw":"."},{"type":"EXT","children":[{"type":"alpha","raw":"rs"}]}]}"#
+        );
+    }
+    #[test]
+    fn production_labels() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_whitespace
+            START -> ( VALUE )*
+            VALUE -> negative: ( minus num )
+            VALUE -> ( num )
+            >minus -> '-'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&g.parse("-1 2").unwrap()).unwrap(),
+            r#"{"type":"START","children":[{"type":"negative","children":[{"type":"minus","raw":"-"},{"type":"num","raw":"1"}]},{"type":"VALUE","children":[{"type":"num","raw":"2"}]}]}"#
+        );
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn lookahead() {
+        let source = r#"
+            START -> ( STMT )*
+            STMT -> assign: ( name eq num )
+            STMT -> compare: ( name eqeq num )
+            >eqeq -> '=='
+            >eq -> '='
+            >name -> r'[a-z]+'
+            >num -> r'\d+'
+            "#;
+        let g = Grammar::compile(source).unwrap();
+        assert!(g.parse("a=1").is_ok());
+        assert!(g.parse("a==1").is_err());
+
+        let g = Grammar::compile(&format!("@options lookahead=2\n{}", source)).unwrap();
+        assert_eq!(g.options.lookahead, 2);
+        assert_eq!(
+            serde_json::to_string(&g.parse("a=1b==2").unwrap()).unwrap(),
+            r#"{"type":"START","children":[{"type":"assign","children":[{"type":"name","raw":"a"},{"type":"eq","raw":"="},{"type":"num","raw":"1"}]},{"type":"compare","children":[{"type":"name","raw":"b"},{"type":"eqeq","raw":"=="},{"type":"num","raw":"2"}]}]}"#
+        );
+        assert!(g.parse("a=").is_err());
+        assert_eq!(
+            Grammar::compile(&g.to_string()).unwrap().options.lookahead,
+            2
+        );
+    }
+    #[test]
+    fn ambiguity_warnings() {
+        let source = r#"
+            START -> ( STMT )*
+            STMT -> assign: ( name eq num )
+            STMT -> call: ( name paren )
+            STMT -> compare: ( name eqeq num )
+            >eqeq -> '=='
+            >eq -> '='
+            >paren -> '()'
+            >name -> r'[a-z]+'
+            >num -> r'\d+'
+            "#;
+        let g = Grammar::compile(source).unwrap();
+        let (ast, warnings) = g.parse_with_ambiguity_warnings("a=1b=2").unwrap();
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            serde_json::to_string(&g.parse("a=1b=2").unwrap()).unwrap()
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                AmbiguityWarning {
+                    rule: "STMT".into(),
+                    position: 0,
+                    candidates: vec![0, 1, 2],
+                },
+                AmbiguityWarning {
+                    rule: "STMT".into(),
+                    position: 3,
+                    candidates: vec![0, 1, 2],
+                },
+            ]
+        );
+
+        let g = Grammar::compile(&format!("@options lookahead=2\n{}", source)).unwrap();
+        let (_, warnings) = g.parse_with_ambiguity_warnings("a=1b()").unwrap();
+        assert!(warnings.is_empty());
+    }
+    #[test]
+    fn atom_fragments() {
+        let g = Grammar::compile(
+            r#"
+            @fragment digit
+            START -> ( int )
+            >digit -> r'[0-9]'
+            >int -> r'{digit}+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&g.parse("123").unwrap()).unwrap(),
+            r#"{"type":"START","children":[{"type":"int","raw":"123"}]}"#
+        );
+        assert_eq!(g.atom_names(), vec!["int"]);
+        assert!(g.to_string().contains(r"(?:[0-9])+"));
+
+        // quantifiers and escaped braces are left as they are
+        let g = Grammar::compile(
+            r#"
+            START -> ( a )
+            >digit -> r'[0-9]'
+            >a -> r'\{{digit}{2}\p{Alphabetic}\}'
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse("{12é}").is_ok());
+        assert!(g.parse("{1é}").is_err());
+
+        // names are skipped by characters, not bytes
+        let g = Grammar::compile(
+            r#"
+            @fragment größe
+            START -> ( a )
+            >größe -> r'[0-9]'
+            >a -> r'{größe}x'
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse("1x").is_ok());
+    }
+    #[test]
+    fn atom_fragment_cycle() {
+        let result = Grammar::compile(
+            r#"
+            START -> ( a )
+            >a -> r'x{b}'
+            >b -> r'{a}y'
+            "#,
+        );
+        match result {
+            Err(ParseError::Grammar(msg)) => {
+                assert_eq!(msg, "cycle in atom fragments: a -> b -> a")
+            }
+            r => panic!("expected grammar error, got {:?}", r.map(|g| g.to_string())),
+        }
+    }
+    #[test]
+    fn error_display() {
+        let compile = |source: &str| Grammar::compile(source).unwrap_err().to_string();
+        assert!(compile("START -> ( a )\n>a -> r'('")
+            .starts_with("invalid regex in atom 'a': regex parse error:"));
+        assert_eq!(
+            compile("@options tabs\nSTART -> ( a )\n>a -> 'a'"),
+            "unknown option 'tabs'"
+        );
+        assert_eq!(
+            compile("START -> ( a ){3,1}\n>a -> 'a'"),
+            "quantifier minimum 3 is larger than maximum"
+        );
+        assert_eq!(
+            compile("START -> ( a )\n>a"),
+            "expected token '->' found 'EOF' (at byte 17)"
+        );
+        assert_eq!(
+            ParseError::from(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no such file"
+            ))
+            .to_string(),
+            "no such file"
+        );
+        let error = ParseError::from(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no such file",
+        ));
+        let source = std::error::Error::source(&error).unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        let unclosed = String::from("(");
+        assert!(ParseError::from(Regex::new(&unclosed).unwrap_err())
+            .to_string()
+            .starts_with("regex parse error:"));
+
+        fn parse(source: &str, input: &str) -> Result<AST, Box<dyn std::error::Error>> {
+            Ok(Grammar::compile(source)?.parse(input)?)
+        }
+        assert!(parse("START -> ( a )\n>a -> 'a'", "a").is_ok());
+        assert_eq!(
+            parse("START -> ( a )\n>a -> 'a'", "b")
+                .unwrap_err()
+                .to_string(),
+            "no atom matches the input near 'b' (at byte 0)"
+        );
+    }
+    #[test]
+    fn reachable_atoms() {
+        let g = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        assert_eq!(
+            g.reachable_atoms("PRODUCT"),
+            vec!["minus", "multiply", "divide", "num"]
+        );
+        assert!(!g.reachable_atoms("PRODUCT").contains(&"pluss"));
+        assert_eq!(g.reachable_atoms("OPA"), vec!["pluss", "minus"]);
+        assert_eq!(g.reachable_atoms("START").len(), g.atoms.len());
+        assert!(g.reachable_atoms("UNDEFINED").is_empty());
+    }
+    #[test]
+    fn minimize() {
+        let source = r#"
+            START -> ( SUM )
+            SUM -> ( PRODUCT ( OPA PRODUCT )* )
+            PRODUCT -> ( NUMBER ( OPB NUMBER )* )
+            NUMBER -> ( num )
+            NUMBER -> ( minus num )
+            OPA -> ( ( pluss ) | ( minus ) )
+            OPB -> ( ( multiply ) | ( divide ) )
+            UNUSED -> ( OPA )
+
+            >pluss -> '+'
+            >minus -> '-'
+            >multiply -> 'x'
+            >divide -> '/'
+            >num -> r'\d+'
+        "#;
+        let g = Grammar::compile(source).unwrap();
+        let mut minimized = Grammar::compile(source).unwrap();
+        minimized.minimize();
+        assert_eq!(minimized.rule_names(), vec!["START", "PRODUCT", "NUMBER"]);
+        assert!(minimized.validate().is_empty());
+
+        // the original tree with the nodes of inlined rules replaced by their children
+        fn splice(ast: AST, inlined: &[&str]) -> Vec<AST> {
+            match ast {
+                AST::Node { t, children, .. } => {
+                    let children = children
+                        .into_iter()
+                        .flat_map(|c| splice(c, inlined))
+                        .collect();
+                    if inlined.contains(&t.as_str()) {
+                        children
+                    } else {
+                        vec![AST::node(t, children)]
+                    }
+                }
+                leaf => vec![leaf],
+            }
+        }
+        let json = |ast: &AST| serde_json::to_string(ast).unwrap();
+        for input in ["1", "1+2x3", "-1x-2/3-4", "1/2/3+4+-5"].iter() {
+            let expected = splice(g.parse(input).unwrap(), &["SUM", "OPA", "OPB"]);
+            assert_eq!(json(&minimized.parse(input).unwrap()), json(&expected[0]));
+        }
+        for input in ["", "1+", "+1", "1x2x", "--1"].iter() {
+            assert!(minimized.parse(input).is_err());
+        }
+    }
+    #[test]
+    fn significant_newlines() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_whitespace newlines
+            START -> ( ( STMT )* )
+            STMT -> ( EXPR newline )
+            EXPR -> ( num ( plus num )* )
+            >plus -> '+'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert!(g.validate().is_empty());
+        match g.parse("1\n2\n").unwrap() {
+            AST::Node { children, .. } => {
+                assert_eq!(children.len(), 2);
+                assert!(children.iter().all(|c| c.get_t() == "STMT"));
+            }
+            ast => panic!("expected a node, got {:?}", ast),
+        }
+        let types = g
+            .tokenize("\n1 +\n\n 2\n")
+            .unwrap()
+            .into_iter()
+            .map(|l| l.t)
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec!["num", "plus", "newline", "num", "newline"]);
+        assert!(g.parse("1 + 2\n\n  3\n").is_ok());
+        assert!(g.parse("1\n2").is_err());
+        assert!(g.parse("1 2\n").is_err());
+
+        let g = Grammar::compile(&g.to_string()).unwrap();
+        assert!(g.options.newlines);
+        assert!(g
+            .with_options(ParseOptions::default())
+            .parse("1\n")
+            .is_err());
+    }
+    #[test]
+    fn indentation() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all indentation
+            START -> ( ( STMT )* )
+            STMT -> ( name ( BLOCK )? )
+            BLOCK -> ( colon indent ( STMT )* dedent )
+            >colon -> ':'
+            >name -> r'[a-z]+'
+            "#,
+        )
+        .unwrap();
+        assert!(g.validate().is_empty());
+        let input = "a:\n  b\n  c:\n\n    d\n  e\nf".to_string();
+        let types = g
+            .tokenize(&input)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.t)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                "name", "colon", "indent", "name", "name", "colon", "indent", "name", "dedent",
+                "name", "dedent", "name"
+            ]
+        );
+        assert!(g.parse(&input).is_ok());
+        assert!(g.parse("a:\n  b\n    c").is_err());
+
+        match g.tokenize("a:\n    b\n  c") {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 11);
+                assert_eq!(msg, "dedent doesn't match any outer indentation level");
+            }
+            r => panic!("expected lexer error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn parse_file_errors() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all
+            START -> ( ( num )* $ )
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("gtp-parse-file-{}.txt", std::process::id()));
+        std::fs::write(&path, "1 2\n3 é x").unwrap();
+        let result = g.parse_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "{}:2:3: no atom matches the input near 'é x'",
+                path.display()
+            )
+        );
+
+        let missing = std::env::temp_dir().join("gtp-does-not-exist.txt");
+        match g.parse_file(&missing) {
+            Err(ParseError::File {
+                position: None,
+                error,
+                ..
+            }) => assert!(matches!(*error, ParseError::Io(_))),
+            r => panic!("expected io error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn rule_and_atom_names() {
+        let g = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        assert_eq!(
+            g.rule_names(),
+            vec!["START", "SUM", "PRODUCT", "NUMBER", "OPA", "OPB"]
+        );
+        assert_eq!(
+            g.atom_names(),
+            vec!["pluss", "minus", "multiply", "divide", "num"]
+        );
+    }
+    #[test]
+    fn structurally_equal_after_reload() {
+        let g = Grammar::compile(RAW_GRAMMAR_SUM).unwrap();
+        let reloaded = Grammar::compile(&g.to_string()).unwrap();
+        assert!(g.structurally_equal(&reloaded));
+
+        let other = Grammar::compile(RAW_GRAMMAR_FILES).unwrap();
+        assert!(!g.structurally_equal(&other));
+        let changed = Grammar::compile(&RAW_GRAMMAR_SUM.replace(r"\d+", r"\d")).unwrap();
+        assert!(!g.structurally_equal(&changed));
+    }
+    #[test]
+    fn validate_grammar() {
+        assert!(get_parsing_grammar().validate().is_empty());
+        assert!(Grammar::compile(RAW_GRAMMAR_SUM)
+            .unwrap()
+            .validate()
+            .is_empty());
+
+        // compiling rejects undefined references, so the tree is converted directly
+        let ast = get_parsing_grammar()
+            .parse(
+                r#"
+            START -> ( ITEM )*
+            ITEM -> ( num )
+            ITEM -> ( num dot num )
+            ITEM -> ( ( LIST ) | ( num ) )
+            >num -> r'\d+'
+            "#,
+            )
+            .unwrap();
+        let g = parse_ast_grammar(ast).unwrap();
+        assert_eq!(
+            g.validate(),
+            vec![
+                "rule 'ITEM' references undefined atom 'dot'",
+                "rule 'ITEM' references undefined rule 'LIST'",
+                "rule 'ITEM': productions 1 and 2 can both start with 'num', only the first \
+                 will be used",
+                "rule 'ITEM': productions 1 and 3 can both start with 'num', only the first \
+                 will be used",
+                "rule 'ITEM': productions 2 and 3 can both start with 'num', only the first \
+                 will be used",
+            ]
+        );
+    }
+    #[test]
+    fn parse_ast_grammar_invalid_tree() {
+        let invalid = |ast: AST| match parse_ast_grammar(ast) {
+            Err(ParseError::Grammar(msg)) => msg,
+            r => panic!("expected grammar error, got {:?}", r.map(|g| g.to_string())),
+        };
+        assert_eq!(
+            invalid(AST::leaf("START", "x")),
+            "invalid grammar syntax tree, expected a node, found leaf 'START'"
+        );
+        assert_eq!(
+            invalid(AST::node("START", vec![])),
+            "invalid grammar syntax tree, expected a 'START' node with a single 'DOC' node"
+        );
+        let doc = |item: AST| AST::node("START", vec![AST::node("DOC", vec![item])]);
+        assert_eq!(
+            invalid(doc(AST::node("EXP", vec![]))),
+            "invalid grammar syntax tree, expected another child node"
+        );
+        assert_eq!(
+            invalid(doc(AST::node("OTHER", vec![]))),
+            "invalid grammar syntax tree, expected a rule or atom, found 'OTHER'"
+        );
+    }
+    #[test]
+    fn validate_left_recursion() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( A )
+            A -> ( B x )
+            B -> ( A y )
+            B -> ( x )
+            >x -> 'x'
+            >y -> 'y'
+            "#,
+        )
+        .unwrap();
+        let problems = g.validate();
+        assert!(problems.contains(&"rule 'A' is left recursive through B".to_string()));
+        assert!(!problems
+            .iter()
+            .any(|p| p.starts_with("rule 'B' is left recursive")));
+
+        // recursion after the first symbol is fine
+        let g = Grammar::compile(
+            "START -> ( A )\nA -> ( x B )\nB -> ( y ( A )? )\n>x -> 'x'\n>y -> 'y'",
+        )
+        .unwrap();
+        assert!(g.validate().is_empty());
+    }
+    #[test]
+    fn undefined_references() {
+        let source = "START -> ( ITEM )*\nITEM -> ( num dot num )\n>num -> r'\\d+'";
+        match Grammar::compile(source) {
+            Err(err @ ParseError::Token { start: 33, .. }) => assert_eq!(
+                err.located(source).to_string(),
+                "2:15: rule 'ITEM' references undefined atom 'dot'"
+            ),
+            other => panic!("expected undefined atom, got {:?}", other),
+        }
+
+        let source = "START -> ( ITEM )*\nITEM -> ( ( LIST ) | ( num ) )\n>num -> r'\\d+'";
+        match Grammar::compile(source) {
+            Err(err @ ParseError::Token { start: 31, .. }) => assert_eq!(
+                err.located(source).to_string(),
+                "2:13: rule 'ITEM' references undefined rule 'LIST'"
+            ),
+            other => panic!("expected undefined rule, got {:?}", other),
+        }
+
+        // the marker of the regex is not the undefined atom
+        let source = ">num -> r'\\d+'\nSTART -> ( num r )";
+        match Grammar::compile(source) {
+            Err(err @ ParseError::Token { start: 30, .. }) => assert_eq!(
+                err.located(source).to_string(),
+                "2:16: rule 'START' references undefined atom 'r'"
+            ),
+            other => panic!("expected undefined atom, got {:?}", other),
+        }
+
+        assert!(Grammar::compile("START -> ( num newline )*\n>num -> r'\\d+'").is_ok());
+    }
+    #[test]
+    fn validate_switch_branches() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( SIGN OPT PAIR )
+            SIGN -> ( ( pluss )? | ( minus ) )
+            OPT -> ( ( pluss num ) | ( pluss ) )
+            PAIR -> ( ( num ) | ( NUMBER ) )
+            NUMBER -> ( num )
+            NUMBER -> ( minus num )
+            >pluss -> '+'
+            >minus -> '-'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            g.validate(),
+            vec![
+                "rule 'SIGN': the left side of '|' can be empty, but the right side is \
+                 required whenever the next token can't start the left side",
+                "rule 'OPT': the right side of '|' is never used, all tokens it can start \
+                 with also start the left side",
+                "rule 'PAIR': both sides of '|' can start with 'num'",
+            ]
+        );
+    }
+    #[test]
+    fn anchored_atoms() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( a b c d )
+            >a -> r'^foo'
+            >b -> r'bar$'
+            >c -> r'[$^]\$'
+            >d -> '^$'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            g.validate(),
+            vec![
+                "atom 'a' contains the anchor '^', atoms are always matched at the start of the \
+                 remaining input",
+                "atom 'b' contains the anchor '$', atoms are always matched at the start of the \
+                 remaining input",
+            ]
+        );
+    }
+    #[test]
+    fn grammar_skip_directive() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_whitespace
+            @skip '/*' '*/'
+            @skip '#[^\n]*'
+            START -> ( num )*
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(g.skip.len(), 2);
+        assert!(g.parse("1 /* 2 */ 3 # 4").is_ok());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn flatten_right_recursion() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( OP ( START )? )
+            OP -> ( lb START rb )
+            OP -> ( pluss )
+            OP -> ( minus )
+            >lb -> '['
+            >rb -> ']'
+            >pluss -> '+'
+            >minus -> '-'
+            "#,
+        )
+        .unwrap();
+        let ast = g.parse("+-[+-]+").unwrap().flatten_rule("START");
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            r#"{"type":"START","children":[{"type":"OP","children":[{"type":"pluss","raw":"+"}]},{"type":"OP","children":[{"type":"minus","raw":"-"}]},{"type":"OP","children":[{"type":"lb","raw":"["},{"type":"START","children":[{"type":"OP","children":[{"type":"pluss","raw":"+"}]},{"type":"OP","children":[{"type":"minus","raw":"-"}]}]},{"type":"rb","raw":"]"}]},{"type":"OP","children":[{"type":"pluss","raw":"+"}]}]}"#
+        );
+    }
+    #[test]
+    fn atom_alias() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( num ( pluss num )* )
+            >pluss as PLUS -> '+'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&g.parse("1+2").unwrap()).unwrap(),
+            r#"{"type":"START","children":[{"type":"num","raw":"1"},{"type":"PLUS","raw":"+"},{"type":"num","raw":"2"}]}"#
+        );
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn decode_escapes() {
+        let g = Grammar::compile(
+            r#"
+            @decode string
+            START -> ( string ident )
+            >string -> r'"(\\.|[^"\\])*"'
+            >ident -> r'[a-z\\]+'
+            "#,
+        )
+        .unwrap();
+        let ast = g.parse(r#""a\n b\t\"\u00e9\ud83e\udd80\q"a\n"#).unwrap();
+        let (_, children) = ast.assume_node().unwrap();
+        assert_eq!(
+            children[0].clone().assume_leaf().unwrap().1,
+            "\"a\n b\t\"é🦀\\q\""
+        );
+        assert_eq!(children[1].clone().assume_leaf().unwrap().1, "a\\n");
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+    }
+    #[test]
+    fn folded_rules() {
+        let g = Grammar::compile(
+            r#"
+            @fold left SUM
+            @fold right POW
+            START -> ( SUM )
+            SUM -> ( POW ( minus POW )* )
+            POW -> ( num ( pow num )* )
+            >minus -> '-'
+            >pow -> '^'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert!(g.validate().is_empty());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+        let g = g.with_options(ParseOptions {
+            bubble_intermediate: true,
+            ..ParseOptions::default()
+        });
+        let num = |n: &str| AST::leaf("num", n);
+        let sub = |a, b| AST::node("SUM", vec![a, AST::leaf("minus", "-"), b]);
+        let pow = |a, b| AST::node("POW", vec![a, AST::leaf("pow", "^"), b]);
+
+        assert_eq!(g.parse("1").unwrap(), num("1"));
+        assert_eq!(g.parse("1-2").unwrap(), sub(num("1"), num("2")));
+        assert_eq!(
+            g.parse("1-2-3").unwrap(),
+            sub(sub(num("1"), num("2")), num("3"))
+        );
+        assert_eq!(
+            g.parse("1-2-3-4").unwrap(),
+            sub(sub(sub(num("1"), num("2")), num("3")), num("4"))
+        );
+        assert_eq!(
+            g.parse("2^3^4").unwrap(),
+            pow(num("2"), pow(num("3"), num("4")))
+        );
+        assert_eq!(
+            g.parse("1-2^3^4-5").unwrap(),
+            sub(
+                sub(num("1"), pow(num("2"), pow(num("3"), num("4")))),
+                num("5")
+            )
+        );
+
+        // folded rules are kept by `minimize`
+        let mut minimized = g.clone();
+        minimized.minimize();
+        assert_eq!(minimized.rule_names(), vec!["START", "SUM", "POW"]);
+        assert!(minimized.validate().is_empty());
+        assert_eq!(
+            minimized.parse("1-2-3").unwrap(),
+            sub(sub(num("1"), num("2")), num("3"))
+        );
+
+        let g = Grammar::compile("@fold left START\nSTART -> ( num )*\n>num -> r'\\d+'").unwrap();
+        assert_eq!(
+            g.validate(),
+            vec!["rule 'START' is folded, but none of its productions end with a repeated group"]
+        );
+        assert!(Grammar::compile("@fold up START\nSTART -> ( num )\n>num -> '1'").is_err());
+    }
+    #[test]
+    fn transparent_rules() {
+        let g = Grammar::compile(
+            r#"
+            @transparent ITEMS
+            START -> ( LIST )
+            LIST -> ( lb ( ITEMS )? rb )
+            ITEMS -> ( num ( comma num )* )
+            >lb -> '['
+            >rb -> ']'
+            >comma -> ','
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        let ast = g.parse("[1,2,3]").unwrap();
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            concat!(
+                r#"{"type":"START","children":[{"type":"LIST","children":["#,
+                r#"{"type":"lb","raw":"["},{"type":"num","raw":"1"},{"type":"comma","raw":","},"#,
+                r#"{"type":"num","raw":"2"},{"type":"comma","raw":","},{"type":"num","raw":"3"},"#,
+                r#"{"type":"rb","raw":"]"}]}]}"#
+            )
+        );
+        assert!(g.validate().is_empty());
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+        assert!(matches!(
+            g.parse_rule_public("ITEMS", "1,2"),
+            Err(ParseError::Grammar(_))
+        ));
+
+        let g = Grammar::compile("@transparent START OTHER\nSTART -> ( num )\n>num -> r'\\d+'")
+            .unwrap();
+        assert_eq!(
+            g.validate(),
+            vec![
+                "rule 'START' can't be transparent",
+                "undefined rule 'OTHER' is transparent"
+            ]
+        );
+    }
+    #[test]
+    fn recover_at_closing_delimiter() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all
+            @recover lb rb
+            START -> ( BLOCK )*
+            BLOCK -> ( lb ( STMT )* rb )
+            STMT -> ( name eq VALUE semi )
+            STMT -> ( BLOCK )
+            VALUE -> ( num )
+            >lb -> '{'
+            >rb -> '}'
+            >eq -> '='
+            >semi -> ';'
+            >num -> r'\d+'
+            >name -> r'[a-z]+'
+            "#,
+        )
+        .unwrap();
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+
+        let input = "{ a = 1; } { b = ; { c = 2; } d } { e = 3; }";
+        assert!(g.parse(input).is_err());
+        let (ast, errors) = g.parse_recovering(input);
+        match errors.as_slice() {
+            [ParseError::Token { start, msg, .. }] => {
+                assert_eq!(*start, 17);
+                assert_eq!(
+                    msg,
+                    "unexpected token 'semi' when trying to parse rule 'VALUE'"
+                );
+            }
+            e => panic!("expected one token error, got {:?}", e),
+        }
+        let ast = ast.unwrap();
+        let (_, blocks) = ast.clone().assume_node().unwrap();
+        assert_eq!(blocks.len(), 3);
+        // the error in the second block is skipped up to its closing `}`
+        assert_eq!(
+            blocks[1].leaves().collect::<Vec<_>>(),
+            vec![
+                ("lb", "{"),
+                ("name", "b"),
+                ("eq", "="),
+                ("ERROR", "; { c = 2; } d "),
+                ("rb", "}")
+            ]
+        );
+        assert_eq!(
+            blocks[2].leaves().map(|(_, raw)| raw).collect::<String>(),
+            "{e=3;}"
+        );
+
+        // without a closing delimiter the error can't be recovered from
+        let (ast, errors) = g.parse_recovering("{ a = 1; } { b = ;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(ast.unwrap().assume_node().unwrap().1.len(), 2);
+    }
+    #[test]
+    fn production_order() {
+        let mut g = Grammar::compile(
+            r#"
+            START -> ( X )
+            X -> ( a b )
+            X -> ( a c )
+            >a -> 'a'
+            >b -> 'b'
+            >c -> 'c'
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse("ab").is_ok());
+        assert!(g.parse("ac").is_err());
+
+        // the order index decides, not the position in the rules vector
+        g.rules.reverse();
+        assert!(g.parse("ab").is_ok());
+        assert!(g.parse("ac").is_err());
+
+        // with equal order the position in the rules vector decides
+        g.rules.iter_mut().for_each(|r| r.order = 0);
+        assert!(g.parse("ab").is_err());
+        assert!(g.parse("ac").is_ok());
+    }
+    #[test]
+    fn parse_into_custom_type() {
+        #[derive(Debug, PartialEq)]
+        struct File {
+            name: String,
+            extension: Option<String>,
+        }
+        impl FromAst for File {
+            fn from_ast(ast: &AST) -> Result<Self, String> {
+                let parts = Vec::<String>::from_ast(ast)?;
+                match parts.as_slice() {
+                    [name] => Ok(File {
+                        name: name.clone(),
+                        extension: None,
+                    }),
+                    [name, _, extension] => Ok(File {
+                        name: name.clone(),
+                        extension: Some(extension.clone()),
+                    }),
+                    _ => Err(format!("unexpected file {:?}", parts)),
+                }
+            }
+        }
+
+        let g = Grammar::compile(RAW_GRAMMAR_FILES)
+            .unwrap()
+            .with_options(ParseOptions {
+                ignore_whitespace: true,
+                ..ParseOptions::default()
+            });
+        let files: Vec<File> = g.parse_into("README.md main").unwrap();
+        assert_eq!(
+            files,
+            vec![
+                File {
+                    name: "README".into(),
+                    extension: Some("md".into()),
+                },
+                File {
+                    name: "main".into(),
+                    extension: None,
+                },
+            ]
+        );
+        match g.parse_into::<String>("main") {
+            Err(ParseError::Convert(msg)) => assert_eq!(msg, "expected a leaf, found node 'START'"),
+            r => panic!("expected conversion error, got {:?}", r),
+        }
     }
     #[test]
     fn parse_with_parsed_grammar() {
         let g = get_parsing_grammar();
-        let ast = g.parse(&RAW_GRAMMAR_FILES.into()).unwrap();
-        let gp = parse_ast_grammar(ast).with_options(ParseOptions {
+        let ast = g.parse(RAW_GRAMMAR_FILES).unwrap();
+        let gp = parse_ast_grammar(ast).unwrap().with_options(ParseOptions {
             ignore_newline: true,
             ignore_whitespace: true,
             bubble_intermediate: true,
+            ..ParseOptions::default()
         });
         assert_eq!(
-            serde_json::to_string(&gp.parse(&"fileA".into()).unwrap()).unwrap(),
+            serde_json::to_string(&gp.parse("fileA").unwrap()).unwrap(),
             r#"{"type":"alpha","raw":"fileA"}"#
         );
         assert_eq!(
-            serde_json::to_string(&gp.parse(&"fileA.md".into()).unwrap()).unwrap(),
+            serde_json::to_string(&gp.parse("fileA.md").unwrap()).unwrap(),
             r#"{"type":"FILE","children":[{"type":"alpha","raw":"fileA"},{"type":"dot","raw":"."},{"type":"alpha","raw":"md"}]}"#
         );
         assert_eq!(
-            serde_json::to_string(&gp.parse(&"fileA fileB".into()).unwrap()).unwrap(),
+            serde_json::to_string(&gp.parse("fileA fileB").unwrap()).unwrap(),
             r#"{"type":"START","children":[{"type":"alpha","raw":"fileA"},{"type":"alpha","raw":"fileB"}]}"#
         );
     }
+    #[test]
+    fn wildcard_skips_to_sentinel() {
+        let g = Grammar::compile(
+            r#"
+            @options ignore_all
+            START -> ( kw SKIP num )
+            SKIP -> ( ( semi ) | ( _ SKIP ) )
+
+            >kw -> 'skip'
+            >semi -> ';'
+            >num -> r'\d+'
+            >name -> r'[a-z]+'
+            >eq -> '='
+            "#,
+        )
+        .unwrap();
+        assert!(g.to_string().contains("-> ( ( semi ) | ( _ SKIP ) )"));
+        let leaves = |input: &str| {
+            g.parse(input)
+                .unwrap()
+                .leaves()
+                .map(|(t, raw)| format!("{}:{}", t, raw))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            leaves("skip a = 1 ; 42"),
+            vec!["kw:skip", "name:a", "eq:=", "num:1", "semi:;", "num:42"]
+        );
+        assert_eq!(leaves("skip ; 7"), vec!["kw:skip", "semi:;", "num:7"]);
+        assert!(g.parse("skip a = 1").is_err());
+    }
+    #[test]
+    fn error_display_variants() {
+        let input = "a\nbc d";
+        let file = |position| ParseError::File {
+            path: "in.txt".into(),
+            position,
+            error: Box::new(ParseError::Input(5, "unexpected 'd'".into())),
+        };
+        let cases = vec![
+            (
+                ParseError::Lexem(2, "bad lexem".into()),
+                "bad lexem (at byte 2)",
+                "2:1: bad lexem",
+            ),
+            (
+                ParseError::Input(5, "unexpected 'd'".into()),
+                "unexpected 'd' (at byte 5)",
+                "2:4: unexpected 'd'",
+            ),
+            (
+                ParseError::Token {
+                    start: 3,
+                    len: 1,
+                    msg: "bad token".into(),
+                },
+                "bad token (at byte 3)",
+                "2:2: bad token",
+            ),
+            (
+                ParseError::NoMatch("no rule named 'X'".into()),
+                "no rule named 'X'",
+                "no rule named 'X'",
+            ),
+            (
+                ParseError::Grammar("bad grammar".into()),
+                "bad grammar",
+                "bad grammar",
+            ),
+            (
+                ParseError::Convert("bad tree".into()),
+                "bad tree",
+                "bad tree",
+            ),
+            (
+                ParseError::from(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "not found",
+                )),
+                "not found",
+                "not found",
+            ),
+            (
+                ParseError::InputTooLarge { size: 10, max: 5 },
+                "input is 10 bytes, more than the maximum of 5 bytes",
+                "input is 10 bytes, more than the maximum of 5 bytes",
+            ),
+            (
+                file(Some((2, 4))),
+                "in.txt:2:4: unexpected 'd'",
+                "in.txt:2:4: unexpected 'd'",
+            ),
+            (
+                file(None),
+                "in.txt: unexpected 'd' (at byte 5)",
+                "in.txt: unexpected 'd' (at byte 5)",
+            ),
+        ];
+        for (error, display, located) in cases {
+            assert_eq!(error.to_string(), display);
+            assert_eq!(error.located(input).to_string(), located);
+        }
+    }
+    #[test]
+    fn hidden_atoms() {
+        let source = r#"
+            @options ignore_all
+            START -> ( num ( slash num )* )
+
+            >comment hidden -> r'//[^\n]*'
+            >slash -> '/'
+            >num -> r'\d+'
+            "#;
+        let g = Grammar::compile(source).unwrap();
+        assert!(g.validate().is_empty());
+        assert!(g.to_string().contains(">comment hidden -> r'//[^\\n]*'"));
+        assert!(g.structurally_equal(&Grammar::compile(&g.to_string()).unwrap()));
+
+        let input = "1 // one\n/ 2 // two\n//\n/3";
+        let tokens = g.tokenize(input).unwrap();
+        assert_eq!(
+            tokens.iter().map(|l| l.raw.as_str()).collect::<Vec<_>>(),
+            vec!["1", "/", "2", "/", "3"]
+        );
+        assert!(g.parse(input).is_ok());
+
+        let g =
+            Grammar::compile(&source.replace("( slash num )*", "( slash num comment )*")).unwrap();
+        assert_eq!(
+            g.validate(),
+            vec!["rule 'START' references hidden atom 'comment', it is always skipped"]
+        );
+        match Grammar::compile(&source.replace("comment hidden", "comment hiden")) {
+            Err(ParseError::Grammar(msg)) => assert_eq!(
+                msg,
+                "unknown modifier 'hiden' of atom 'comment', expected 'as' or 'hidden'"
+            ),
+            r => panic!("expected grammar error, got {:?}", r.map(|g| g.to_string())),
+        }
+
+        // skipped text is checked like lexems
+        let g = Grammar::compile(&source.replace(r"r'//[^\n]*'", "balanced('/* */')")).unwrap();
+        assert!(g.parse("1 /* one */ / 2").is_ok());
+        match g.parse("1 /* open 2 3") {
+            Err(ParseError::Lexem(2, msg)) => assert_eq!(msg, "unterminated '/*', expected '*/'"),
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn escaped_literals() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( word ( tab word )* newline )
+
+            >tab -> '\t'
+            >newline -> '\u000a'
+            >word -> r'[a-z\\]+'
+            "#,
+        )
+        .unwrap();
+        let tokens = g.tokenize("a\tb\\c\n").unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|l| (l.t.as_str(), l.raw.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("word", "a"),
+                ("tab", "\t"),
+                ("word", "b\\c"),
+                ("newline", "\n")
+            ]
+        );
+        assert!(g.parse("a\tb\n").is_ok());
+
+        let written = g.to_string();
+        assert!(written.contains(r">tab            -> '\t'"));
+        assert!(written.contains(r">newline        -> '\n'"));
+        assert!(g.structurally_equal(&Grammar::compile(&written).unwrap()));
+        let backslash = Grammar::compile("START -> ( bs )\n>bs -> '\\\\'").unwrap();
+        assert!(backslash.parse("\\").is_ok());
+        assert!(backslash.to_string().contains(r">bs             -> '\\'"));
+    }
 }
@@ -1,5 +1,48 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+/// `log::debug!` with the `std` feature, nothing without it. Also a `tracing` event with the
+/// `tracing` feature, so it is shown inside the current span.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "std")]
+        log::debug!($($arg)*);
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+/// Enter a `tracing` span until the end of the current block with the `tracing` feature,
+/// nothing without it
+macro_rules! span {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($arg)*).entered();
+    };
+}
+
+#[cfg(feature = "std")]
+mod cache;
+mod cst;
+#[cfg(feature = "std")]
+mod diff;
+#[cfg(feature = "std")]
+mod ebnf;
+#[cfg(feature = "std")]
 pub mod grammar;
+#[cfg(feature = "std")]
+mod macros;
 pub mod parsing;
+#[cfg(feature = "std")]
+mod sample;
 
+pub use cst::CST;
+#[cfg(feature = "std")]
+pub use diff::{Change, GrammarDiff};
+#[cfg(feature = "std")]
 pub use grammar::*;
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub use macros::{__grammar_atom, __grammar_switches};
 pub use parsing::*;
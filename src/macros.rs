@@ -0,0 +1,190 @@
+use crate::parsing::*;
+use regex::Regex;
+
+/// Declare a `Grammar` with the same syntax as text grammars, except that every rule and atom
+/// ends with `;` and atom regexes are string literals.
+///
+/// ```
+/// let g = gtp::grammar! {
+///     START -> ( num ( pluss num )* );
+///     >pluss -> r"\+";
+///     >num -> r"\d+";
+/// };
+/// assert!(g.parse("1+2").is_ok());
+/// ```
+#[macro_export]
+macro_rules! grammar {
+    ( $($body:tt)* ) => {
+        $crate::__grammar_items!([] [] $($body)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __grammar_items {
+    ([$($rules:expr,)*] [$($atoms:expr,)*]) => {
+        $crate::Grammar {
+            rules: vec![$($rules,)*],
+            atoms: vec![$($atoms,)*],
+            ..$crate::Grammar::default()
+        }
+    };
+    ($rules:tt [$($atoms:expr,)*] > $name:ident -> $re:literal ; $($rest:tt)*) => {
+        $crate::__grammar_items!(
+            $rules
+            [$($atoms,)* $crate::__grammar_atom(stringify!($name), $re),]
+            $($rest)*
+        )
+    };
+    ([$($rules:expr,)*] $atoms:tt $name:ident -> ( $($p:tt)* ) * ; $($rest:tt)*) => {
+        $crate::__grammar_items!(
+            [$($rules,)* $crate::Rule {
+                name: stringify!($name).into(),
+                order: 0,
+                label: None,
+                production: $crate::SymbolType::rep($crate::__grammar_prod!($($p)*)),
+            },]
+            $atoms
+            $($rest)*
+        )
+    };
+    ([$($rules:expr,)*] $atoms:tt $name:ident -> ( $($p:tt)* ) ? ; $($rest:tt)*) => {
+        $crate::__grammar_items!(
+            [$($rules,)* $crate::Rule {
+                name: stringify!($name).into(),
+                order: 0,
+                label: None,
+                production: $crate::SymbolType::opt($crate::__grammar_prod!($($p)*)),
+            },]
+            $atoms
+            $($rest)*
+        )
+    };
+    ([$($rules:expr,)*] $atoms:tt $name:ident -> ( $($p:tt)* ) ; $($rest:tt)*) => {
+        $crate::__grammar_items!(
+            [$($rules,)* $crate::Rule {
+                name: stringify!($name).into(),
+                order: 0,
+                label: None,
+                production: $crate::__grammar_prod!($($p)*),
+            },]
+            $atoms
+            $($rest)*
+        )
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __grammar_prod {
+    ( $($p:tt)* ) => {
+        $crate::__grammar_switches($crate::__grammar_seq!([] $($p)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __grammar_seq {
+    ([$($items:expr,)*]) => {
+        vec![$($items,)*]
+    };
+    ([$($items:expr,)*] | $($rest:tt)*) => {
+        $crate::__grammar_seq!([$($items,)* None,] $($rest)*)
+    };
+    ([$($items:expr,)*] ( $($g:tt)* ) * $($rest:tt)*) => {
+        $crate::__grammar_seq!(
+            [$($items,)* Some($crate::SymbolType::rep($crate::__grammar_prod!($($g)*))),]
+            $($rest)*
+        )
+    };
+    ([$($items:expr,)*] ( $($g:tt)* ) ? $($rest:tt)*) => {
+        $crate::__grammar_seq!(
+            [$($items,)* Some($crate::SymbolType::opt($crate::__grammar_prod!($($g)*))),]
+            $($rest)*
+        )
+    };
+    ([$($items:expr,)*] ( $($g:tt)* ) $($rest:tt)*) => {
+        $crate::__grammar_seq!([$($items,)* Some($crate::__grammar_prod!($($g)*)),] $($rest)*)
+    };
+    ([$($items:expr,)*] _ $($rest:tt)*) => {
+        $crate::__grammar_seq!([$($items,)* Some($crate::SymbolType::any()),] $($rest)*)
+    };
+    ([$($items:expr,)*] $name:ident $($rest:tt)*) => {
+        $crate::__grammar_seq!(
+            [$($items,)* Some($crate::SymbolType::named(stringify!($name))),]
+            $($rest)*
+        )
+    };
+}
+
+#[doc(hidden)]
+pub fn __grammar_atom(name: &str, re: &str) -> Atom {
+    Atom::Matched {
+        name: name.into(),
+        m: Box::new(Regex::new(re).unwrap()),
+    }
+}
+
+/// Build a group from the symbols of a production, where `None` marks a `|` between the
+/// previous and next symbol
+#[doc(hidden)]
+pub fn __grammar_switches(items: Vec<Option<SymbolType>>) -> SymbolType {
+    let mut children = Vec::new();
+    let mut items = items.into_iter();
+    while let Some(item) = items.next() {
+        match item {
+            Some(s) => children.push(s),
+            None => {
+                let lhs = children.pop().expect("'|' without left hand side");
+                let rhs = items.next().flatten().expect("'|' without right hand side");
+                children.push(SymbolType::switch(lhs, rhs));
+            }
+        }
+    }
+    SymbolType::Group(children)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn macro_matches_compiled_grammar() {
+        let compiled = Grammar::compile(&format!(
+            "{}\nLIST -> ( num )*\nMAYBE -> ( num )?",
+            crate::grammar::RAW_GRAMMAR_SUM
+        ))
+        .unwrap();
+        let declared = crate::grammar! {
+            START -> ( SUM );
+            SUM -> ( PRODUCT ( OPA PRODUCT )* );
+            PRODUCT -> ( NUMBER ( OPB NUMBER )* );
+            NUMBER -> ( num );
+            NUMBER -> ( minus num );
+            OPA -> ( ( pluss ) | ( minus ) );
+            OPB -> ( ( multiply ) | ( divide ) );
+            LIST -> ( num )*;
+            MAYBE -> ( num )?;
+
+            >pluss -> r"\+";
+            >minus -> r"\-";
+            >multiply -> "x";
+            >divide -> "/";
+            >num -> r"\d+";
+        };
+        assert!(compiled.structurally_equal(&declared));
+        for input in ["1", "1+2x3", "-1x2/3-4", "1+"].iter() {
+            let input = input.to_string();
+            assert_eq!(
+                compiled
+                    .parse(&input)
+                    .ok()
+                    .map(|a| serde_json::to_string(&a).unwrap()),
+                declared
+                    .parse(&input)
+                    .ok()
+                    .map(|a| serde_json::to_string(&a).unwrap()),
+            );
+        }
+    }
+}
@@ -1,71 +1,271 @@
-mod grammar;
-mod parsing;
-
 use clap::Clap;
-use grammar::*;
-use parsing::*;
+use gtp::*;
 use std::fs;
-use std::io::{self, Read};
-
-fn get_line_from_pos(mut pos: usize, input: &String) -> (usize, usize, &str) {
-    let mut lines = input.split("\n");
-    let mut line_nr = 0;
-    let mut prev_line = lines.next().unwrap();
-    for line in lines {
-        if prev_line.len() >= pos {
-            break;
-        } else {
-            pos -= prev_line.len() + 1;
-            prev_line = line;
-            line_nr += 1;
-        }
-    }
-    return (pos, line_nr, prev_line);
-}
+use std::io::{self, BufRead, Read, Write};
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
 
-fn print_error(err: ParseError, input: &String) {
+fn format_error(err: ParseError, input: &str, tab_width: usize) -> String {
     match err {
         ParseError::Lexem(pos, msg) | ParseError::Input(pos, msg) => {
-            let (pos, line_nr, line) = get_line_from_pos(pos, input);
-            eprintln!("{:>3}. | {}", line_nr + 1, line);
-            eprintln!("     | {}^ {}", vec![" "; pos].join(""), msg);
+            format_position(pos, 0, &msg, input, tab_width)
         }
-        ParseError::NoMatch(msg) => {
-            eprintln!("{}", msg);
+        ParseError::Token { start, len, msg } => {
+            format_position(start, len, &msg, input, tab_width)
         }
+        err => format!("{}\n", err),
     }
 }
 
-fn print_output(ast: &AST, format: &Format) {
-    match format {
-        Format::Json => {
-            println!("{}", serde_json::to_string(&ast).unwrap());
+/// The line containing the byte offset `pos`, with the `len` bytes from it underlined, or a
+/// single caret if `len` is 0. Tabs are expanded to `tab_width` columns, and the caret is
+/// placed by display width, so wide characters like `漢` and `🦀` take two columns.
+fn format_position(pos: usize, len: usize, msg: &str, input: &str, tab_width: usize) -> String {
+    let pos = pos.min(input.len());
+    let (line_nr, _) = line_column_with_tabs(input, pos, tab_width);
+    let line_start = input[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = input[line_start..].split('\n').next().unwrap_or_default();
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let column = |pos: usize| line_column_with_tabs(line, pos, tab_width).1;
+    // the first `end` bytes of the line as shown, with tabs expanded
+    let shown = |end: usize| {
+        let mut shown = String::with_capacity(end);
+        for (i, c) in line[..end].char_indices() {
+            match c {
+                '\t' => shown.push_str(&" ".repeat(column(i + 1) - column(i))),
+                c => shown.push(c),
+            }
         }
+        shown
+    };
+    // the underline stops at the end of the line
+    let start = (pos - line_start).min(line.len());
+    let end = (pos + len - line_start).min(line.len());
+    let (col, end_col) = (shown(start).width(), shown(end).width());
+    format!(
+        "{:>3}. | {}\n     | {}{} {}\n",
+        line_nr,
+        shown(line.len()),
+        " ".repeat(col),
+        "^".repeat(end_col.saturating_sub(col).max(1)),
+        msg
+    )
+}
+
+fn print_error(err: ParseError, input: &str, tab_width: usize) {
+    eprint!("{}", format_error(err, input, tab_width));
+}
+
+fn read_from<R: Read>(mut reader: R, name: &str) -> Result<String, String> {
+    let mut buffer = String::new();
+    reader
+        .read_to_string(&mut buffer)
+        .map_err(|e| format!("could not read {}: {}", name, e))?;
+    Ok(buffer)
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let file = fs::File::open(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    read_from(file, path)
+}
+
+/// Text of the grammar, read from `stdin` if its path is `-`. The input can't be read from
+/// stdin too then.
+fn read_grammar<R: Read>(opts: &Opts, stdin: R) -> Result<String, String> {
+    if opts.grammar != "-" {
+        return read_file(&opts.grammar);
+    }
+    if opts.stdin || opts.repl {
+        return Err("the grammar is read from stdin, so the input can't be, \
+                    give it as an argument or with --input-file instead"
+            .into());
+    }
+    read_from(stdin, "stdin")
+}
+
+fn exit_with_error(msg: String) -> ! {
+    eprintln!("error: {}", msg);
+    std::process::exit(1);
+}
+
+fn format_duration(name: &str, duration: Duration) -> String {
+    format!("{:<9} {:>9.3} ms", name, duration.as_secs_f64() * 1000.0)
+}
+
+/// Total and average time of `iterations` parses taking `elapsed` together, with the
+/// throughput and the size of the tree of the input
+fn format_benchmark(
+    iterations: u32,
+    elapsed: Duration,
+    tokens: usize,
+    bytes: usize,
+    tree: usize,
+) -> String {
+    let secs = elapsed.as_secs_f64();
+    let parsed = iterations as f64;
+    [
+        format_duration("total", elapsed),
+        format_duration("average", elapsed / iterations),
+        format!(
+            "{:<8} {:>10.0} tokens/s",
+            "tokens",
+            tokens as f64 * parsed / secs
+        ),
+        format!(
+            "{:<8} {:>10.3} MB/s",
+            "bytes",
+            bytes as f64 * parsed / secs / 1e6
+        ),
+        format!("{:<8} {:>10} nodes", "tree", tree),
+    ]
+    .join("\n")
+}
+
+/// The tree, or other output, as json, indented if `pretty`, or as a yaml document always
+/// starting with `---`, so that the output of several trees is a yaml stream
+fn format_output<T: serde::Serialize>(output: &T, format: &Format, pretty: bool) -> String {
+    match format {
+        Format::Json if pretty => serde_json::to_string_pretty(output).unwrap(),
+        Format::Json => serde_json::to_string(output).unwrap(),
         Format::Yaml => {
-            println!("{}", serde_yaml::to_string(&ast).unwrap());
+            let yaml = serde_yaml::to_string(output).unwrap();
+            let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml);
+            format!("---\n{}", yaml.trim_end_matches('\n'))
         }
     }
 }
 
+fn print_output<T: serde::Serialize>(output: &T, format: &Format, pretty: bool) {
+    println!("{}", format_output(output, format, pretty));
+}
+
+/// Type, raw text and byte offset of a lexem, serialized in that order for `--tokens`
+struct TokenOutput<'a> {
+    t: &'a str,
+    raw: &'a str,
+    offset: usize,
+}
+
+impl serde::Serialize for TokenOutput<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("type", self.t)?;
+        map.serialize_entry("raw", self.raw)?;
+        map.serialize_entry("offset", &self.offset)?;
+        map.end()
+    }
+}
+
+/// Every lexem for the output of `--tokens`
+fn token_output(lexems: &[Lexem]) -> Vec<TokenOutput<'_>> {
+    lexems
+        .iter()
+        .map(|l| TokenOutput {
+            t: &l.t,
+            raw: &l.raw,
+            offset: l.start,
+        })
+        .collect()
+}
+
+/// The options declared by the grammar, overridden by the flags turning them on or off
+fn parse_options(opts: &Opts, mut o: ParseOptions) -> ParseOptions {
+    let flag = |option: &mut bool, on: bool, off: bool| {
+        if on {
+            *option = true;
+        } else if off {
+            *option = false;
+        }
+    };
+    let all = opts.ignore_all;
+    // the flags of single options win over --ignore-all
+    flag(&mut o.ignore_newline, all, false);
+    flag(&mut o.ignore_whitespace, all, false);
+    flag(
+        &mut o.ignore_newline,
+        opts.ignore_newline,
+        opts.no_ignore_newline,
+    );
+    flag(
+        &mut o.ignore_whitespace,
+        opts.ignore_whitespace,
+        opts.no_ignore_whitespace,
+    );
+    flag(&mut o.bubble_intermediate, opts.bubble, opts.no_bubble);
+    if let Some(max) = opts.max_input_bytes {
+        o.max_input_bytes = max;
+    }
+    o.tab_width = opts.tab_width;
+    o
+}
+
+/// Parse every line read from `input`, writing the tree to `out` or the error to `err`
+fn repl<R: BufRead>(
+    grammar: &Grammar,
+    input: R,
+    format: &Format,
+    pretty: bool,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        match grammar.parse(&line) {
+            Ok(ast) => writeln!(out, "{}", format_output(&ast, format, pretty))?,
+            Err(e) => write!(err, "{}", format_error(e, &line, grammar.options.tab_width))?,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
 /// Parse input text with provided grammar, output parsed syntax tree
 #[derive(Clap)]
 struct Opts {
-    /// File containing grammar
+    /// File containing grammar, or `-` to read it from stdin
     grammar: String,
 
     /// Format of output
     #[clap(short, long, default_value = "json")]
     output: Format,
+    /// Indent json output over multiple lines, yaml output always is
+    #[clap(long)]
+    pretty: bool,
 
-    // input types
+    // input types, at most one can be given. Without input the compiled grammar is printed.
     /// Input to parse
+    #[clap(conflicts_with_all = &["input-file", "stdin"])]
     input: Option<String>,
     /// Read input text from file instead of arg
-    #[clap(short, long)]
+    #[clap(short, long, conflicts_with = "stdin")]
     input_file: Option<String>,
     /// Read input text from stdin
     #[clap(long)]
     stdin: bool,
+    /// Parse each line read from stdin until EOF, printing the tree or error for every line
+    #[clap(long, conflicts_with_all = &["input", "input-file", "stdin"])]
+    repl: bool,
+    /// Only compile and validate the grammar, exit with an error if any problems are found
+    #[clap(long, alias = "dry-run")]
+    check: bool,
+    /// Directory to cache compiled grammars in, so runs with an unchanged grammar skip
+    /// compiling it
+    #[clap(long, value_name = "DIR")]
+    grammar_cache: Option<String>,
+    /// Print how long compiling the grammar, lexing the input, and lexing and parsing it took to
+    /// stderr
+    #[clap(long)]
+    measure: bool,
+    /// Parse the input this many times and print the throughput to stderr instead of the tree
+    #[clap(long, value_name = "ITERATIONS", conflicts_with_all = &["repl", "check"])]
+    benchmark_grammar: Option<u32>,
+    /// Only lex the input, and output the type, raw text and byte offset of every token
+    #[clap(long, conflicts_with_all = &["repl", "check", "benchmark-grammar"])]
+    tokens: bool,
 
     // parse options:
     /// Set all ignore options to true
@@ -74,12 +274,27 @@ struct Opts {
     /// Skip newlines in input
     #[clap(long)]
     ignore_newline: bool,
+    /// Don't skip newlines, even if the grammar or --ignore-all does
+    #[clap(long, conflicts_with = "ignore-newline")]
+    no_ignore_newline: bool,
     /// Skip whitespaces in input
     #[clap(long)]
     ignore_whitespace: bool,
+    /// Don't skip whitespaces, even if the grammar or --ignore-all does
+    #[clap(long, conflicts_with = "ignore-whitespace")]
+    no_ignore_whitespace: bool,
     /// Remove intermediate nodes in the ast with only one child, making the child "bubble up"
     #[clap(long)]
     bubble: bool,
+    /// Keep intermediate nodes, even if the grammar removes them
+    #[clap(long, conflicts_with = "bubble")]
+    no_bubble: bool,
+    /// Reject inputs larger than this many bytes
+    #[clap(long)]
+    max_input_bytes: Option<usize>,
+    /// Columns between tab stops when showing where errors are
+    #[clap(long, default_value = "1")]
+    tab_width: usize,
 }
 
 enum Format {
@@ -104,53 +319,399 @@ fn main() {
 
     let opts: Opts = Opts::parse();
 
-    let g = get_parsing_grammar();
-
-    let raw_grammar = fs::read_to_string(opts.grammar).expect("could not read grammar file");
+    let raw_grammar = read_grammar(&opts, io::stdin()).unwrap_or_else(|e| exit_with_error(e));
 
-    let ast = match g.parse(&raw_grammar) {
-        Ok(ast) => ast,
+    let start = Instant::now();
+    let compiled = match &opts.grammar_cache {
+        Some(dir) => Grammar::compile_cached(&raw_grammar, dir).map(|(grammar, _)| grammar),
+        None => Grammar::compile(&raw_grammar),
+    };
+    let grammar = match compiled {
+        Ok(grammar) => grammar,
         Err(err) => {
-            print_error(err, &raw_grammar);
+            print_error(err, &raw_grammar, opts.tab_width);
             std::process::exit(1);
         }
     };
+    if opts.measure {
+        eprintln!("{}", format_duration("compile", start.elapsed()));
+    }
 
-    let options = {
-        let mut o = ParseOptions::default();
-        let all = opts.ignore_all;
-        o.ignore_newline = opts.ignore_newline || all;
-        o.ignore_whitespace = opts.ignore_whitespace || all;
-        o.bubble_intermediate = opts.bubble;
-        o
-    };
+    if opts.check {
+        let problems = grammar.validate();
+        for problem in problems.iter() {
+            eprintln!("error: {}", problem);
+        }
+        std::process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
 
-    let grammar = parse_ast_grammar(ast).with_options(options);
+    let options = parse_options(&opts, grammar.options.clone());
+    let grammar = grammar.with_options(options);
+
+    if opts.repl {
+        let stdin = io::stdin();
+        repl(
+            &grammar,
+            stdin.lock(),
+            &opts.output,
+            opts.pretty,
+            &mut io::stdout(),
+            &mut io::stderr(),
+        )
+        .unwrap_or_else(|e| exit_with_error(format!("could not read stdin: {}", e)));
+        return;
+    }
 
     let input = if let Some(input) = opts.input {
-        Some(input)
+        Ok(Some(input))
     } else if let Some(input_file) = opts.input_file {
-        Some(fs::read_to_string(input_file).expect("could not read input file"))
+        read_file(&input_file).map(Some)
     } else if opts.stdin {
-        let mut buffer = String::new();
-        io::stdin()
-            .read_to_string(&mut buffer)
-            .expect("could not read input file");
-        Some(buffer)
+        read_from(io::stdin(), "stdin").map(Some)
     } else {
-        None
-    };
+        Ok(None)
+    }
+    .unwrap_or_else(|e| exit_with_error(e));
+
+    if opts.tokens {
+        let input = input.unwrap_or_else(|| exit_with_error("--tokens needs an input".into()));
+        match grammar.tokenize(&input) {
+            Ok(lexems) => print_output(&token_output(&lexems), &opts.output, opts.pretty),
+            Err(err) => {
+                print_error(err, &input, opts.tab_width);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(iterations) = opts.benchmark_grammar {
+        let input =
+            input.unwrap_or_else(|| exit_with_error("--benchmark-grammar needs an input".into()));
+        if iterations == 0 {
+            exit_with_error("--benchmark-grammar needs at least one iteration".into());
+        }
+        let (tokens, ast) = match grammar
+            .tokenize(&input)
+            .and_then(|t| Ok((t, grammar.parse(&input)?)))
+        {
+            Ok(result) => result,
+            Err(err) => {
+                print_error(err, &input, opts.tab_width);
+                std::process::exit(1);
+            }
+        };
+        let start = Instant::now();
+        for _ in 0..iterations {
+            let _ = grammar.parse(&input);
+        }
+        eprintln!(
+            "{}",
+            format_benchmark(
+                iterations,
+                start.elapsed(),
+                tokens.len(),
+                input.len(),
+                ast.descendants().count()
+            )
+        );
+        return;
+    }
 
     if let Some(input) = input {
-        let ast = match grammar.parse(&input) {
+        if opts.measure {
+            // parsing lexes the input as it goes, so lexing is timed on its own first, and the
+            // parse time includes lexing it again
+            let start = Instant::now();
+            let _ = grammar.tokenize(&input);
+            eprintln!("{}", format_duration("lex", start.elapsed()));
+        }
+        let start = Instant::now();
+        let result = grammar.parse(&input);
+        if opts.measure {
+            eprintln!("{}", format_duration("lex+parse", start.elapsed()));
+        }
+        let ast = match result {
             Ok(ast) => ast,
             Err(err) => {
-                print_error(err, &input);
+                print_error(err, &input, opts.tab_width);
                 std::process::exit(1);
             }
         };
-        print_output(&ast, &opts.output);
+        print_output(&ast, &opts.output, opts.pretty);
     } else {
         println!("{}", grammar);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn caret_counts_chars_not_bytes() {
+        let input = "first\ncafé 12";
+        let pos = input.find("12").unwrap();
+        assert_eq!(line_column(input, pos), (2, 6));
+        assert_eq!(
+            format_error(ParseError::Input(pos, "bad".into()), input, 1),
+            "  2. | café 12\n     |      ^ bad\n"
+        );
+
+        let input = "🦀🦀x";
+        let pos = input.find('x').unwrap();
+        assert_eq!(line_column(input, pos), (1, 3));
+        // wide characters take two columns in terminals
+        assert_eq!(
+            format_error(ParseError::Input(pos, "bad".into()), input, 1),
+            "  1. | 🦀🦀x\n     |     ^ bad\n"
+        );
+        let input = "名前 = 12x";
+        let pos = input.find("12").unwrap();
+        assert_eq!(
+            format_error(
+                ParseError::Token {
+                    start: pos,
+                    len: 2,
+                    msg: "bad".into()
+                },
+                input,
+                1
+            ),
+            "  1. | 名前 = 12x\n     |        ^^ bad\n"
+        );
+    }
+    #[test]
+    fn underline_whole_token() {
+        let grammar =
+            Grammar::compile("START -> ( num )\n>num -> r'\\d+'\n>word -> r'[a-zé]+'").unwrap();
+        let input = "1 café".to_string();
+        let err = grammar
+            .with_options(ParseOptions {
+                ignore_whitespace: true,
+                ..ParseOptions::default()
+            })
+            .parse(&input)
+            .unwrap_err();
+        assert_eq!(
+            format_error(err, &input, 1),
+            "  1. | 1 café\n     |   ^^^^ expected EOF\n"
+        );
+    }
+    #[test]
+    fn caret_after_tabs() {
+        let input = "\tab\tx";
+        let pos = input.find('x').unwrap();
+        let err = || ParseError::Token {
+            start: pos,
+            len: 1,
+            msg: "bad".into(),
+        };
+        assert_eq!(
+            format_error(err(), input, 1),
+            "  1. |  ab x\n     |     ^ bad\n"
+        );
+        assert_eq!(
+            format_error(err(), input, 4),
+            "  1. |     ab  x\n     |         ^ bad\n"
+        );
+        assert_eq!(
+            format_error(err(), input, 8),
+            "  1. |         ab      x\n     |                 ^ bad\n"
+        );
+    }
+    #[test]
+    fn tokens_output() {
+        let grammar = Grammar::compile(
+            "START -> ( lpar num rpar )\n>lpar -> '('\n>rpar -> ')'\n>num -> r'\\d+'",
+        )
+        .unwrap();
+        let lexems = grammar.tokenize("(1424)").unwrap();
+        assert_eq!(
+            format_output(&token_output(&lexems), &Format::Json, false),
+            concat!(
+                r#"[{"type":"lpar","raw":"(","offset":0},"#,
+                r#"{"type":"num","raw":"1424","offset":1},"#,
+                r#"{"type":"rpar","raw":")","offset":5}]"#
+            )
+        );
+        let err = grammar.tokenize("(14x)").unwrap_err();
+        assert_eq!(
+            format_error(err, "(14x)", 1),
+            "  1. | (14x)\n     |    ^ no atom matches the input near 'x)'\n"
+        );
+
+        let parse = |args: &[&str]| Opts::try_parse_from([&["gtp", "grammar"], args].concat());
+        assert!(parse(&["--tokens", "(1424)"]).is_ok());
+        assert!(parse(&["--tokens", "--repl"]).is_err());
+        assert!(parse(&["--tokens", "--benchmark-grammar", "2", "1"]).is_err());
+    }
+    #[test]
+    fn measure_format() {
+        assert_eq!(
+            format_duration("lex", Duration::from_micros(1500)),
+            "lex           1.500 ms"
+        );
+        assert_eq!(
+            format_duration("lex+parse", Duration::from_micros(2500)),
+            "lex+parse     2.500 ms"
+        );
+    }
+    #[test]
+    fn benchmark_format() {
+        assert_eq!(
+            format_benchmark(4, Duration::from_millis(2), 500, 2_000_000, 57),
+            concat!(
+                "total         2.000 ms\n",
+                "average       0.500 ms\n",
+                "tokens      1000000 tokens/s\n",
+                "bytes      4000.000 MB/s\n",
+                "tree             57 nodes"
+            )
+        );
+
+        let parse = |args: &[&str]| Opts::try_parse_from([&["gtp", "grammar"], args].concat());
+        assert!(parse(&["--benchmark-grammar", "10", "-i", "input"]).is_ok());
+        assert!(parse(&["--benchmark-grammar", "ten", "1+2"]).is_err());
+        assert!(parse(&["--benchmark-grammar", "10", "--repl"]).is_err());
+    }
+    #[test]
+    fn flags_override_grammar_options() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["gtp", "grammar"], args].concat());
+        let grammar_options = ParseOptions {
+            ignore_newline: true,
+            ignore_whitespace: true,
+            bubble_intermediate: true,
+            ..ParseOptions::default()
+        };
+
+        let o = parse_options(&parse(&["1+2"]).unwrap(), grammar_options.clone());
+        assert!(o.ignore_newline && o.ignore_whitespace && o.bubble_intermediate);
+
+        let args = ["--no-ignore-newline", "--no-bubble", "1+2"];
+        let o = parse_options(&parse(&args).unwrap(), grammar_options);
+        assert!(!o.ignore_newline && o.ignore_whitespace && !o.bubble_intermediate);
+
+        let args = ["--ignore-all", "--no-ignore-whitespace", "--bubble", "1+2"];
+        let o = parse_options(&parse(&args).unwrap(), ParseOptions::default());
+        assert!(o.ignore_newline && !o.ignore_whitespace && o.bubble_intermediate);
+
+        assert!(parse(&["--bubble", "--no-bubble", "1+2"]).is_err());
+        assert!(parse(&["--ignore-newline", "--no-ignore-newline", "1+2"]).is_err());
+    }
+    #[test]
+    fn conflicting_inputs() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["gtp", "grammar"], args].concat());
+        assert!(parse(&["1+2"]).is_ok());
+        assert!(parse(&["-i", "input"]).is_ok());
+        assert!(parse(&["--stdin"]).is_ok());
+        assert!(parse(&["1+2", "-i", "input"]).is_err());
+        assert!(parse(&["1+2", "--stdin"]).is_err());
+        assert!(parse(&["-i", "input", "--stdin"]).is_err());
+        assert!(parse(&["1+2", "-i", "input", "--stdin"]).is_err());
+    }
+    #[test]
+    fn grammar_from_stdin() {
+        let stdin = &b"START -> ( num ( pluss num )* )\n>pluss -> '+'\n>num -> r'\\d+'"[..];
+        let opts = Opts::try_parse_from(["gtp", "-", "1+2"]).unwrap();
+        let grammar = Grammar::compile(&read_grammar(&opts, stdin).unwrap()).unwrap();
+        assert!(grammar.parse(&opts.input.unwrap()).is_ok());
+
+        let opts = Opts::try_parse_from(["gtp", "-", "--stdin"]).unwrap();
+        assert!(read_grammar(&opts, stdin)
+            .unwrap_err()
+            .starts_with("the grammar is read from stdin"));
+        let opts = Opts::try_parse_from(["gtp", "-", "--repl"]).unwrap();
+        assert!(read_grammar(&opts, stdin).is_err());
+    }
+    #[test]
+    fn repl_lines() {
+        let grammar = Grammar::compile("START -> ( num $ )\n>num -> r'\\d+'").unwrap();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        repl(
+            &grammar,
+            &b"12\nx\n3\n"[..],
+            &Format::Json,
+            false,
+            &mut out,
+            &mut err,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "{\"type\":\"START\",\"children\":[{\"type\":\"num\",\"raw\":\"12\"}]}\n",
+                "{\"type\":\"START\",\"children\":[{\"type\":\"num\",\"raw\":\"3\"}]}\n",
+            )
+        );
+        assert_eq!(
+            String::from_utf8(err).unwrap(),
+            "  1. | x\n     | ^ no atom matches the input near 'x'\n"
+        );
+
+        let parse = |args: &[&str]| Opts::try_parse_from([&["gtp", "grammar"], args].concat());
+        assert!(parse(&["--repl"]).is_ok());
+        assert!(parse(&["--repl", "1+2"]).is_err());
+        assert!(parse(&["--repl", "--stdin"]).is_err());
+    }
+    #[test]
+    fn output_formats() {
+        let ast = AST::node("START", vec![AST::leaf("num", "12")]);
+        assert_eq!(
+            format_output(&ast, &Format::Json, false),
+            r#"{"type":"START","children":[{"type":"num","raw":"12"}]}"#
+        );
+        assert_eq!(
+            format_output(&ast, &Format::Json, true),
+            concat!(
+                "{\n",
+                "  \"type\": \"START\",\n",
+                "  \"children\": [\n",
+                "    {\n",
+                "      \"type\": \"num\",\n",
+                "      \"raw\": \"12\"\n",
+                "    }\n",
+                "  ]\n",
+                "}"
+            )
+        );
+        let yaml = "---\ntype: START\nchildren:\n  - type: num\n    raw: \"12\"";
+        assert_eq!(format_output(&ast, &Format::Yaml, false), yaml);
+        assert_eq!(format_output(&ast, &Format::Yaml, true), yaml);
+    }
+    #[test]
+    fn crlf_line_endings() {
+        let input = "1 2\r\n3\r\n45 x\r\n6";
+        let pos = input.find('x').unwrap();
+        assert_eq!(line_column(input, pos), (3, 4));
+        assert_eq!(
+            format_error(ParseError::Input(pos, "bad".into()), input, 1),
+            "  3. | 45 x\n     |    ^ bad\n"
+        );
+        // the line break itself is reported at the end of its line
+        let pos = input.find("\r\n6").unwrap();
+        assert_eq!(line_column(input, pos), (3, 5));
+        assert_eq!(line_column(input, pos + 1), (3, 5));
+        assert_eq!(line_column(input, pos + 2), (4, 1));
+        assert_eq!(
+            format_error(ParseError::Input(pos, "bad".into()), input, 1),
+            "  3. | 45 x\n     |     ^ bad\n"
+        );
+    }
+    #[test]
+    fn read_errors() {
+        let err = read_file("does/not/exist.grammar").unwrap_err();
+        assert!(err.starts_with("could not read does/not/exist.grammar: "));
+
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("broken pipe"))
+            }
+        }
+        assert_eq!(
+            read_from(Broken, "stdin").unwrap_err(),
+            "could not read stdin: broken pipe"
+        );
+        assert_eq!(read_from(&b"1+2"[..], "stdin").unwrap(), "1+2");
+    }
+}
@@ -1,15 +1,75 @@
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+#[cfg(feature = "std")]
 use regex::Regex;
+#[cfg(feature = "std")]
+use std::{fs, path::Path};
 
+/// Positions in errors are byte offsets into the input, like all positions in the crate. Use
+/// `line_column` or `byte_to_char_offset` to show them to users.
 #[derive(Debug, Clone)]
 pub enum ParseError {
+    /// The input couldn't be split into lexems at a byte offset
     Lexem(usize, String),
     NoMatch(String),
+    /// The lexems didn't match the grammar at a byte offset
     Input(usize, String),
+    /// Unexpected lexem in the input, starting at byte `start` and `len` bytes long
+    Token {
+        start: usize,
+        len: usize,
+        msg: String,
+    },
+    Grammar(String),
+    /// The syntax tree couldn't be converted with `FromAst`
+    Convert(String),
+    /// The input couldn't be read. Shared so the error stays `Clone`.
+    #[cfg(feature = "std")]
+    Io(std::sync::Arc<std::io::Error>),
+    /// A regex of the grammar couldn't be compiled
+    #[cfg(feature = "std")]
+    Regex(regex::Error),
+    /// The input is longer than `ParseOptions::max_input_bytes`
+    InputTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// Error in the file at `path`, with the line and column of the error if it has a position
+    File {
+        path: String,
+        position: Option<(usize, usize)>,
+        error: Box<ParseError>,
+    },
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
 
-#[derive(Debug)]
+impl ParseError {
+    fn token(lexem: &Lexem, msg: String) -> ParseError {
+        ParseError::Token {
+            start: lexem.start,
+            len: lexem.raw.len(),
+            msg,
+        }
+    }
+    /// Byte offset in the input the error was found at, if it has one
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            ParseError::Lexem(pos, _) | ParseError::Input(pos, _) => Some(*pos),
+            ParseError::Token { start, .. } => Some(*start),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum SymbolType {
     Symbol(Symbol),
     Group(Vec<SymbolType>),
@@ -19,13 +79,172 @@ pub enum SymbolType {
 }
 
 impl SymbolType {
+    /// Lexem of type `t`, not included in the syntax tree
+    pub fn lexem<T: Into<String>>(t: T) -> Self {
+        SymbolType::Symbol(Symbol::Lexem {
+            t: t.into(),
+            include_raw: false,
+        })
+    }
+    /// Lexem of type `t`, included as a leaf in the syntax tree
+    pub fn leaf<T: Into<String>>(t: T) -> Self {
+        SymbolType::Symbol(Symbol::Lexem {
+            t: t.into(),
+            include_raw: true,
+        })
+    }
+    pub fn rule<T: Into<String>>(name: T) -> Self {
+        SymbolType::Symbol(Symbol::AST(name.into()))
+    }
+    /// Rule reference if `name` is all uppercase, the wildcard if it is `_`, otherwise a lexem
+    /// included in the tree, like names in text grammars
+    pub fn named<T: Into<String>>(name: T) -> Self {
+        let name = name.into();
+        if name == "_" {
+            SymbolType::any()
+        } else if name.to_ascii_uppercase() == name {
+            SymbolType::rule(name)
+        } else {
+            SymbolType::leaf(name)
+        }
+    }
+    pub fn eof() -> Self {
+        SymbolType::Symbol(Symbol::EOF)
+    }
+    /// Any single lexem, see `Symbol::Any`
+    pub fn any() -> Self {
+        SymbolType::Symbol(Symbol::Any)
+    }
+    /// Short description for spans, the name of a symbol or the shape of a group
+    #[cfg(feature = "tracing")]
+    fn trace_label(&self) -> &str {
+        match self {
+            SymbolType::Symbol(Symbol::Lexem { t, .. }) => t,
+            SymbolType::Symbol(Symbol::AST(r)) => r,
+            SymbolType::Symbol(Symbol::EOF) => "$",
+            SymbolType::Symbol(Symbol::Any) => "_",
+            SymbolType::Group(_) => "( )",
+            SymbolType::Optional(_) => "( )?",
+            SymbolType::Repeated(_) => "( )*",
+            SymbolType::Switch(_, _) => "|",
+        }
+    }
+    pub fn seq(symbols: Vec<SymbolType>) -> Self {
+        SymbolType::Group(symbols)
+    }
+    pub fn opt(s: SymbolType) -> Self {
+        SymbolType::Optional(Box::new(s))
+    }
+    pub fn rep(s: SymbolType) -> Self {
+        SymbolType::Repeated(Box::new(s))
+    }
+    /// One or more `item` with the lexem `separator` between them, written
+    /// `( ITEM ( separator ITEM )* )` in text grammars
+    pub fn separated<T: Into<String>>(item: SymbolType, separator: T) -> Self {
+        let rest = SymbolType::Group(vec![SymbolType::lexem(separator), item.clone()]);
+        SymbolType::Group(vec![item, SymbolType::rep(rest)])
+    }
+    /// `s` repeated at least `min` times, and at most `max` times if given
+    pub fn bounded(s: SymbolType, min: usize, max: Option<usize>) -> Self {
+        // the copies are spliced into one group, so it reads like a production in a text grammar
+        let parts = match s {
+            SymbolType::Group(ref g) => g.clone(),
+            _ => vec![s.clone()],
+        };
+        let mut symbols = (0..min).flat_map(|_| parts.clone()).collect::<Vec<_>>();
+        match max {
+            None => symbols.push(SymbolType::rep(s)),
+            Some(max) => symbols.extend((min..max).fold(None, |tail, _| {
+                let mut g = parts.clone();
+                g.extend(tail);
+                Some(SymbolType::opt(SymbolType::seq(g)))
+            })),
+        }
+        SymbolType::seq(symbols)
+    }
+    pub fn switch(a: SymbolType, b: SymbolType) -> Self {
+        SymbolType::Switch(Box::new(a), Box::new(b))
+    }
+    /// Equivalent symbol type without redundant nesting, see `Grammar::canonicalize`
+    fn canonical(self) -> SymbolType {
+        match self {
+            SymbolType::Symbol(_) => self,
+            SymbolType::Group(g) => {
+                let mut symbols: Vec<SymbolType> = Vec::new();
+                for s in g.into_iter().map(SymbolType::canonical) {
+                    let spliced = match s {
+                        SymbolType::Group(g) => g,
+                        s => vec![s],
+                    };
+                    for s in spliced {
+                        // a repetition consumes everything an equal repetition after it could
+                        match (symbols.last(), &s) {
+                            (Some(SymbolType::Repeated(a)), SymbolType::Repeated(b)) if a == b => {}
+                            _ => symbols.push(s),
+                        }
+                    }
+                }
+                if symbols.len() == 1 {
+                    symbols.pop().unwrap()
+                } else {
+                    SymbolType::Group(symbols)
+                }
+            }
+            SymbolType::Optional(o) => match o.canonical() {
+                s @ SymbolType::Optional(_) | s @ SymbolType::Repeated(_) => s,
+                s => SymbolType::opt(s),
+            },
+            SymbolType::Repeated(m) => match m.canonical() {
+                SymbolType::Optional(s) | SymbolType::Repeated(s) => SymbolType::Repeated(s),
+                s => SymbolType::rep(s),
+            },
+            SymbolType::Switch(a, b) => SymbolType::switch(a.canonical(), b.canonical()),
+        }
+    }
     fn nullable(&self) -> bool {
         match self {
-            SymbolType::Symbol(_) | SymbolType::Group(_) => false,
+            SymbolType::Symbol(_) => false,
+            SymbolType::Group(g) => g.iter().all(|s| s.nullable()),
             SymbolType::Switch(a, b) => a.nullable() || b.nullable(),
             SymbolType::Optional(_) | SymbolType::Repeated(_) => true,
         }
     }
+    /// Check if the rule `name` is referenced anywhere in this symbol type
+    fn references(&self, name: &str) -> bool {
+        let mut found = false;
+        self.walk(&mut |s| match s {
+            SymbolType::Symbol(Symbol::AST(r)) if r == name => found = true,
+            _ => {}
+        });
+        found
+    }
+    /// This symbol type with references to the rule `name` replaced by `production`
+    fn inline(self, name: &str, production: &SymbolType) -> SymbolType {
+        let inline = |s: Box<SymbolType>| Box::new(s.inline(name, production));
+        match self {
+            SymbolType::Symbol(Symbol::AST(r)) if r == name => production.clone(),
+            SymbolType::Symbol(_) => self,
+            SymbolType::Group(g) => {
+                SymbolType::Group(g.into_iter().map(|s| s.inline(name, production)).collect())
+            }
+            SymbolType::Optional(o) => SymbolType::Optional(inline(o)),
+            SymbolType::Repeated(m) => SymbolType::Repeated(inline(m)),
+            SymbolType::Switch(a, b) => SymbolType::Switch(inline(a), inline(b)),
+        }
+    }
+    /// Call `f` for this and every nested symbol type
+    pub(crate) fn walk<'a>(&'a self, f: &mut dyn FnMut(&'a SymbolType)) {
+        f(self);
+        match self {
+            SymbolType::Symbol(_) => {}
+            SymbolType::Group(g) => g.iter().for_each(|s| s.walk(f)),
+            SymbolType::Optional(s) | SymbolType::Repeated(s) => s.walk(f),
+            SymbolType::Switch(a, b) => {
+                a.walk(f);
+                b.walk(f);
+            }
+        }
+    }
     fn first_symbol(&self) -> Vec<&Symbol> {
         match self {
             SymbolType::Symbol(i) => vec![i],
@@ -51,364 +270,2601 @@ impl SymbolType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Rule {
     pub name: String,
     pub production: SymbolType,
+    /// Productions of rules with the same name are tried in ascending order, rules with equal
+    /// order in the order they appear in `Grammar::rules`
+    pub order: usize,
+    /// Type of the nodes parsed with this production instead of the rule name
+    pub label: Option<String>,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ParseOptions {
     pub ignore_whitespace: bool,
     pub ignore_newline: bool,
     pub bubble_intermediate: bool,
+    /// Number of lexems looked at when choosing between productions, values below 1 mean 1
+    pub lookahead: usize,
+    /// Emit `indent` and `dedent` lexems when the first lexem on a line is indented more or
+    /// less than the lines before it, for indentation sensitive grammars. Usually combined with
+    /// `ignore_whitespace`.
+    pub indentation: bool,
+    /// Keep ignored characters as trivia attached to the surrounding lexems instead of dropping
+    /// them
+    pub record_trivia: bool,
+    /// Additional characters skipped between lexems, like separators not worth modelling in
+    /// the grammar. An ignored character is skipped even if an atom could match it.
+    pub ignore_chars: Vec<char>,
+    /// Replace the last child node of a node with its children if it has the same type, so
+    /// recursive rules like `LIST -> ( ITEM ( LIST )? )` give flat lists like `( ITEM )*` does
+    pub flatten_repeated: bool,
+    /// Reject inputs longer than this many bytes before parsing them, 0 means no limit
+    pub max_input_bytes: usize,
+    /// Columns between tab stops when reporting columns of positions, like editors showing
+    /// tabs this wide, values below 1 mean 1
+    pub tab_width: usize,
+    /// Emit a `newline` lexem at the end of every line with lexems on it, even if
+    /// `ignore_newline` is set, for grammars where line breaks end statements. Empty lines are
+    /// skipped.
+    pub newlines: bool,
+    /// Record the index of the production each node was parsed with in `AST::Node::production`
+    pub record_production_index: bool,
 }
 
-impl ParseOptions {
-    pub fn default() -> Self {
-        ParseOptions {
-            ignore_whitespace: false,
-            ignore_newline: false,
-            bubble_intermediate: false,
-        }
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Grammar {
     pub rules: Vec<Rule>,
     pub atoms: Vec<Atom>,
+    /// Lexer modes, the first one is active at the start of the input. If there are no modes
+    /// all atoms are always active.
+    pub modes: Vec<LexerMode>,
+    /// Patterns skipped between lexems, like comments
+    pub skip: Vec<Skip>,
+    /// Types used in the syntax tree for leaves of the atom, instead of the atom name
+    pub aliases: BTreeMap<String, String>,
+    /// Atoms with escape sequences like `\n` and `\u00e9` decoded in the `raw` value of their
+    /// leaves
+    pub decode_escapes: Vec<String>,
+    /// Which atom is used when more than one matches the input
+    pub lexing: Lexing,
+    /// Rules that don't get a node in the syntax tree, their children are added to the node
+    /// of the rule using them instead
+    pub transparent: Vec<String>,
+    /// Atoms skipped between lexems like `skip`, so they never reach the parser, like comments
+    pub hidden: Vec<String>,
+    /// Pairs of opening and closing delimiter atoms, see `Grammar::parse_recovering`
+    pub recover: Vec<(String, String)>,
+    /// Rules whose nodes get the number of repetitions of the repeated groups in their
+    /// productions, in `AST::Node::count`
+    pub counted: Vec<String>,
+    /// Rules where repetitions starting with a lexem, like `( ITEM ( comma ITEM )* )` built by
+    /// `SymbolType::separated`, accept that separator after the last item, so `[1,2,3,]` is a
+    /// list
+    pub trailing_separators: Vec<String>,
+    /// Rules with productions ending with a repeated group, like `( NUMBER ( minus NUMBER )* )`,
+    /// where every repetition after the first nests the ones before or after it in another
+    /// node of the rule, giving binary trees for left or right associative operators
+    pub folds: Vec<(String, Fold)>,
 
     pub options: ParseOptions,
+    /// Tables built from the atoms the first time the grammar lexes input
+    pub atom_tables: AtomTables,
+}
+
+/// Tables the lexer builds from the atoms of a grammar, kept between inputs. They are only
+/// used while the atoms and the lexing are the ones they were built for, and built for every
+/// input without std.
+#[derive(Debug, Clone, Default)]
+pub struct AtomTables {
+    /// `Grammar::char_atoms` with the atom literals and lexing it was built for
+    #[cfg(feature = "std")]
+    char_atoms: std::sync::OnceLock<(Vec<Option<String>>, Lexing, CharAtoms)>,
+}
+
+/// Direction repetitions are nested in, see `Grammar::folds`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fold {
+    /// `1-2-3` gives `((1-2)-3)`
+    Left,
+    /// `2^3^4` gives `(2^(3^4))`
+    Right,
+}
+
+/// How the lexer picks between atoms matching the same input
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Lexing {
+    /// The atom declared first
+    #[default]
+    FirstMatch,
+    /// The atom matching the longest text, the one declared first if several do
+    LongestMatch,
+}
+
+#[derive(Debug, Clone)]
+pub enum Skip {
+    /// Text matching the regex
+    #[cfg(feature = "std")]
+    Matched(Regex),
+    /// Text from `start` up to and including the next `end`
+    Delimited { start: String, end: String },
+}
+
+impl PartialEq for Skip {
+    fn eq(&self, other: &Skip) -> bool {
+        match (self, other) {
+            #[cfg(feature = "std")]
+            (Skip::Matched(a), Skip::Matched(b)) => a.as_str() == b.as_str(),
+            (
+                Skip::Delimited { start, end },
+                Skip::Delimited {
+                    start: other_start,
+                    end: other_end,
+                },
+            ) => start == other_start && end == other_end,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+impl Skip {
+    /// Length of the skipped text at the start of `input`, or an error message if the
+    /// pattern was started but never ended
+    fn match_input(&self, input: &str) -> Result<usize, String> {
+        match self {
+            #[cfg(feature = "std")]
+            Skip::Matched(m) => Ok(m
+                .find(input)
+                .filter(|m| m.start() == 0)
+                .map(|m| m.end())
+                .unwrap_or(0)),
+            Skip::Delimited { start, end } => {
+                if !input.starts_with(start.as_str()) {
+                    return Ok(0);
+                }
+                input[start.len()..]
+                    .find(end.as_str())
+                    .map(|i| start.len() + i + end.len())
+                    .ok_or_else(|| format!("unterminated '{}', expected '{}'", start, end))
+            }
+        }
+    }
+}
+
+/// A named lexer state, where only some of the atoms can be matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexerMode {
+    pub name: String,
+    pub atoms: Vec<ModeAtom>,
+}
+
+/// An atom active in a `LexerMode`, optionally changing mode when matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModeAtom {
+    pub name: String,
+    pub action: Option<ModeAction>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModeAction {
+    Push(String),
+    Pop,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Symbol {
-    Lexem { t: String, include_raw: bool },
+    Lexem {
+        t: String,
+        include_raw: bool,
+    },
     AST(String),
+    /// Matches only when all of the input has been consumed, written as `$` in grammars
+    EOF,
+    /// Matches any single lexem, included in the syntax tree with its own type. Written as `_`
+    /// in grammars.
+    Any,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AST {
-    Node { t: String, children: Vec<AST> },
-    Leaf { t: String, raw: String },
+    Node {
+        t: String,
+        children: Vec<AST>,
+        /// Number of repetitions of the repeated groups in the node's rule, only for rules in
+        /// `Grammar::counted`
+        count: Option<usize>,
+        /// Index of the production of the node's rule it was parsed with, in the order they
+        /// are tried, only with `ParseOptions::record_production_index`
+        production: Option<usize>,
+    },
+    Leaf {
+        t: String,
+        raw: String,
+        /// Text of the named groups matched by the atom's regex, by group name
+        captures: BTreeMap<String, String>,
+    },
 }
 
 impl AST {
+    pub fn node<T: Into<String>>(t: T, children: Vec<AST>) -> Self {
+        AST::Node {
+            t: t.into(),
+            children,
+            count: None,
+            production: None,
+        }
+    }
+    pub fn leaf<T: Into<String>, R: Into<String>>(t: T, raw: R) -> Self {
+        AST::Leaf {
+            t: t.into(),
+            raw: raw.into(),
+            captures: BTreeMap::new(),
+        }
+    }
     pub fn get_t(&self) -> &String {
         match self {
             AST::Node { t, .. } => t,
             AST::Leaf { t, .. } => t,
         }
     }
-}
-
-impl Grammar {
-    fn match_input(&self, input: &str) -> Option<(Lexem, usize)> {
-        self.atoms
-            .iter()
-            .find_map(|atom| atom.match_input(input))
-            .map(|(name, i)| {
-                (
-                    Lexem {
-                        t: name,
-                        raw: String::from(&input[0..i]),
-                    },
-                    i,
-                )
-            })
-    }
-    fn first_from_rule(&self, rule: &String) -> Vec<&String> {
-        self.rules
-            .iter()
-            .filter(|r| r.name == *rule)
-            .map(|r| {
-                r.production
-                    .first_symbol()
-                    .iter()
-                    .map(|s| match s {
-                        Symbol::Lexem { t, .. } => vec![t],
-                        Symbol::AST(r) if r != rule => self.first_from_rule(&r),
-                        _ => vec![],
-                    })
-                    .flatten()
-                    .collect::<Vec<_>>()
-            })
-            .flatten()
-            .collect()
+    /// Rewrite the tree bottom-up, `f` is called for every node after its children have been
+    /// rewritten
+    pub fn transform(&self, f: impl Fn(AST) -> AST) -> AST {
+        self.transform_with(&f)
     }
-    fn first_from_symbol<'a>(&'a self, s: &'a Symbol) -> Vec<&'a String> {
-        match s {
-            Symbol::Lexem { t, .. } => vec![t],
-            Symbol::AST(r) => self.first_from_rule(r),
+    fn transform_with(&self, f: &dyn Fn(AST) -> AST) -> AST {
+        match self {
+            AST::Node {
+                t,
+                children,
+                count,
+                production,
+            } => f(AST::Node {
+                t: t.clone(),
+                children: children.iter().map(|c| c.transform_with(f)).collect(),
+                count: *count,
+                production: *production,
+            }),
+            AST::Leaf { .. } => f(self.clone()),
         }
     }
-    fn production_matches_lexem(&self, p: &SymbolType, t: &String) -> bool {
-        p.first_symbol()
-            .iter()
-            .map(|s| self.first_from_symbol(s).contains(&t))
-            .fold(false, |a, b| a || b)
+    /// Replace nested nodes of type `rule` with their children, turning right-recursive chains
+    /// like `LIST -> ( ITEM ( LIST )? )` into a single node
+    pub fn flatten_rule(&self, rule: &str) -> AST {
+        self.transform(|ast| match ast {
+            AST::Node {
+                t,
+                children,
+                count,
+                production,
+            } if t == rule => AST::Node {
+                children: children
+                    .into_iter()
+                    .flat_map(|c| match c {
+                        AST::Node { t, children, .. } if t == rule => children,
+                        c => vec![c],
+                    })
+                    .collect(),
+                t,
+                count,
+                production,
+            },
+            ast => ast,
+        })
     }
-    pub fn parse(&self, input: &String) -> ParseResult<AST> {
-        log::debug!("parsing input:\n{}", input);
-
-        let mut lexems = Lexem::iter(self, input);
-
-        let ast = self.parse_rule(&"START".into(), &mut lexems)?;
-
-        if lexems.peek().is_some() {
-            return Err(ParseError::Input(lexems.cursor, "expected EOF".into()));
+    /// This node and all nodes and leaves below it, depth first with parents before their
+    /// children
+    pub fn descendants(&self) -> impl Iterator<Item = &AST> {
+        let mut stack = vec![self];
+        core::iter::from_fn(move || {
+            let ast = stack.pop()?;
+            if let AST::Node { children, .. } = ast {
+                stack.extend(children.iter().rev());
+            }
+            Some(ast)
+        })
+    }
+    /// This tree with only the nodes and leaves of the types in `keep`. The kept descendants
+    /// of removed nodes become children of their nearest kept ancestor. The root is always
+    /// kept, so the result is still a single tree.
+    pub fn project(&self, keep: &[&str]) -> AST {
+        match self {
+            AST::Node { t, children, .. } => AST::node(
+                t.clone(),
+                children.iter().flat_map(|c| c.projected(keep)).collect(),
+            ),
+            AST::Leaf { .. } => self.clone(),
         }
-        lexems.ok?;
-        Ok(ast)
     }
-    fn parse_rule(&self, rule: &String, lexems: &mut LexemIter) -> ParseResult<AST> {
-        let cursor = lexems.cursor;
-        let peeked = lexems
-            .peek()
-            .ok_or(ParseError::Input(cursor, "unexpected EOF".into()))?;
-        log::debug!("parsing rule: {:?}", rule);
-        log::debug!("peeked: {:?}", peeked);
-
-        let rules = self
-            .rules
-            .iter()
-            .filter(|Rule { name, .. }| name == rule)
-            .collect::<Vec<_>>();
-
-        if rules.len() == 0 {
-            return Err(ParseError::NoMatch(format!("no rule named '{}'", rule)));
+    /// The kept nodes and leaves at the top of this tree, see `project`
+    fn projected(&self, keep: &[&str]) -> Vec<AST> {
+        let kept = keep.contains(&self.get_t().as_str());
+        match self {
+            AST::Node { .. } if kept => vec![self.project(keep)],
+            AST::Node { children, .. } => children.iter().flat_map(|c| c.projected(keep)).collect(),
+            AST::Leaf { .. } if kept => vec![self.clone()],
+            AST::Leaf { .. } => Vec::new(),
         }
-
-        log::debug!("rules found: {:?}", rules);
-
-        if let Some(Rule { production, .. }) = rules
-            .iter()
-            .find(|r| self.production_matches_lexem(&r.production, &peeked.t))
-        {
-            log::debug!("choosing production: {:?}", production);
-
-            let children = self.parse_symbol_type(production, lexems)?;
-            if self.options.bubble_intermediate && children.len() == 1 {
-                return Ok(children.into_iter().next().unwrap());
-            } else {
-                return Ok(AST::Node {
-                    t: rule.clone(),
-                    children,
-                });
+    }
+    /// Number of nodes and leaves on the longest path from this one down to a leaf
+    pub fn depth(&self) -> usize {
+        let mut stack = vec![(self, 1)];
+        let mut depth = 0;
+        while let Some((ast, d)) = stack.pop() {
+            depth = depth.max(d);
+            if let AST::Node { children, .. } = ast {
+                stack.extend(children.iter().map(|c| (c, d + 1)));
             }
         }
-
-        return Err(ParseError::Input(
-            cursor,
-            format!(
-                "unexpected token '{}' when trying to parse rule '{}'",
-                peeked.t, rule
-            ),
-        ));
+        depth
     }
-    fn parse_symbol_type(&self, s: &SymbolType, lexems: &mut LexemIter) -> ParseResult<Vec<AST>> {
-        let mut parsed = Vec::new();
-        match s {
-            SymbolType::Symbol(s) => {
-                if let Some(ast) = self.parse_symbol(s, lexems)? {
-                    parsed.push(ast);
-                }
-            }
-            SymbolType::Group(g) => {
-                for s in g.iter() {
-                    parsed.extend(self.parse_symbol_type(s, lexems)?);
-                }
-            }
-            SymbolType::Optional(o) => {
-                if let Some(p) = lexems.peek() {
-                    if self.production_matches_lexem(o, &p.t) {
-                        parsed.extend(self.parse_symbol_type(o, lexems)?);
+    /// Hash of the types, raw texts and shape of the tree, equal for equal trees
+    #[cfg(feature = "std")]
+    pub fn structural_hash(&self) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Indices of the children to follow from this node to every leaf, in the order the leaves
+    /// appear in the input
+    fn leaf_paths(&self) -> Vec<Vec<usize>> {
+        let mut paths = Vec::new();
+        let mut stack = vec![(self, Vec::new())];
+        while let Some((ast, path)) = stack.pop() {
+            match ast {
+                AST::Node { children, .. } => {
+                    for (i, c) in children.iter().enumerate().rev() {
+                        let mut path = path.clone();
+                        path.push(i);
+                        stack.push((c, path));
                     }
                 }
+                AST::Leaf { .. } => paths.push(path),
             }
-            SymbolType::Repeated(m) => {
-                while let Some(p) = lexems.peek() {
-                    if self.production_matches_lexem(m, &p.t) {
-                        parsed.extend(self.parse_symbol_type(m, lexems)?);
-                    } else {
-                        break;
-                    }
+        }
+        paths
+    }
+    /// Type and raw text of every leaf, in the order they appear in the input
+    pub fn leaves(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.descendants().filter_map(|ast| match ast {
+            AST::Leaf { t, raw, .. } => Some((t.as_str(), raw.as_str())),
+            AST::Node { .. } => None,
+        })
+    }
+}
+
+/// Hashes the nodes depth first without recursion, so deep trees don't overflow the stack
+impl Hash for AST {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for ast in self.descendants() {
+            match ast {
+                AST::Node { t, children, .. } => {
+                    0u8.hash(state);
+                    t.hash(state);
+                    children.len().hash(state);
                 }
-            }
-            SymbolType::Switch(a, b) => {
-                let cursor = lexems.cursor;
-                let p = lexems
-                    .peek()
-                    .ok_or(ParseError::Input(cursor, "unexpected EOF".into()))?;
-                if self.production_matches_lexem(a, &p.t) {
-                    parsed.extend(self.parse_symbol_type(a, lexems)?);
-                } else {
-                    parsed.extend(self.parse_symbol_type(b, lexems)?);
+                AST::Leaf { t, raw, captures } => {
+                    1u8.hash(state);
+                    t.hash(state);
+                    raw.hash(state);
+                    captures.hash(state);
                 }
             }
         }
-        Ok(parsed)
     }
-    fn parse_symbol(&self, s: &Symbol, lexems: &mut LexemIter) -> ParseResult<Option<AST>> {
-        match s {
-            Symbol::Lexem { t, include_raw } => {
-                if lexems.peek().map(|p| p.t == *t).unwrap_or(false) {
-                    let a = lexems.next().unwrap();
-                    if *include_raw {
-                        Ok(Some(AST::Leaf { t: a.t, raw: a.raw }))
-                    } else {
-                        Ok(None)
-                    }
-                } else {
-                    Err(ParseError::Input(
-                        lexems.cursor,
-                        format!(
-                            "expected token '{}' found '{}'",
-                            t,
-                            lexems.peek().map(|l| &l.t).unwrap_or(&"EOF".into())
-                        ),
-                    ))
-                }
-            }
-            Symbol::AST(rule) => Ok(Some(self.parse_rule(rule, lexems)?)),
+}
+
+/// Conversion from a syntax tree into a custom type, see `Grammar::parse_into`
+pub trait FromAst: Sized {
+    fn from_ast(ast: &AST) -> Result<Self, String>;
+}
+
+impl FromAst for String {
+    fn from_ast(ast: &AST) -> Result<Self, String> {
+        match ast {
+            AST::Leaf { raw, .. } => Ok(raw.clone()),
+            AST::Node { t, .. } => Err(format!("expected a leaf, found node '{}'", t)),
         }
     }
-    pub fn with_options(mut self, options: ParseOptions) -> Grammar {
-        self.options = options;
-        self
+}
+
+impl<T: FromAst> FromAst for Vec<T> {
+    fn from_ast(ast: &AST) -> Result<Self, String> {
+        match ast {
+            AST::Node { children, .. } => children.iter().map(T::from_ast).collect(),
+            AST::Leaf { t, .. } => Err(format!("expected a node, found leaf '{}'", t)),
+        }
     }
 }
 
+/// Receives the nodes of the syntax tree in order as they are parsed, see
+/// `Grammar::parse_with_callbacks`
+pub trait ParseCallbacks {
+    fn on_enter(&mut self, _rule: &str) {}
+    fn on_leaf(&mut self, _t: &str, _raw: &str) {}
+    /// Called after the rule has been parsed, or parsing it failed
+    fn on_exit(&mut self, _rule: &str) {}
+    /// Called before each repetition after the first of the repeated group ending a folded
+    /// rule, see `Grammar::folds`
+    fn on_fold(&mut self, _rule: &str, _fold: Fold) {}
+    /// Called for every consumed lexem, including the ones not included in the tree
+    fn on_lexem(&mut self, _lexem: &Lexem) {}
+    /// Called before each repetition of a repeated group
+    fn on_repetition(&mut self) {}
+    /// Called after `on_enter` with the index of the production of the rule being parsed, in
+    /// the order they are tried
+    fn on_production(&mut self, _rule: &str, _index: usize) {}
+}
+
+/// The lexem found at an offset in the input, see `Grammar::explain_token`
 #[derive(Debug, Clone)]
-pub struct Lexem {
-    t: String,
-    raw: String,
+pub struct TokenInfo {
+    pub lexem: Lexem,
+    /// Names of the rules being parsed when the lexem was consumed, outermost first
+    pub rules: Vec<String>,
 }
 
-impl Lexem {
-    fn iter<'a>(grammar: &'a Grammar, input: &'a String) -> LexemIter<'a> {
-        LexemIter {
-            grammar,
-            input,
-            cursor: 0,
-            ok: Ok(()),
-            peeked: None,
-            options: grammar.options,
-        }
-    }
+/// A rule where more than one production matched the lookahead, the first one was chosen,
+/// see `Grammar::parse_with_ambiguity_warnings`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmbiguityWarning {
+    pub rule: String,
+    /// Byte offset of the first lexem of the rule
+    pub position: usize,
+    /// Indices of the matching productions, in the order they are tried
+    pub candidates: Vec<usize>,
 }
 
-#[derive(Clone)]
-struct LexemIter<'a> {
-    grammar: &'a Grammar,
-    input: &'a String,
-    cursor: usize,
-    ok: ParseResult<()>,
-    peeked: Option<Lexem>,
-    options: ParseOptions,
+/// A lexem that can start two productions of a rule, see `Grammar::first_conflicts`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirstConflict {
+    pub rule: String,
+    pub token: String,
+    /// Indices of the two productions, in the order they are tried
+    pub production_indices: (usize, usize),
 }
 
-impl LexemIter<'_> {
-    fn peek(&mut self) -> Option<&Lexem> {
-        if self.peeked.is_some() {
-            return self.peeked.as_ref();
-        }
-        self.peeked = self.shift();
-        self.peeked.as_ref()
+struct TokenFinder {
+    offset: usize,
+    rules: Vec<String>,
+    found: Option<TokenInfo>,
+}
+
+impl ParseCallbacks for TokenFinder {
+    fn on_enter(&mut self, rule: &str) {
+        self.rules.push(rule.into());
     }
-    fn shift(&mut self) -> Option<Lexem> {
-        if self.peeked.is_some() {
-            return self.peeked.take();
-        }
-        if self.cursor >= self.input.len() {
-            return None;
-        }
-        self.skip_ignored();
-        match self.grammar.match_input(&self.input[self.cursor..]) {
-            Some((lexem, i)) => {
-                self.cursor += i;
-                self.skip_ignored();
-                Some(lexem)
-            }
-            None => {
-                self.ok = Err(ParseError::Lexem(
-                    self.cursor,
-                    "could not find atom matching character(s)".into(),
-                ));
-                None
-            }
-        }
+    fn on_exit(&mut self, _rule: &str) {
+        self.rules.pop();
     }
-    fn skip_ignored(&mut self) {
-        while self.cursor < self.input.len() {
-            let c = self.input.chars().nth(self.cursor).unwrap();
-            if c == ' ' && self.options.ignore_whitespace
-                || c == '\n' && self.options.ignore_newline
-            {
-                self.cursor += 1;
-            } else {
-                break;
-            }
+    fn on_lexem(&mut self, lexem: &Lexem) {
+        if lexem.start <= self.offset && self.offset < lexem.start + lexem.raw.len() {
+            self.found = Some(TokenInfo {
+                lexem: lexem.clone(),
+                rules: self.rules.clone(),
+            });
         }
     }
 }
 
-impl Iterator for LexemIter<'_> {
-    type Item = Lexem;
+/// Callbacks for parsing only to check the input
+struct NoCallbacks;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let n = self.shift();
-        log::debug!("next lexem: {:?}", n);
-        n
-    }
+impl ParseCallbacks for NoCallbacks {}
+
+struct AstBuilder {
+    bubble_intermediate: bool,
+    flatten_repeated: bool,
+    record_production_index: bool,
+    /// Captures of the last consumed lexem, given to the leaf built from it
+    captures: BTreeMap<String, String>,
+    /// Byte range of the last consumed lexem in the input
+    span: (usize, usize),
+    /// Byte ranges of the built leaves in order, only recorded when not `None`
+    spans: Option<Vec<(usize, usize)>>,
+    /// Node types of the rules in `Grammar::counted`, including the labels of their productions
+    counted: Vec<String>,
+    /// The nodes being built, innermost last
+    stack: Vec<OpenNode>,
+    root: Option<AST>,
 }
 
-#[derive(Debug)]
-pub enum Atom {
-    Simple { name: String },
-    Matched { name: String, m: Regex },
+/// A node being built by `AstBuilder`
+struct OpenNode {
+    t: String,
+    children: Vec<AST>,
+    /// Repetitions to fold, see `Grammar::folds`
+    folds: Option<Repetitions>,
+    /// Repetitions of repeated groups matched in the node's rule so far
+    repetitions: usize,
+    production: Option<usize>,
 }
 
-impl Atom {
-    fn match_input(&self, input: &str) -> Option<(String, usize)> {
-        match self {
-            Atom::Simple { name } => {
-                if input.starts_with(name) {
-                    return Some((name.clone(), name.len()));
-                }
-            }
-            Atom::Matched { name, m } => {
-                let m = m.find(input)?;
-                if m.start() != 0 {
-                    return None;
-                }
-                return Some((name.clone(), m.end()));
-            }
-        }
-        None
+/// Direction and child indices of the repetitions after the first in a folded node
+type Repetitions = (Fold, Vec<usize>);
+
+/// Nest `children` split at `bounds` in nodes of type `t`, see `Grammar::folds`
+fn fold_children(t: &str, mut children: Vec<AST>, fold: Fold, bounds: &[usize]) -> Vec<AST> {
+    let mut parts = Vec::new();
+    for bound in bounds.iter().rev() {
+        parts.push(children.split_off(*bound));
+    }
+    parts.push(children);
+    parts.reverse();
+    match fold {
+        Fold::Left => parts
+            .into_iter()
+            .reduce(|folded, part| {
+                let mut children = vec![AST::node(t, folded)];
+                children.extend(part);
+                children
+            })
+            .unwrap(),
+        // the last child before each repetition is the first operand of its node
+        Fold::Right => parts
+            .into_iter()
+            .rev()
+            .reduce(|folded, mut part| {
+                let mut children = part.pop().into_iter().collect::<Vec<_>>();
+                children.extend(folded);
+                part.push(AST::node(t, children));
+                part
+            })
+            .unwrap(),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn simple_lexem_iter() {
+impl AstBuilder {
+    fn new(grammar: &Grammar) -> Self {
+        let counted = grammar
+            .rules
+            .iter()
+            .filter(|r| grammar.counted.contains(&r.name))
+            .flat_map(|r| core::iter::once(&r.name).chain(r.label.as_ref()))
+            .cloned()
+            .collect();
+        AstBuilder {
+            bubble_intermediate: grammar.options.bubble_intermediate,
+            flatten_repeated: grammar.options.flatten_repeated,
+            record_production_index: grammar.options.record_production_index,
+            captures: BTreeMap::new(),
+            span: (0, 0),
+            spans: None,
+            counted,
+            stack: Vec::new(),
+            root: None,
+        }
+    }
+    fn push(&mut self, ast: AST) {
+        match self.stack.last_mut() {
+            Some(node) => node.children.push(ast),
+            None => self.root = Some(ast),
+        }
+    }
+    /// Close the nodes still being built, like after a parse error, and take the tree
+    fn finish(mut self) -> Option<AST> {
+        while let Some(node) = self.stack.last() {
+            let t = node.t.clone();
+            self.on_exit(&t);
+        }
+        self.root
+    }
+}
+
+impl ParseCallbacks for AstBuilder {
+    fn on_enter(&mut self, rule: &str) {
+        self.stack.push(OpenNode {
+            t: rule.into(),
+            children: Vec::new(),
+            folds: None,
+            repetitions: 0,
+            production: None,
+        });
+    }
+    fn on_production(&mut self, _rule: &str, index: usize) {
+        if self.record_production_index {
+            self.stack.last_mut().unwrap().production = Some(index);
+        }
+    }
+    fn on_fold(&mut self, _rule: &str, fold: Fold) {
+        let node = self.stack.last_mut().unwrap();
+        node.folds
+            .get_or_insert((fold, Vec::new()))
+            .1
+            .push(node.children.len());
+    }
+    fn on_repetition(&mut self) {
+        if let Some(node) = self.stack.last_mut() {
+            node.repetitions += 1;
+        }
+    }
+    fn on_leaf(&mut self, t: &str, raw: &str) {
+        let captures = core::mem::take(&mut self.captures);
+        if let Some(spans) = &mut self.spans {
+            spans.push(self.span);
+        }
+        self.push(AST::Leaf {
+            t: t.into(),
+            raw: raw.into(),
+            captures,
+        });
+    }
+    fn on_lexem(&mut self, lexem: &Lexem) {
+        self.captures = lexem.captures.clone();
+        self.span = (lexem.start, lexem.start + lexem.raw.len());
+    }
+    fn on_exit(&mut self, rule: &str) {
+        let OpenNode {
+            mut children,
+            folds,
+            repetitions,
+            production,
+            ..
+        } = self.stack.pop().unwrap();
+        // only the tail of a recursive list, nodes of the rule nested elsewhere are kept
+        if self.flatten_repeated {
+            if let Some(AST::Node { t, .. }) = children.last() {
+                if t == rule {
+                    if let Some(AST::Node { children: tail, .. }) = children.pop() {
+                        children.extend(tail);
+                    }
+                }
+            }
+        }
+        if let Some((fold, bounds)) = folds {
+            children = fold_children(rule, children, fold, &bounds);
+        }
+        // counted nodes are kept, so their count isn't lost
+        let counted = self.counted.iter().any(|t| t == rule);
+        if self.bubble_intermediate && children.len() == 1 && !counted {
+            self.push(children.into_iter().next().unwrap());
+        } else {
+            self.push(AST::Node {
+                t: rule.into(),
+                children,
+                count: Some(repetitions).filter(|_| counted),
+                production,
+            });
+        }
+    }
+}
+
+fn pushed<'a>(mut stack: Vec<&'a SymbolType>, s: &'a SymbolType) -> Vec<&'a SymbolType> {
+    stack.push(s);
+    stack
+}
+
+/// Upper bound on the symbols visited when matching a production against more than one lexem
+const LOOKAHEAD_STEPS: usize = 10_000;
+
+/// Candidate atoms by the first character of the input, see `Grammar::char_atoms`
+type CharAtoms = BTreeMap<char, Vec<usize>>;
+
+/// If `atom` can be matched in the lexer mode `mode`
+fn is_active(atom: &Atom, mode: Option<&LexerMode>) -> bool {
+    mode.is_none_or(|m| m.atoms.iter().any(|a| a.name == *atom.name()))
+}
+
+impl Grammar {
+    pub(crate) fn match_input(
+        &self,
+        input: &str,
+        mode: Option<&LexerMode>,
+    ) -> Option<(Lexem, usize)> {
+        self.match_atoms(self.atoms.iter(), input, mode)
+    }
+    /// Like `match_input`, only trying `atoms`
+    fn match_atoms<'g>(
+        &'g self,
+        atoms: impl Iterator<Item = &'g Atom>,
+        input: &str,
+        mode: Option<&LexerMode>,
+    ) -> Option<(Lexem, usize)> {
+        let mut matches = atoms
+            .filter(|atom| is_active(atom, mode))
+            .filter_map(|atom| atom.match_input(input).map(|m| (atom, m)));
+        let matched = match self.lexing {
+            Lexing::FirstMatch => matches.next(),
+            Lexing::LongestMatch => matches.fold(None, |longest, m| match longest {
+                Some((_, (_, len))) if len >= (m.1).1 => longest,
+                _ => Some(m),
+            }),
+        };
+        matched.map(|(atom, (name, i))| {
+            (
+                Lexem {
+                    t: name,
+                    raw: String::from(&input[0..i]),
+                    start: 0,
+                    leading: None,
+                    trailing: None,
+                    captures: atom.captures(input),
+                },
+                i,
+            )
+        })
+    }
+    /// Indices of the atoms that can match text starting with a character, for characters
+    /// matched by an atom with a single character literal. The atom is last, after the atoms
+    /// declared before it that could match first. Empty unless `Lexing::FirstMatch` is used.
+    fn char_atoms(&self) -> CharAtoms {
+        let mut char_atoms = CharAtoms::new();
+        if self.lexing != Lexing::FirstMatch {
+            return char_atoms;
+        }
+        let literals = self.atoms.iter().map(Atom::literal).collect::<Vec<_>>();
+        for (i, literal) in literals.iter().enumerate() {
+            let mut chars = literal.iter().flat_map(|l| l.chars());
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => continue,
+            };
+            if char_atoms.contains_key(&c) {
+                continue;
+            }
+            let mut candidates = literals[..i]
+                .iter()
+                .enumerate()
+                .filter(|(_, l)| l.as_ref().is_none_or(|l| l.starts_with(c)))
+                .map(|(j, _)| j)
+                .collect::<Vec<_>>();
+            candidates.push(i);
+            char_atoms.insert(c, candidates);
+        }
+        char_atoms
+    }
+    /// `char_atoms` from the `atom_tables`, built again if the atoms or the lexing changed
+    /// since. The table only depends on the literals of the atoms, so those are compared.
+    fn cached_char_atoms(&self) -> Cow<'_, CharAtoms> {
+        #[cfg(feature = "std")]
+        {
+            let literals = self.atoms.iter().map(Atom::literal).collect::<Vec<_>>();
+            let (built_for, lexing, char_atoms) = self
+                .atom_tables
+                .char_atoms
+                .get_or_init(|| (literals.clone(), self.lexing, self.char_atoms()));
+            if *lexing == self.lexing && *built_for == literals {
+                return Cow::Borrowed(char_atoms);
+            }
+        }
+        Cow::Owned(self.char_atoms())
+    }
+    fn check_lexem(&self, lexem: &Lexem) -> Result<(), String> {
+        self.atoms
+            .iter()
+            .find(|a| *a.name() == lexem.t)
+            .map(|a| a.check(&lexem.raw))
+            .unwrap_or(Ok(()))
+    }
+    fn first_from_rule(&self, rule: &String) -> Vec<&String> {
+        self.first_from_rule_visiting(rule, &mut Vec::new())
+    }
+    /// Like `first_from_rule`, without following the rules in `visiting` again, so left
+    /// recursion through other rules ends
+    fn first_from_rule_visiting<'a>(
+        &'a self,
+        rule: &String,
+        visiting: &mut Vec<&'a String>,
+    ) -> Vec<&'a String> {
+        let mut first = Vec::new();
+        if visiting.contains(&rule) {
+            return first;
+        }
+        for r in self.rules.iter().filter(|r| r.name == *rule) {
+            visiting.push(&r.name);
+            for s in r.production.first_symbol() {
+                match s {
+                    Symbol::AST(next) => {
+                        first.extend(self.first_from_rule_visiting(next, visiting))
+                    }
+                    s => first.extend(self.first_from_symbol(s)),
+                }
+            }
+            visiting.pop();
+        }
+        first
+    }
+    /// Rules the productions of `rule` can start with that lead back to `rule`, if it is left
+    /// recursive through other rules
+    fn left_recursion<'a>(&'a self, rule: &str) -> Option<Vec<&'a str>> {
+        let mut path = Vec::new();
+        let mut visited = Vec::new();
+        if self.left_recursion_path(rule, rule, &mut path, &mut visited) {
+            Some(path)
+        } else {
+            None
+        }
+    }
+    fn left_recursion_path<'a>(
+        &'a self,
+        target: &str,
+        current: &str,
+        path: &mut Vec<&'a str>,
+        visited: &mut Vec<&'a str>,
+    ) -> bool {
+        for r in self.rules.iter().filter(|r| r.name == current) {
+            for s in r.production.first_symbol() {
+                let next = match s {
+                    Symbol::AST(next) => next.as_str(),
+                    _ => continue,
+                };
+                if next == target {
+                    // recursion of a rule into itself is handled by the parser
+                    if !path.is_empty() {
+                        return true;
+                    }
+                    continue;
+                }
+                if visited.contains(&next) {
+                    continue;
+                }
+                visited.push(next);
+                path.push(next);
+                if self.left_recursion_path(target, next, path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+    fn first_from_symbol<'a>(&'a self, s: &'a Symbol) -> Vec<&'a String> {
+        match s {
+            Symbol::Lexem { t, .. } => vec![t],
+            Symbol::AST(r) => self.first_from_rule(r),
+            Symbol::EOF => vec![],
+            Symbol::Any => self.atoms.iter().map(|a| a.name()).collect(),
+        }
+    }
+    /// Check if the production can start with the next lexems, looking at as many lexems as
+    /// `ParseOptions::lookahead`
+    fn production_matches(&self, p: &SymbolType, lexems: &mut LexemIter) -> bool {
+        let types = lexems.peek_types(self.options.lookahead.max(1));
+        match types.as_slice() {
+            [] => false,
+            [t] => self.production_matches_lexem(p, t),
+            _ => {
+                let mut steps = LOOKAHEAD_STEPS;
+                self.derives_prefix(vec![p], &types, false, &mut steps)
+            }
+        }
+    }
+    /// Check if the symbols in `stack`, the last one first, can derive lexems starting with
+    /// `types`, or a non-empty prefix of them. Gives up and assumes a match when running out
+    /// of `steps`, which bounds the search for recursive rules.
+    fn derives_prefix<'a>(
+        &'a self,
+        mut stack: Vec<&'a SymbolType>,
+        types: &[String],
+        consumed: bool,
+        steps: &mut usize,
+    ) -> bool {
+        if types.is_empty() {
+            return true;
+        }
+        if *steps == 0 {
+            return consumed;
+        }
+        *steps -= 1;
+        let top = match stack.pop() {
+            Some(top) => top,
+            // the rest depends on what follows the production
+            None => return consumed,
+        };
+        match top {
+            SymbolType::Symbol(Symbol::Lexem { t, .. }) => {
+                *t == types[0] && self.derives_prefix(stack, &types[1..], true, steps)
+            }
+            SymbolType::Symbol(Symbol::AST(rule)) => self.rules_named(rule).into_iter().any(|r| {
+                self.derives_prefix(pushed(stack.clone(), &r.production), types, consumed, steps)
+            }),
+            SymbolType::Symbol(Symbol::EOF) => false,
+            SymbolType::Symbol(Symbol::Any) => self.derives_prefix(stack, &types[1..], true, steps),
+            SymbolType::Group(g) => {
+                stack.extend(g.iter().rev());
+                self.derives_prefix(stack, types, consumed, steps)
+            }
+            SymbolType::Optional(o) => {
+                self.derives_prefix(pushed(stack.clone(), o), types, consumed, steps)
+                    || self.derives_prefix(stack, types, consumed, steps)
+            }
+            SymbolType::Repeated(m) => {
+                self.derives_prefix(
+                    pushed(pushed(stack.clone(), top), m),
+                    types,
+                    consumed,
+                    steps,
+                ) || self.derives_prefix(stack, types, consumed, steps)
+            }
+            SymbolType::Switch(a, b) => {
+                self.derives_prefix(pushed(stack.clone(), a), types, consumed, steps)
+                    || self.derives_prefix(pushed(stack, b), types, consumed, steps)
+            }
+        }
+    }
+    fn production_matches_lexem(&self, p: &SymbolType, t: &String) -> bool {
+        p.first_symbol()
+            .iter()
+            .any(|s| self.first_from_symbol(s).contains(&t))
+    }
+    pub fn tokenize(&self, input: &str) -> ParseResult<Vec<Lexem>> {
+        let mut lexems = Lexem::iter(self, input);
+        let tokens = lexems.by_ref().collect();
+        lexems.ok?;
+        Ok(tokens)
+    }
+    /// Like `tokenize`, reusing the lexems at the start of the input that are unchanged since
+    /// the input last lexed with `cache`. A lexem is reused if the text up to the end of the
+    /// lexem after it is unchanged, so atoms must not look further ahead than that to decide
+    /// where they end. Grammars with lexer modes, `indentation` or `newlines` are always
+    /// lexed from the start.
+    pub fn tokenize_cached(&self, input: &str, cache: &mut LexerCache) -> ParseResult<Vec<Lexem>> {
+        let stateful = !self.modes.is_empty() || self.options.indentation || self.options.newlines;
+        let changed = cache
+            .input
+            .bytes()
+            .zip(input.bytes())
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| cache.input.len().min(input.len()));
+        let reused = if stateful {
+            0
+        } else {
+            cache
+                .lexems
+                .windows(2)
+                .take_while(|w| w[1].0.start + w[1].0.raw.len() <= changed)
+                .count()
+        };
+        cache.lexems.truncate(reused);
+
+        let mut lexems = Lexem::iter(self, input);
+        if let Some((_, cursor)) = cache.lexems.last() {
+            lexems.cursor = *cursor;
+        }
+        while let Some(lexem) = lexems.next() {
+            cache.lexems.push((lexem, lexems.cursor));
+        }
+        cache.input = input.into();
+        cache.reused = reused;
+        if let Err(err) = lexems.ok {
+            cache.input.clear();
+            cache.lexems.clear();
+            return Err(err);
+        }
+        Ok(cache.lexems.iter().map(|(l, _)| l.clone()).collect())
+    }
+    /// Like `parse`, lexing the input with `tokenize_cached`
+    pub fn parse_cached(&self, input: &str, cache: &mut LexerCache) -> ParseResult<AST> {
+        let mut lexems = Lexem::iter(self, input);
+        lexems.pending = self.tokenize_cached(input, cache)?.into();
+        lexems.cursor = input.len();
+        let mut builder = AstBuilder::new(self);
+        self.parse_lexems("START", &mut lexems, &mut builder)?;
+        Ok(builder.root.unwrap())
+    }
+    pub fn parse(&self, input: &str) -> ParseResult<AST> {
+        let mut builder = AstBuilder::new(self);
+        self.parse_with_callbacks(input, &mut builder)?;
+        Ok(builder.root.unwrap())
+    }
+    /// Like `parse`, but also returns where in the input every leaf of the tree was found
+    pub fn parse_with_sourcemap(&self, input: &str) -> ParseResult<(AST, SourceMap)> {
+        let mut builder = AstBuilder::new(self);
+        builder.spans = Some(Vec::new());
+        self.parse_with_callbacks(input, &mut builder)?;
+        let spans = builder.spans.take().unwrap();
+        let ast = builder.root.unwrap();
+        let map = ast
+            .leaf_paths()
+            .into_iter()
+            .zip(spans)
+            .map(|(path, (start, end))| (path, start, end))
+            .collect();
+        Ok((ast, map))
+    }
+    /// Like `parse`, but recovers from errors inside rules with productions enclosed in a pair
+    /// of delimiters from `Grammar::recover`, like `( lbrace ( STMT )* rbrace )`. The input
+    /// from the error up to the balanced closing delimiter is skipped and added to the tree as
+    /// an `ERROR` leaf, then parsing continues after it. Returns the tree, partial if an error
+    /// couldn't be recovered from, and every error found.
+    pub fn parse_recovering(&self, input: &str) -> (Option<AST>, Vec<ParseError>) {
+        let mut builder = AstBuilder::new(self);
+        let mut lexems = Lexem::iter(self, input);
+        lexems.errors = Some(Vec::new());
+        let result = self.parse_lexems("START", &mut lexems, &mut builder);
+        let mut errors = lexems.errors.take().unwrap();
+        errors.extend(result.err());
+        (builder.finish(), errors)
+    }
+    /// Parse as much of `input` as possible, returning the tree built until the first error
+    /// along with the error. Nodes not finished at the error only have the children parsed
+    /// before it.
+    pub fn parse_best_effort(&self, input: &str) -> (Option<AST>, Option<ParseError>) {
+        let mut builder = AstBuilder::new(self);
+        let result = self.parse_with_callbacks(input, &mut builder);
+        (builder.finish(), result.err())
+    }
+    /// Parse tokens lexed by something else than this grammar's atoms. Positions in errors are
+    /// byte offsets in the raw strings of the tokens joined together.
+    pub fn parse_tokens(&self, tokens: &[Token]) -> ParseResult<AST> {
+        let mut lexems = Lexem::iter(self, "");
+        for token in tokens {
+            lexems.pending.push_back(Lexem {
+                t: token.t.clone(),
+                raw: token.raw.clone(),
+                start: lexems.cursor,
+                leading: None,
+                trailing: None,
+                captures: BTreeMap::new(),
+            });
+            lexems.cursor += token.raw.len();
+        }
+        let mut builder = AstBuilder::new(self);
+        self.parse_lexems("START", &mut lexems, &mut builder)?;
+        Ok(builder.root.unwrap())
+    }
+    /// Read and parse the file at `path`, errors include the path and the line and column of
+    /// the error
+    #[cfg(feature = "std")]
+    pub fn parse_file<P: AsRef<Path>>(&self, path: P) -> ParseResult<AST> {
+        let path = path.as_ref();
+        let in_file = |error, position| ParseError::File {
+            path: path.display().to_string(),
+            position,
+            error: Box::new(error),
+        };
+        let input = fs::read_to_string(path).map_err(|e| in_file(e.into(), None))?;
+        self.parse(&input).map_err(|error| {
+            let position = error
+                .position()
+                .map(|pos| line_column_with_tabs(&input, pos, self.options.tab_width));
+            in_file(error, position)
+        })
+    }
+    /// Parse the input and convert the syntax tree into `T`
+    pub fn parse_into<T: FromAst>(&self, input: &str) -> ParseResult<T> {
+        T::from_ast(&self.parse(input)?).map_err(ParseError::Convert)
+    }
+    /// Parse the input without building an `AST`, calling `callbacks` for every node as it is
+    /// parsed. `bubble_intermediate` has no effect here, as nodes are reported before their
+    /// children are known.
+    pub fn parse_with_callbacks(
+        &self,
+        input: &str,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        debug!("parsing input:\n{}", input);
+
+        let mut lexems = Lexem::iter(self, input);
+        self.parse_lexems("START", &mut lexems, callbacks)
+    }
+    /// Like `parse_with_callbacks`, with the ignored characters recorded as trivia of the
+    /// lexems given to `ParseCallbacks::on_lexem`
+    pub(crate) fn parse_with_trivia(
+        &self,
+        input: &str,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        let options = ParseOptions {
+            record_trivia: true,
+            ..self.options.clone()
+        };
+        let mut lexems = Lexem::iter(self, input);
+        lexems.options = &options;
+        self.parse_lexems("START", &mut lexems, callbacks)
+    }
+    /// Like `parse`, but also reports the rules where the input matched more than one
+    /// production. The first matching production is always chosen, so the tree might not be
+    /// the intended one when there are warnings.
+    pub fn parse_with_ambiguity_warnings(
+        &self,
+        input: &str,
+    ) -> ParseResult<(AST, Vec<AmbiguityWarning>)> {
+        let mut builder = AstBuilder::new(self);
+        let mut lexems = Lexem::iter(self, input);
+        lexems.ambiguities = Some(Vec::new());
+        self.parse_lexems("START", &mut lexems, &mut builder)?;
+        Ok((builder.root.unwrap(), lexems.ambiguities.unwrap()))
+    }
+    /// Parse `input` as the rule `rule` instead of `START`, like for parsing a fragment of a
+    /// larger document. All of the input must match the rule.
+    pub fn parse_rule_public(&self, rule: &str, input: &str) -> ParseResult<AST> {
+        if !self.rules.iter().any(|r| r.name == rule) {
+            return Err(ParseError::Grammar(format!("no rule named '{}'", rule)));
+        }
+        let mut builder = AstBuilder::new(self);
+        let mut lexems = Lexem::iter(self, input);
+        self.parse_lexems(rule, &mut lexems, &mut builder)?;
+        Ok(builder.root.unwrap())
+    }
+    fn parse_lexems(
+        &self,
+        rule: &str,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        if self.transparent.iter().any(|t| t == rule) {
+            return Err(ParseError::Grammar(format!(
+                "rule '{}' is transparent, it can't be the root of the syntax tree",
+                rule
+            )));
+        }
+        let result = self.parse_rule(&rule.into(), lexems, callbacks);
+        // a lexer error is the cause of any parse error found after it
+        lexems.ok.clone()?;
+        result?;
+
+        if let Some(l) = lexems.peek() {
+            return Err(ParseError::token(l, "expected EOF".into()));
+        }
+        lexems.ok.clone()?;
+        Ok(())
+    }
+    /// Tokens that could come next after `input`, like for completing a partially written
+    /// input. Fails if the input can't be parsed up to its end.
+    pub fn complete(&self, input: &str) -> ParseResult<Vec<String>> {
+        self.expected_after(Lexem::iter(self, input))
+    }
+    /// Tokens that could come at the byte `offset` of `input`, after the lexems ending before
+    /// it, like for "expected one of ..." hints in an editor. The input after `offset` is
+    /// ignored. Empty if the input can't be parsed up to `offset`.
+    pub fn expected_at(&self, input: &str, offset: usize) -> Vec<String> {
+        let mut all = Lexem::iter(self, input);
+        let mut lexems = Lexem::iter(self, "");
+        for l in all.by_ref() {
+            if l.start + l.raw.len() > offset {
+                break;
+            }
+            lexems.pending.push_back(l);
+        }
+        // lexer errors after the offset don't matter
+        if matches!(&all.ok, Err(e) if e.position().is_none_or(|p| p < offset)) {
+            return Vec::new();
+        }
+        lexems.cursor = offset;
+        self.expected_after(lexems).unwrap_or_default()
+    }
+    /// Tokens the parser looked for after the last of `lexems`
+    fn expected_after(&self, mut lexems: LexemIter) -> ParseResult<Vec<String>> {
+        let result = self.parse_rule(&"START".into(), &mut lexems, &mut NoCallbacks);
+        lexems.ok.clone()?;
+        if let Some(l) = lexems.peek() {
+            let err = ParseError::token(l, "expected EOF".into());
+            result?;
+            return Err(err);
+        }
+
+        let mut expected = lexems.expected;
+        expected.sort();
+        expected.dedup();
+        Ok(expected)
+    }
+    /// Find the lexem covering the byte `offset` in the input, and the rules that consumed it.
+    /// If the input can't be parsed, lexems found before the error are still reported.
+    pub fn explain_token(&self, input: &str, offset: usize) -> ParseResult<Option<TokenInfo>> {
+        let mut finder = TokenFinder {
+            offset,
+            rules: Vec::new(),
+            found: None,
+        };
+        let result = self.parse_with_callbacks(input, &mut finder);
+        match finder.found {
+            Some(info) => Ok(Some(info)),
+            None => result.map(|_| None),
+        }
+    }
+    fn parse_rule(
+        &self,
+        rule: &String,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        span!("rule", name = %rule);
+        let cursor = lexems.cursor;
+        if lexems.peek().is_none() {
+            let first = self.first_from_rule(rule).into_iter().cloned();
+            lexems.expected.extend(first);
+        }
+        let peeked = lexems
+            .peek()
+            .ok_or(ParseError::Input(cursor, "unexpected EOF".into()))?
+            .clone();
+        debug!("parsing rule: {:?}", rule);
+        debug!("peeked: {:?}", peeked);
+
+        let rules = self.rules_named(rule);
+
+        if rules.is_empty() {
+            return Err(ParseError::NoMatch(format!("no rule named '{}'", rule)));
+        }
+
+        debug!("rules found: {:?}", rules);
+
+        let chosen = rules
+            .iter()
+            .position(|r| self.production_matches(&r.production, lexems));
+        if let Some(i) = chosen {
+            if lexems.ambiguities.is_some() {
+                let candidates: Vec<usize> = (i..rules.len())
+                    .filter(|&j| j == i || self.production_matches(&rules[j].production, lexems))
+                    .collect();
+                if candidates.len() > 1 {
+                    let position = lexems.peek().map(|l| l.start).unwrap_or(cursor);
+                    lexems.ambiguities.as_mut().unwrap().push(AmbiguityWarning {
+                        rule: rule.clone(),
+                        position,
+                        candidates,
+                    });
+                }
+            }
+            let Rule {
+                production, label, ..
+            } = rules[i];
+            debug!("choosing production: {:?}", production);
+
+            let trailing = lexems.trailing_separators;
+            lexems.trailing_separators = self.trailing_separators.contains(rule);
+            let result = if self.transparent.contains(rule) {
+                self.parse_recoverable(production, lexems, callbacks)
+            } else {
+                let t = label.as_ref().unwrap_or(rule);
+                callbacks.on_enter(t);
+                callbacks.on_production(t, i);
+                let result = match self.folds.iter().find(|(r, _)| r == rule) {
+                    Some((_, fold)) => self.parse_folded(t, *fold, production, lexems, callbacks),
+                    None => self.parse_recoverable(production, lexems, callbacks),
+                };
+                callbacks.on_exit(t);
+                result
+            };
+            lexems.trailing_separators = trailing;
+            return result;
+        }
+
+        Err(ParseError::token(
+            &peeked,
+            format!(
+                "unexpected token '{}' when trying to parse rule '{}'",
+                peeked.t, rule
+            ),
+        ))
+    }
+    /// Parse `production`, and if it is enclosed in a pair of delimiters from
+    /// `Grammar::recover` and errors are collected, recover from errors inside it by skipping
+    /// to the balanced closing delimiter
+    fn parse_recoverable(
+        &self,
+        production: &SymbolType,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        let pair = match production {
+            SymbolType::Group(g) if g.len() >= 2 && lexems.errors.is_some() => {
+                self.recover.iter().position(|(open, close)| {
+                    matches!(&g[0], SymbolType::Symbol(Symbol::Lexem { t, .. }) if t == open)
+                        && matches!(&g[g.len() - 1], SymbolType::Symbol(Symbol::Lexem { t, .. }) if t == close)
+                })
+            }
+            _ => None,
+        };
+        let pair = match pair {
+            Some(pair) => pair,
+            None => return self.parse_symbol_type(production, lexems, callbacks),
+        };
+        // depth of the delimiters inside the opening one of this production
+        let depth = lexems.depths[pair] + 1;
+        let err = match self.parse_symbol_type(production, lexems, callbacks) {
+            Err(err) if lexems.ok.is_ok() && lexems.depths[pair] >= depth => err,
+            result => return result,
+        };
+        let close = &self.recover[pair].1;
+        let start = err.position().unwrap_or(lexems.cursor);
+        let end = loop {
+            let at_depth = lexems.depths[pair] == depth;
+            match lexems.peek() {
+                Some(l) if l.t == *close && at_depth => break l.start,
+                Some(_) => {
+                    lexems.next();
+                }
+                None => return Err(err),
+            }
+        };
+        lexems.ok.clone()?;
+        callbacks.on_leaf("ERROR", &lexems.input[start.min(end)..end]);
+        lexems.errors.as_mut().unwrap().push(err);
+        match production {
+            SymbolType::Group(g) => self.parse_symbol_type(&g[g.len() - 1], lexems, callbacks),
+            _ => unreachable!(),
+        }
+    }
+    /// Parse `production` of a rule folded in the direction `fold`, calling `on_fold` before
+    /// each repetition after the first of the repeated group it ends with
+    fn parse_folded(
+        &self,
+        t: &str,
+        fold: Fold,
+        production: &SymbolType,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        match production {
+            SymbolType::Group(g) => match g.split_last() {
+                Some((SymbolType::Repeated(m), init)) => {
+                    for s in init.iter() {
+                        self.parse_symbol_type(s, lexems, callbacks)?;
+                    }
+                    self.parse_repeated(m, lexems, callbacks, Some((t, fold)))
+                }
+                _ => self.parse_recoverable(production, lexems, callbacks),
+            },
+            _ => self.parse_recoverable(production, lexems, callbacks),
+        }
+    }
+    /// Parse `m` as many times as possible, calling `on_fold` before each repetition after the
+    /// first if `fold` is given
+    fn parse_repeated(
+        &self,
+        m: &SymbolType,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+        fold: Option<(&str, Fold)>,
+    ) -> ParseResult<()> {
+        let separator = if lexems.trailing_separators {
+            trailing_separator(m)
+        } else {
+            None
+        };
+        let mut first = true;
+        loop {
+            if let Some(separator) = separator {
+                if self.at_trailing_separator(m, separator, lexems) {
+                    self.parse_symbol(separator, lexems, callbacks)?;
+                    break;
+                }
+            }
+            if !self.production_matches(m, lexems) {
+                break;
+            }
+            if let (Some((t, fold)), false) = (fold, first) {
+                callbacks.on_fold(t, fold);
+            }
+            first = false;
+            callbacks.on_repetition();
+            self.parse_symbol_type(m, lexems, callbacks)?;
+        }
+        if lexems.peek().is_none() {
+            lexems
+                .expected
+                .extend(self.first_lexems(m).into_iter().cloned());
+        }
+        Ok(())
+    }
+    fn parse_symbol_type(
+        &self,
+        s: &SymbolType,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        span!("symbol", symbol = s.trace_label());
+        match s {
+            SymbolType::Symbol(s) => {
+                self.parse_symbol(s, lexems, callbacks)?;
+            }
+            SymbolType::Group(g) => {
+                for s in g.iter() {
+                    self.parse_symbol_type(s, lexems, callbacks)?;
+                }
+            }
+            SymbolType::Optional(o) => {
+                if lexems.peek().is_none() {
+                    lexems
+                        .expected
+                        .extend(self.first_lexems(o).into_iter().cloned());
+                } else if self.production_matches(o, lexems) {
+                    self.parse_symbol_type(o, lexems, callbacks)?;
+                }
+            }
+            SymbolType::Repeated(m) => self.parse_repeated(m, lexems, callbacks, None)?,
+            SymbolType::Switch(a, b) => {
+                let cursor = lexems.cursor;
+                if lexems.peek().is_none() {
+                    lexems
+                        .expected
+                        .extend(self.first_lexems(s).into_iter().cloned());
+                }
+                lexems
+                    .peek()
+                    .ok_or(ParseError::Input(cursor, "unexpected EOF".into()))?;
+                if self.production_matches(a, lexems) {
+                    self.parse_symbol_type(a, lexems, callbacks)?;
+                } else {
+                    self.parse_symbol_type(b, lexems, callbacks)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Check if the next lexem is `separator` and the lexem after it can't continue `m`
+    fn at_trailing_separator(
+        &self,
+        m: &SymbolType,
+        separator: &Symbol,
+        lexems: &mut LexemIter,
+    ) -> bool {
+        let types = lexems.peek_types(2);
+        match (separator, types.as_slice()) {
+            (Symbol::Lexem { t, .. }, [next]) => next == t,
+            (Symbol::Lexem { t, .. }, [next, _]) if next == t => {
+                let mut steps = LOOKAHEAD_STEPS;
+                !self.derives_prefix(vec![m], &types, false, &mut steps)
+            }
+            _ => false,
+        }
+    }
+    fn parse_symbol(
+        &self,
+        s: &Symbol,
+        lexems: &mut LexemIter,
+        callbacks: &mut dyn ParseCallbacks,
+    ) -> ParseResult<()> {
+        match s {
+            Symbol::Lexem { t, include_raw } => {
+                if lexems.peek().map(|p| p.t == *t).unwrap_or(false) {
+                    let a = lexems.next().unwrap();
+                    callbacks.on_lexem(&a);
+                    if *include_raw {
+                        let t = self.aliases.get(&a.t).unwrap_or(&a.t);
+                        if self.decode_escapes.contains(&a.t) {
+                            callbacks.on_leaf(t, &decode_escapes(&a.raw));
+                        } else {
+                            callbacks.on_leaf(t, &a.raw);
+                        }
+                    }
+                    Ok(())
+                } else {
+                    match lexems.peek() {
+                        Some(l) => Err(ParseError::token(
+                            l,
+                            format!("expected token '{}' found '{}'", t, l.t),
+                        )),
+                        None => {
+                            lexems.expected.push(t.clone());
+                            Err(ParseError::Input(
+                                lexems.cursor,
+                                format!("expected token '{}' found 'EOF'", t),
+                            ))
+                        }
+                    }
+                }
+            }
+            Symbol::AST(rule) => self.parse_rule(rule, lexems, callbacks),
+            Symbol::Any => match lexems.peek() {
+                Some(l) => {
+                    let t = l.t.clone();
+                    self.parse_symbol(
+                        &Symbol::Lexem {
+                            t,
+                            include_raw: true,
+                        },
+                        lexems,
+                        callbacks,
+                    )
+                }
+                None => Err(ParseError::Input(
+                    lexems.cursor,
+                    "expected any token found 'EOF'".into(),
+                )),
+            },
+            Symbol::EOF => match lexems.peek() {
+                None => Ok(()),
+                Some(l) => Err(ParseError::token(
+                    l,
+                    format!("expected EOF found '{}'", l.t),
+                )),
+            },
+        }
+    }
+    /// Check the grammar for problems that would make it fail or behave unexpectedly when
+    /// parsing, returning a description of each problem found
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.rules.iter().any(|r| r.name == "START") {
+            problems.push("no rule named 'START'".to_string());
+        }
+        for name in self.transparent.iter() {
+            if name == "START" {
+                problems.push("rule 'START' can't be transparent".to_string());
+            } else if !self.rules.iter().any(|r| r.name == *name) {
+                problems.push(format!("undefined rule '{}' is transparent", name));
+            }
+        }
+        for name in self.counted.iter() {
+            if !self.rules.iter().any(|r| r.name == *name) {
+                problems.push(format!("undefined rule '{}' is counted", name));
+            }
+        }
+        for name in self.trailing_separators.iter() {
+            if !self.rules.iter().any(|r| r.name == *name) {
+                problems.push(format!(
+                    "undefined rule '{}' accepts trailing separators",
+                    name
+                ));
+            }
+        }
+        for name in self.hidden.iter() {
+            if !self.atoms.iter().any(|a| a.name() == name) {
+                problems.push(format!("undefined atom '{}' is hidden", name));
+            }
+        }
+        for (name, _) in self.folds.iter() {
+            let folded = self.rules_named(name).iter().any(|r| match &r.production {
+                SymbolType::Group(g) => matches!(g.last(), Some(SymbolType::Repeated(_))),
+                _ => false,
+            });
+            if !folded {
+                problems.push(format!(
+                    "rule '{}' is folded, but none of its productions end with a repeated group",
+                    name
+                ));
+            }
+        }
+
+        for rule in self.rules.iter() {
+            rule.production.walk(&mut |s| match s {
+                SymbolType::Symbol(Symbol::AST(name))
+                    if !self.rules.iter().any(|r| r.name == *name) =>
+                {
+                    problems.push(format!(
+                        "rule '{}' references undefined rule '{}'",
+                        rule.name, name
+                    ));
+                }
+                SymbolType::Symbol(Symbol::Lexem { t, .. })
+                    if !self.atoms.iter().any(|a| a.name() == t)
+                        && !(self.options.indentation && (t == "indent" || t == "dedent"))
+                        && !(self.options.newlines && t == "newline") =>
+                {
+                    problems.push(format!(
+                        "rule '{}' references undefined atom '{}'",
+                        rule.name, t
+                    ));
+                }
+                SymbolType::Symbol(Symbol::Lexem { t, .. }) if self.hidden.contains(t) => {
+                    problems.push(format!(
+                        "rule '{}' references hidden atom '{}', it is always skipped",
+                        rule.name, t
+                    ));
+                }
+                SymbolType::Switch(a, b) => {
+                    // the left side is only parsed when the next lexem can start it, so it
+                    // never matches nothing unless the right side can
+                    if a.nullable() && !b.nullable() {
+                        problems.push(format!(
+                            "rule '{}': the left side of '|' can be empty, but the right side \
+                             is required whenever the next token can't start the left side",
+                            rule.name
+                        ));
+                    }
+                    let conflicts = self.shared_first_lexems(a, b);
+                    let right = self.first_lexems(b);
+                    if !right.is_empty() && conflicts.len() == right.len() {
+                        problems.push(format!(
+                            "rule '{}': the right side of '|' is never used, all tokens it can \
+                             start with also start the left side",
+                            rule.name
+                        ));
+                        return;
+                    }
+                    for t in conflicts {
+                        problems.push(format!(
+                            "rule '{}': both sides of '|' can start with '{}'",
+                            rule.name, t
+                        ));
+                    }
+                }
+                _ => {}
+            });
+        }
+
+        let mut recursive: Vec<&str> = Vec::new();
+        for name in self.rule_names() {
+            if recursive.contains(&name) {
+                continue;
+            }
+            if let Some(path) = self.left_recursion(name) {
+                problems.push(format!(
+                    "rule '{}' is left recursive through {}",
+                    name,
+                    path.join(", ")
+                ));
+                recursive.push(name);
+                recursive.extend(path);
+            }
+        }
+
+        for atom in self.atoms.iter() {
+            if let Atom::Matched { name, m } = atom {
+                if m.match_prefix("") == Some(0) {
+                    problems.push(format!(
+                        "atom '{}' can match an empty string, empty matches are ignored",
+                        name
+                    ));
+                }
+            }
+        }
+        problems.extend(self.anchored_atoms());
+
+        for conflict in self.first_conflicts() {
+            let (i, j) = conflict.production_indices;
+            problems.push(format!(
+                "rule '{}': productions {} and {} can both start with '{}', only the first will \
+                 be used",
+                conflict.rule,
+                i + 1,
+                j + 1,
+                conflict.token
+            ));
+        }
+
+        problems
+    }
+    /// Pairs of productions of the same rule that can start with the same lexem, where only the
+    /// first one will be used. Rules are sorted by name.
+    pub fn first_conflicts(&self) -> Vec<FirstConflict> {
+        let mut names = self.rules.iter().map(|r| &r.name).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        let mut conflicts = Vec::new();
+        for name in names {
+            let productions = self.productions_for(name);
+            for (i, a) in productions.iter().enumerate() {
+                for (j, b) in productions.iter().enumerate().skip(i + 1) {
+                    for t in self.shared_first_lexems(a, b) {
+                        conflicts.push(FirstConflict {
+                            rule: name.clone(),
+                            token: t.clone(),
+                            production_indices: (i, j),
+                        });
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+    /// Problems with atom regexes containing anchors, which don't make sense as atoms are
+    /// always matched at the start of the remaining input
+    pub(crate) fn anchored_atoms(&self) -> Vec<String> {
+        self.atoms
+            .iter()
+            .filter_map(|atom| match atom {
+                Atom::Matched { name, m } => m.pattern().and_then(regex_anchor).map(|anchor| {
+                    format!(
+                        "atom '{}' contains the anchor '{}', atoms are always matched at the \
+                         start of the remaining input",
+                        name, anchor
+                    )
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+    /// Rewrite all productions into an equivalent form without redundant nesting, like groups
+    /// with a single symbol or optional optionals, so equivalent grammars become structurally
+    /// equal
+    pub fn canonicalize(&mut self) {
+        for rule in self.rules.iter_mut() {
+            let production = core::mem::replace(&mut rule.production, SymbolType::Group(vec![]));
+            rule.production = production.canonical();
+        }
+    }
+    /// `rule` and the rules it references, directly or through other rules
+    fn reachable_rules(&self, rule: &str) -> Vec<String> {
+        let mut reachable = vec![rule.to_string()];
+        let mut i = 0;
+        while i < reachable.len() {
+            for rule in self.rules_named(&reachable[i]) {
+                rule.production.walk(&mut |s| {
+                    if let SymbolType::Symbol(Symbol::AST(name)) = s {
+                        if !reachable.contains(name) {
+                            reachable.push(name.clone());
+                        }
+                    }
+                });
+            }
+            i += 1;
+        }
+        reachable
+    }
+    /// Names of the atoms that can appear in the input of `rule`, used by it or any rule it
+    /// references, in the order they are declared
+    pub fn reachable_atoms(&self, rule: &str) -> Vec<&str> {
+        let mut used = Vec::new();
+        for name in self.reachable_rules(rule) {
+            for rule in self.rules_named(&name) {
+                rule.production.walk(&mut |s| {
+                    if let SymbolType::Symbol(Symbol::Lexem { t, .. }) = s {
+                        used.push(t);
+                    }
+                });
+            }
+        }
+        self.atoms
+            .iter()
+            .map(|a| a.name())
+            .filter(|name| used.contains(name))
+            .map(String::as_str)
+            .collect()
+    }
+    /// Inline rules with a single unlabeled production that are referenced only once and not
+    /// named in a directive like `@fold` or `@count`, and remove rules that can't be reached
+    /// from `START`. The grammar accepts the same inputs, but the syntax trees don't have
+    /// nodes for the inlined rules.
+    pub fn minimize(&mut self) {
+        if self.rules.iter().any(|r| r.name == "START") {
+            let reachable = self.reachable_rules("START");
+            self.rules.retain(|r| reachable.contains(&r.name));
+        }
+        while let Some(i) = self.inlinable_rule() {
+            let rule = self.rules.remove(i);
+            for other in self.rules.iter_mut() {
+                if other.production.references(&rule.name) {
+                    let production =
+                        core::mem::replace(&mut other.production, SymbolType::Group(vec![]));
+                    other.production = production.inline(&rule.name, &rule.production).canonical();
+                }
+            }
+        }
+    }
+    /// Index of a rule `minimize` can inline
+    fn inlinable_rule(&self) -> Option<usize> {
+        self.rules.iter().position(|rule| {
+            let mut references = 0;
+            for other in self.rules.iter() {
+                other.production.walk(&mut |s| match s {
+                    SymbolType::Symbol(Symbol::AST(name)) if *name == rule.name => references += 1,
+                    _ => {}
+                });
+            }
+            rule.name != "START"
+                && rule.label.is_none()
+                // directives on the rule change its nodes or how it is parsed
+                && !self.folds.iter().any(|(name, _)| *name == rule.name)
+                && !self.counted.contains(&rule.name)
+                && !self.transparent.contains(&rule.name)
+                && !self.trailing_separators.contains(&rule.name)
+                && references == 1
+                && !rule.production.references(&rule.name)
+                && self.rules_named(&rule.name).len() == 1
+        })
+    }
+    /// Names of the rules in the grammar, in the order they are first defined
+    pub fn rule_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for rule in self.rules.iter() {
+            if !names.contains(&rule.name.as_str()) {
+                names.push(&rule.name);
+            }
+        }
+        names
+    }
+    /// Names of the atoms in the grammar, in the order they are defined
+    pub fn atom_names(&self) -> Vec<&str> {
+        self.atoms.iter().map(|a| a.name().as_str()).collect()
+    }
+    /// Rules named `rule`, in the order their productions should be tried
+    pub(crate) fn rules_named(&self, rule: &str) -> Vec<&Rule> {
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|r| r.name == rule)
+            .collect::<Vec<_>>();
+        rules.sort_by_key(|r| r.order);
+        rules
+    }
+    /// Productions of the rules named `rule`, in the order they are tried when parsing
+    pub fn productions_for(&self, rule: &str) -> Vec<&SymbolType> {
+        self.rules_named(rule)
+            .into_iter()
+            .map(|r| &r.production)
+            .collect()
+    }
+    fn first_lexems<'a>(&'a self, p: &'a SymbolType) -> Vec<&'a String> {
+        let mut first = p
+            .first_symbol()
+            .into_iter()
+            .flat_map(|s| self.first_from_symbol(s))
+            .collect::<Vec<_>>();
+        first.sort();
+        first.dedup();
+        first
+    }
+    fn shared_first_lexems<'a>(&'a self, a: &'a SymbolType, b: &'a SymbolType) -> Vec<&'a String> {
+        let b = self.first_lexems(b);
+        self.first_lexems(a)
+            .into_iter()
+            .filter(|t| b.contains(t))
+            .collect()
+    }
+    /// Compare the rules, atoms, aliases, lexer modes and skipped patterns of two grammars, ignoring the parse options.
+    /// Rules are equal if they try the same productions with the same labels in the same order.
+    /// Matched atoms are equal if the source of their regexes are equal.
+    pub fn structurally_equal(&self, other: &Grammar) -> bool {
+        let mut names = self.rule_names();
+        names.sort_unstable();
+        let mut other_names = other.rule_names();
+        other_names.sort_unstable();
+        names == other_names
+            && names.iter().all(|name| {
+                let (a, b) = (self.rules_named(name), other.rules_named(name));
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(a, b)| a.label == b.label && a.production == b.production)
+            })
+            && self.atoms == other.atoms
+            && self.modes == other.modes
+            && self.skip == other.skip
+            && self.aliases == other.aliases
+            && self.decode_escapes == other.decode_escapes
+            && self.transparent == other.transparent
+            && self.hidden == other.hidden
+            && self.recover == other.recover
+            && self.folds == other.folds
+            && self.counted == other.counted
+            && self.trailing_separators == other.trailing_separators
+    }
+    pub fn with_options(mut self, options: ParseOptions) -> Grammar {
+        self.options = options;
+        self
+    }
+}
+
+/// Replace escape sequences in `raw` with the characters they represent. Unknown or
+/// malformed escapes are kept as they are.
+pub(crate) fn decode_escapes(raw: &str) -> String {
+    let mut decoded = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(i) = rest.find('\\') {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let (c, len) = match rest[1..].chars().next() {
+            Some('n') => (Some('\n'), 2),
+            Some('t') => (Some('\t'), 2),
+            Some('r') => (Some('\r'), 2),
+            Some('0') => (Some('\0'), 2),
+            Some('b') => (Some('\u{8}'), 2),
+            Some('f') => (Some('\u{c}'), 2),
+            Some(c @ ('\\' | '"' | '\'' | '/')) => (Some(c), 2),
+            Some('x') => (hex_code(rest, 2, 2).and_then(char::from_u32), 4),
+            Some('u') => match (hex_code(rest, 2, 4), hex_code(rest, 8, 4)) {
+                // surrogate pair, like "🦀"
+                (Some(high @ 0xd800..=0xdbff), Some(low @ 0xdc00..=0xdfff))
+                    if rest[6..].starts_with("\\u") =>
+                {
+                    let code = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+                    (char::from_u32(code), 12)
+                }
+                (Some(code), _) => (char::from_u32(code), 6),
+                _ => (None, 0),
+            },
+            _ => (None, 0),
+        };
+        let len = match c {
+            Some(c) => {
+                decoded.push(c);
+                len
+            }
+            None => {
+                let len = 1 + rest[1..].chars().next().map(char::len_utf8).unwrap_or(0);
+                decoded.push_str(&rest[..len]);
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The value of the `digits` hex digits starting at byte `start` of `s`
+fn hex_code(s: &str, start: usize, digits: usize) -> Option<u32> {
+    let hex = s.get(start..start + digits)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+#[derive(Debug, Clone)]
+pub struct Lexem {
+    pub t: String,
+    pub raw: String,
+    /// Byte offset of the lexem in the input
+    pub start: usize,
+    /// Ignored characters before the lexem, only recorded with `ParseOptions::record_trivia`
+    pub leading: Option<Trivia>,
+    /// Ignored characters after the lexem, only recorded with `ParseOptions::record_trivia`
+    pub trailing: Option<Trivia>,
+    /// Named groups matched by the atom, see `AtomMatcher::captures`
+    pub captures: BTreeMap<String, String>,
+}
+
+/// Path of child indices from the root to a leaf, with the start and end byte offsets of the
+/// leaf in the input, for every leaf of a tree. See `Grammar::parse_with_sourcemap`.
+pub type SourceMap = Vec<(Vec<usize>, usize, usize)>;
+
+/// Lexems of the last input lexed with the cache, see `Grammar::tokenize_cached`
+#[derive(Debug, Default)]
+pub struct LexerCache {
+    input: String,
+    /// Lexems of `input`, with the lexer position after each of them
+    lexems: Vec<(Lexem, usize)>,
+    reused: usize,
+}
+
+impl LexerCache {
+    pub fn new() -> Self {
+        LexerCache::default()
+    }
+    /// Number of lexems reused from the previous input when lexing the last one
+    pub fn reused(&self) -> usize {
+        self.reused
+    }
+}
+
+/// A lexem from outside the grammar, see `Grammar::parse_tokens`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub t: String,
+    pub raw: String,
+}
+
+impl Token {
+    pub fn new(t: &str, raw: &str) -> Token {
+        Token {
+            t: t.into(),
+            raw: raw.into(),
+        }
+    }
+}
+
+/// A run of ignored characters, starting at byte offset `start` in the input
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia {
+    pub start: usize,
+    pub raw: String,
+}
+
+impl Lexem {
+    fn iter<'a>(grammar: &'a Grammar, input: &'a str) -> LexemIter<'a> {
+        let max = grammar.options.max_input_bytes;
+        LexemIter {
+            grammar,
+            input,
+            cursor: 0,
+            ok: if max > 0 && input.len() > max {
+                Err(ParseError::InputTooLarge {
+                    size: input.len(),
+                    max,
+                })
+            } else {
+                Ok(())
+            },
+            peeked: VecDeque::new(),
+            options: &grammar.options,
+            modes: vec![0],
+            expected: Vec::new(),
+            indents: vec![0],
+            pending: VecDeque::new(),
+            ambiguities: None,
+            line_has_lexem: false,
+            errors: None,
+            depths: vec![0; grammar.recover.len()],
+            char_atoms: grammar.cached_char_atoms(),
+            trailing_separators: false,
+        }
+    }
+}
+
+/// The separator starting the repeated symbol `m`, if it is a group starting with a lexem
+fn trailing_separator(m: &SymbolType) -> Option<&Symbol> {
+    match m {
+        SymbolType::Group(g) if g.len() > 1 => match &g[0] {
+            SymbolType::Symbol(s @ Symbol::Lexem { .. }) => Some(s),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The first anchor like `^` or `$` in `regex`, outside of character classes
+fn regex_anchor(regex: &str) -> Option<&'static str> {
+    let mut chars = regex.chars();
+    let mut class_depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('A') if class_depth == 0 => return Some("\\A"),
+                Some('z') if class_depth == 0 => return Some("\\z"),
+                _ => {}
+            },
+            '[' => class_depth += 1,
+            ']' if class_depth > 0 => class_depth -= 1,
+            '^' if class_depth == 0 => return Some("^"),
+            '$' if class_depth == 0 => return Some("$"),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Line and column, both starting at 1, of the byte offset `pos` in `input`. Columns are
+/// counted in characters, the `\r` of `\r\n` line breaks isn't counted.
+pub fn line_column(input: &str, pos: usize) -> (usize, usize) {
+    line_column_with_tabs(input, pos, 1)
+}
+
+/// Like `line_column`, with tabs moving the column to the next tab stop, every `tab_width`
+/// columns
+pub fn line_column_with_tabs(input: &str, pos: usize, tab_width: usize) -> (usize, usize) {
+    let tab_width = tab_width.max(1);
+    let before = &input[..pos.min(input.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..]
+        .trim_end_matches('\r')
+        .chars()
+        .fold(0, |column, c| match c {
+            '\t' => (column / tab_width + 1) * tab_width,
+            _ => column + 1,
+        });
+    (before.matches('\n').count() + 1, column + 1)
+}
+
+/// Number of characters before the byte offset `pos` in `input`, for positions counted in
+/// characters like in most editors
+pub fn byte_to_char_offset(input: &str, pos: usize) -> usize {
+    input.char_indices().take_while(|(i, _)| *i < pos).count()
+}
+
+/// Characters of the input included in the error when no atom matches it
+const SNIPPET_CHARS: usize = 10;
+
+/// Lexem not matched from the input, like `indent` and `dedent`
+fn synthetic_lexem(t: &str, start: usize) -> Lexem {
+    Lexem {
+        t: t.into(),
+        raw: String::new(),
+        start,
+        leading: None,
+        trailing: None,
+        captures: BTreeMap::new(),
+    }
+}
+
+#[derive(Clone)]
+struct LexemIter<'a> {
+    grammar: &'a Grammar,
+    input: &'a str,
+    cursor: usize,
+    ok: ParseResult<()>,
+    /// Lexems lexed ahead of the parser
+    peeked: VecDeque<Lexem>,
+    options: &'a ParseOptions,
+    /// Stack of indices into `grammar.modes`
+    modes: Vec<usize>,
+    /// Lexems the parser looked for after the end of the input
+    expected: Vec<String>,
+    /// Widths of the open indentation levels, see `ParseOptions::indentation`
+    indents: Vec<usize>,
+    /// Lexed lexems not yet returned, after `indent` or `dedent` lexems emitted before them
+    pending: VecDeque<Lexem>,
+    /// Rules matching more than one production, only collected when not `None`
+    ambiguities: Option<Vec<AmbiguityWarning>>,
+    /// If a lexem was lexed since the last line break, see `ParseOptions::newlines`
+    line_has_lexem: bool,
+    /// Errors recovered from, only collected when not `None`
+    errors: Option<Vec<ParseError>>,
+    /// Number of consumed opening minus closing lexems of each pair in `Grammar::recover`
+    depths: Vec<isize>,
+    /// Atoms tried for single characters, instead of all of them
+    char_atoms: Cow<'a, CharAtoms>,
+    /// If the rule being parsed is in `Grammar::trailing_separators`
+    trailing_separators: bool,
+}
+
+impl LexemIter<'_> {
+    fn peek(&mut self) -> Option<&Lexem> {
+        self.peek_n(0)
+    }
+    /// The `n`th lexem after the next one, without consuming any lexems
+    fn peek_n(&mut self, n: usize) -> Option<&Lexem> {
+        while self.peeked.len() <= n {
+            let lexem = self.lex()?;
+            self.peeked.push_back(lexem);
+        }
+        self.peeked.get(n)
+    }
+    /// Types of the next `n` lexems, fewer if the input ends before
+    fn peek_types(&mut self, n: usize) -> Vec<String> {
+        (0..n)
+            .map_while(|i| self.peek_n(i).map(|l| l.t.clone()))
+            .collect()
+    }
+    fn shift(&mut self) -> Option<Lexem> {
+        match self.peeked.pop_front() {
+            Some(lexem) => Some(lexem),
+            None => self.lex(),
+        }
+    }
+    fn lex(&mut self) -> Option<Lexem> {
+        if let Some(lexem) = self.pending.pop_front() {
+            return Some(lexem);
+        }
+        if self.ok.is_err() {
+            return None;
+        }
+        let leading = if self.cursor < self.input.len() {
+            self.skip_ignored()
+        } else {
+            None
+        };
+        if self.ok.is_err() {
+            return None;
+        }
+        if self.cursor >= self.input.len() {
+            // close the indentation levels still open at the end of the input
+            if self.options.indentation && self.indents.len() > 1 {
+                self.indents.pop();
+                return Some(synthetic_lexem("dedent", self.input.len()));
+            }
+            return None;
+        }
+        if self.options.newlines && self.input[self.cursor..].starts_with('\n') {
+            let mut lexem = synthetic_lexem("newline", self.cursor);
+            lexem.raw = "\n".into();
+            lexem.leading = leading;
+            self.cursor += 1;
+            self.line_has_lexem = false;
+            lexem.trailing = self.skip_ignored();
+            return Some(lexem);
+        }
+        let mode = self.modes.last().and_then(|i| self.grammar.modes.get(*i));
+        let input = &self.input[self.cursor..];
+        let candidates = input.chars().next().and_then(|c| self.char_atoms.get(&c));
+        let matched = match candidates {
+            Some(candidates)
+                if is_active(&self.grammar.atoms[*candidates.last().unwrap()], mode) =>
+            {
+                let atoms = candidates.iter().map(|i| &self.grammar.atoms[*i]);
+                self.grammar.match_atoms(atoms, input, mode)
+            }
+            _ => self.grammar.match_input(input, mode),
+        };
+        match matched {
+            Some((mut lexem, i)) => {
+                lexem.start = self.cursor;
+                if let Err(msg) = self.grammar.check_lexem(&lexem) {
+                    self.ok = Err(ParseError::Lexem(self.cursor, msg));
+                    return None;
+                }
+                if let Err(err) = self.change_mode(mode, &lexem.t) {
+                    self.ok = Err(err);
+                    return None;
+                }
+                if self.options.indentation {
+                    if let Err(msg) = self.indent(lexem.start) {
+                        self.ok = Err(ParseError::Lexem(self.cursor, msg));
+                        return None;
+                    }
+                }
+                self.cursor += i;
+                self.line_has_lexem = true;
+                lexem.leading = leading;
+                lexem.trailing = self.skip_ignored();
+                self.pending.push_back(lexem);
+                self.pending.pop_front()
+            }
+            None => {
+                let snippet = self.input[self.cursor..]
+                    .chars()
+                    .take_while(|c| *c != '\n' && *c != '\r')
+                    .take(SNIPPET_CHARS)
+                    .collect::<String>();
+                self.ok = Err(ParseError::Lexem(
+                    self.cursor,
+                    format!("no atom matches the input near '{}'", snippet),
+                ));
+                None
+            }
+        }
+    }
+    /// Queue `indent` or `dedent` lexems if the lexem at `start` is the first on its line, and
+    /// indented more or less than the current indentation level
+    fn indent(&mut self, start: usize) -> Result<(), String> {
+        let line_start = self.input[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let prefix = &self.input[line_start..start];
+        if !prefix.chars().all(|c| c == ' ' || c == '\t') {
+            return Ok(());
+        }
+        let width = prefix.chars().count();
+        if width > *self.indents.last().unwrap() {
+            self.indents.push(width);
+            self.pending.push_back(synthetic_lexem("indent", start));
+        }
+        while width < *self.indents.last().unwrap() {
+            self.indents.pop();
+            self.pending.push_back(synthetic_lexem("dedent", start));
+        }
+        if width != *self.indents.last().unwrap() {
+            return Err("dedent doesn't match any outer indentation level".into());
+        }
+        Ok(())
+    }
+    fn change_mode(&mut self, mode: Option<&LexerMode>, atom: &str) -> ParseResult<()> {
+        let action = mode
+            .and_then(|m| m.atoms.iter().find(|a| a.name == atom))
+            .and_then(|a| a.action.as_ref());
+        match action {
+            Some(ModeAction::Push(name)) => {
+                let i = self
+                    .grammar
+                    .modes
+                    .iter()
+                    .position(|m| m.name == *name)
+                    .ok_or_else(|| {
+                        ParseError::Lexem(self.cursor, format!("no lexer mode named '{}'", name))
+                    })?;
+                self.modes.push(i);
+            }
+            Some(ModeAction::Pop) => {
+                if self.modes.len() == 1 {
+                    return Err(ParseError::Lexem(
+                        self.cursor,
+                        format!("'{}' can't leave the initial lexer mode", atom),
+                    ));
+                }
+                self.modes.pop();
+            }
+            None => {}
+        }
+        Ok(())
+    }
+    fn skip_ignored(&mut self) -> Option<Trivia> {
+        let start = self.cursor;
+        'skip: while let Some(c) = self.input[self.cursor..].chars().next() {
+            if c == '\n' && self.options.newlines && self.line_has_lexem {
+                break;
+            }
+            if c == ' ' && self.options.ignore_whitespace
+                || c == '\n' && (self.options.ignore_newline || self.options.newlines)
+                || self.options.ignore_chars.contains(&c)
+            {
+                self.cursor += c.len_utf8();
+                continue;
+            }
+            for skip in self.grammar.skip.iter() {
+                match skip.match_input(&self.input[self.cursor..]) {
+                    Ok(0) => {}
+                    Ok(i) => {
+                        self.cursor += i;
+                        continue 'skip;
+                    }
+                    Err(msg) => {
+                        self.ok = Err(ParseError::Lexem(self.cursor, msg));
+                        break 'skip;
+                    }
+                }
+            }
+            let mode = self.modes.last().and_then(|i| self.grammar.modes.get(*i));
+            for atom in self.grammar.atoms.iter() {
+                if !self.grammar.hidden.contains(atom.name()) || !is_active(atom, mode) {
+                    continue;
+                }
+                match atom.match_input(&self.input[self.cursor..]) {
+                    None | Some((_, 0)) => {}
+                    Some((_, len)) => {
+                        if let Err(msg) = atom.check(&self.input[self.cursor..self.cursor + len]) {
+                            self.ok = Err(ParseError::Lexem(self.cursor, msg));
+                            break 'skip;
+                        }
+                        self.cursor += len;
+                        continue 'skip;
+                    }
+                }
+            }
+            break;
+        }
+        if self.options.record_trivia && self.cursor > start {
+            Some(Trivia {
+                start,
+                raw: self.input[start..self.cursor].into(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for LexemIter<'_> {
+    type Item = Lexem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.shift();
+        debug!("next lexem: {:?}", n);
+        if let Some(lexem) = &n {
+            for (i, (open, close)) in self.grammar.recover.iter().enumerate() {
+                if lexem.t == *open {
+                    self.depths[i] += 1;
+                } else if lexem.t == *close {
+                    self.depths[i] -= 1;
+                }
+            }
+        }
+        n
+    }
+}
+
+/// Matches the text of an atom at the start of the remaining input. Implemented by
+/// `regex::Regex` and by cloneable closures returning the length of the match.
+pub trait AtomMatcher: Send + Sync {
+    /// Length in bytes of the text matched at the start of `input`
+    fn match_prefix(&self, input: &str) -> Option<usize>;
+    /// A boxed copy of this matcher, for cloning atoms
+    fn clone_matcher(&self) -> Box<dyn AtomMatcher>;
+    /// The regex matched, for matchers that can be written in a text grammar
+    fn pattern(&self) -> Option<&str> {
+        None
+    }
+    /// Text of the named groups in the match at the start of `input`, by group name
+    fn captures(&self, _input: &str) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl AtomMatcher for Regex {
+    fn match_prefix(&self, input: &str) -> Option<usize> {
+        self.find(input).filter(|m| m.start() == 0).map(|m| m.end())
+    }
+    fn clone_matcher(&self) -> Box<dyn AtomMatcher> {
+        Box::new(self.clone())
+    }
+    fn pattern(&self) -> Option<&str> {
+        Some(self.as_str())
+    }
+    fn captures(&self, input: &str) -> BTreeMap<String, String> {
+        let mut named = BTreeMap::new();
+        if self.capture_names().flatten().next().is_none() {
+            return named;
+        }
+        if let Some(captures) =
+            Regex::captures(self, input).filter(|c| c.get(0).unwrap().start() == 0)
+        {
+            for name in self.capture_names().flatten() {
+                if let Some(m) = captures.name(name) {
+                    named.insert(name.to_string(), m.as_str().to_string());
+                }
+            }
+        }
+        named
+    }
+}
+
+impl<F: Fn(&str) -> Option<usize> + Clone + Send + Sync + 'static> AtomMatcher for F {
+    fn match_prefix(&self, input: &str) -> Option<usize> {
+        self(input)
+    }
+    fn clone_matcher(&self) -> Box<dyn AtomMatcher> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn AtomMatcher> {
+    fn clone(&self) -> Self {
+        self.clone_matcher()
+    }
+}
+
+impl fmt::Debug for dyn AtomMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pattern() {
+            Some(pattern) => write!(f, "{:?}", pattern),
+            None => write!(f, "<custom matcher>"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Atom {
+    Simple {
+        name: String,
+    },
+    Matched {
+        name: String,
+        m: Box<dyn AtomMatcher>,
+    },
+    /// Integer without leading zeros in the range `min..=max`, the lexer fails on values out of
+    /// range
+    Integer {
+        name: String,
+        min: i128,
+        max: i128,
+    },
+    /// Text from `open` up to the `close` balancing it, with any nested pairs in between, like
+    /// a block of code captured as is. The lexer fails if the input ends before it is closed.
+    Balanced {
+        name: String,
+        open: String,
+        close: String,
+    },
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Atom) -> bool {
+        match (self, other) {
+            (Atom::Simple { name: a }, Atom::Simple { name: b }) => a == b,
+            // custom matchers can't be compared
+            (Atom::Matched { name: a, m: ma }, Atom::Matched { name: b, m: mb }) => {
+                a == b && ma.pattern().is_some() && ma.pattern() == mb.pattern()
+            }
+            (
+                Atom::Integer { name, min, max },
+                Atom::Integer {
+                    name: other_name,
+                    min: other_min,
+                    max: other_max,
+                },
+            ) => name == other_name && min == other_min && max == other_max,
+            (
+                Atom::Balanced { name, open, close },
+                Atom::Balanced {
+                    name: other_name,
+                    open: other_open,
+                    close: other_close,
+                },
+            ) => name == other_name && open == other_open && close == other_close,
+            _ => false,
+        }
+    }
+}
+
+impl Atom {
+    pub fn name(&self) -> &String {
+        match self {
+            Atom::Simple { name } => name,
+            Atom::Matched { name, .. } => name,
+            Atom::Integer { name, .. } => name,
+            Atom::Balanced { name, .. } => name,
+        }
+    }
+    fn match_input(&self, input: &str) -> Option<(String, usize)> {
+        match self {
+            Atom::Simple { name } => {
+                if input.starts_with(name) {
+                    return Some((name.clone(), name.len()));
+                }
+            }
+            Atom::Matched { name, m } => {
+                // an empty match would never move the lexer forward
+                return m
+                    .match_prefix(input)
+                    .filter(|len| *len > 0)
+                    .map(|len| (name.clone(), len));
+            }
+            Atom::Integer { name, min, .. } => {
+                let sign = if *min < 0 && input.starts_with('-') {
+                    1
+                } else {
+                    0
+                };
+                let digits = input[sign..]
+                    .bytes()
+                    .take_while(|b| b.is_ascii_digit())
+                    .count();
+                if digits > 0 {
+                    return Some((name.clone(), sign + digits));
+                }
+            }
+            Atom::Balanced { name, open, close } => {
+                // unterminated text is matched to the end, and rejected by `check`
+                if !open.is_empty() && !close.is_empty() && input.starts_with(open.as_str()) {
+                    let len = balanced_len(input, open, close).unwrap_or(input.len());
+                    return Some((name.clone(), len));
+                }
+            }
+        }
+        None
+    }
+    /// The text matched by the atom, if it only matches that literal text
+    fn literal(&self) -> Option<String> {
+        match self {
+            Atom::Simple { name } => Some(name.clone()),
+            #[cfg(feature = "std")]
+            Atom::Matched { m, .. } => m.pattern().and_then(crate::grammar::unescape_literal),
+            _ => None,
+        }
+    }
+    fn captures(&self, input: &str) -> BTreeMap<String, String> {
+        match self {
+            Atom::Matched { m, .. } => m.captures(input),
+            _ => BTreeMap::new(),
+        }
+    }
+    /// Check the matched text `raw`, returning an error message if it isn't a valid value
+    fn check(&self, raw: &str) -> Result<(), String> {
+        match self {
+            Atom::Integer { name, min, max } => {
+                let digits = raw.trim_start_matches('-');
+                if digits.len() > 1 && digits.starts_with('0') {
+                    return Err(format!("leading zeros in integer '{}'", raw));
+                }
+                match raw.parse::<i128>() {
+                    Ok(v) if *min <= v && v <= *max => Ok(()),
+                    _ => Err(format!(
+                        "integer '{}' overflows atom '{}', expected a value in {}..={}",
+                        raw, name, min, max
+                    )),
+                }
+            }
+            Atom::Balanced { open, close, .. }
+                if balanced_len(raw, open, close) != Some(raw.len()) =>
+            {
+                Err(format!("unterminated '{}', expected '{}'", open, close))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Length of the text from the `open` at the start of `input` to the `close` balancing it, if
+/// it is closed
+fn balanced_len(input: &str, open: &str, close: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < input.len() {
+        // closing first, so delimiters that are the same don't nest
+        if depth > 0 && input[i..].starts_with(close) {
+            depth -= 1;
+            i += close.len();
+            if depth == 0 {
+                return Some(i);
+            }
+        } else if input[i..].starts_with(open) {
+            depth += 1;
+            i += open.len();
+        } else {
+            i += input[i..].chars().next().unwrap().len_utf8();
+        }
+    }
+    None
+}
+
+// the tests use regex atoms and text grammars, which need std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    #[test]
+    fn simple_lexem_iter() {
         let g = Grammar {
             options: ParseOptions {
                 ignore_whitespace: true,
                 ignore_newline: false,
                 bubble_intermediate: false,
+                ..ParseOptions::default()
             },
             rules: vec![],
             atoms: vec![
                 Atom::Simple { name: "(".into() },
                 Atom::Simple { name: ")".into() },
             ],
+            ..Grammar::default()
         };
-        let input = "(() ())".into();
-        let mut lexem_iter = Lexem::iter(&g, &input);
+        let input = "(() ())";
+        let mut lexem_iter = Lexem::iter(&g, input);
         assert_eq!(lexem_iter.next().unwrap().t, "(");
         assert_eq!(lexem_iter.next().unwrap().t, "(");
         assert_eq!(lexem_iter.next().unwrap().t, ")");
@@ -418,45 +2874,363 @@ mod tests {
         assert!(lexem_iter.next().is_none());
     }
     #[test]
-    fn combined_lexem_iter() {
+    fn combined_lexem_iter() {
+        let g = Grammar {
+            options: ParseOptions {
+                ignore_whitespace: true,
+                ignore_newline: true,
+                bubble_intermediate: false,
+                ..ParseOptions::default()
+            },
+            rules: vec![],
+            atoms: vec![
+                Atom::Simple { name: "(".into() },
+                Atom::Simple { name: ")".into() },
+                Atom::Matched {
+                    name: "NUMBER".into(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            ..Grammar::default()
+        };
+        let input = "(\n1234 )";
+        let mut lexem_iter = Lexem::iter(&g, input);
+        assert_eq!(lexem_iter.next().unwrap().t, "(");
+
+        let n = lexem_iter.next().unwrap();
+        assert_eq!(n.t, "NUMBER");
+        assert_eq!(n.raw, "1234");
+
+        assert_eq!(lexem_iter.next().unwrap().t, ")");
+        assert!(lexem_iter.next().is_none());
+    }
+    #[test]
+    fn record_trivia() {
+        let g = Grammar {
+            options: ParseOptions {
+                ignore_whitespace: true,
+                record_trivia: true,
+                ..ParseOptions::default()
+            },
+            rules: vec![],
+            atoms: vec![
+                Atom::Simple { name: "(".into() },
+                Atom::Simple { name: ")".into() },
+                Atom::Matched {
+                    name: "NUMBER".into(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            ..Grammar::default()
+        };
+        let lexems = g.tokenize("(  1  )").unwrap();
+        assert_eq!(lexems.len(), 3);
+        assert_eq!(lexems[0].leading, None);
+        assert_eq!(
+            lexems[0].trailing,
+            Some(Trivia {
+                start: 1,
+                raw: "  ".into()
+            })
+        );
+        assert_eq!(
+            lexems[1].trailing,
+            Some(Trivia {
+                start: 4,
+                raw: "  ".into()
+            })
+        );
+        assert_eq!(lexems[2].trailing, None);
+
+        let g = g.with_options(ParseOptions {
+            ignore_whitespace: true,
+            ..ParseOptions::default()
+        });
+        let lexems = g.tokenize("(  1  )").unwrap();
+        assert!(lexems.iter().all(|l| l.trailing.is_none()));
+    }
+    #[test]
+    fn lexer_modes() {
+        let g = Grammar {
+            options: ParseOptions::default(),
+            rules: vec![Rule {
+                name: "START".into(),
+                order: 0,
+                label: None,
+                production: SymbolType::Repeated(Box::new(SymbolType::Switch(
+                    Box::new(SymbolType::Symbol(Symbol::Lexem {
+                        t: "text".into(),
+                        include_raw: true,
+                    })),
+                    Box::new(SymbolType::Group(vec![
+                        SymbolType::Symbol(Symbol::Lexem {
+                            t: "open".into(),
+                            include_raw: false,
+                        }),
+                        SymbolType::Symbol(Symbol::Lexem {
+                            t: "ident".into(),
+                            include_raw: true,
+                        }),
+                        SymbolType::Symbol(Symbol::Lexem {
+                            t: "close".into(),
+                            include_raw: false,
+                        }),
+                    ])),
+                ))),
+            }],
+            atoms: vec![
+                Atom::Matched {
+                    name: "open".into(),
+                    m: Box::new(Regex::new(r"\{\{").unwrap()),
+                },
+                Atom::Matched {
+                    name: "close".into(),
+                    m: Box::new(Regex::new(r"\}\}").unwrap()),
+                },
+                Atom::Matched {
+                    name: "ident".into(),
+                    m: Box::new(Regex::new(r"\w+").unwrap()),
+                },
+                Atom::Matched {
+                    name: "text".into(),
+                    m: Box::new(Regex::new(r"[^{]+").unwrap()),
+                },
+            ],
+            modes: vec![
+                LexerMode {
+                    name: "text".into(),
+                    atoms: vec![
+                        ModeAtom {
+                            name: "open".into(),
+                            action: Some(ModeAction::Push("code".into())),
+                        },
+                        ModeAtom {
+                            name: "text".into(),
+                            action: None,
+                        },
+                    ],
+                },
+                LexerMode {
+                    name: "code".into(),
+                    atoms: vec![
+                        ModeAtom {
+                            name: "close".into(),
+                            action: Some(ModeAction::Pop),
+                        },
+                        ModeAtom {
+                            name: "ident".into(),
+                            action: None,
+                        },
+                    ],
+                },
+            ],
+            ..Grammar::default()
+        };
+        let tokens = g.tokenize("hi {{name}} bye").unwrap();
+        assert_eq!(
+            tokens.iter().map(|l| l.t.as_str()).collect::<Vec<_>>(),
+            vec!["text", "open", "ident", "close", "text"]
+        );
+        assert_eq!(tokens[2].raw, "name");
+        assert_eq!(tokens[4].raw, " bye");
+        assert!(g.parse("hi {{name}} bye").is_ok());
+        assert!(g.parse("{{a}}{{b}}").is_ok());
+        // "{{" is only active in the text mode
+        assert!(g.parse("{{{{a}}}}").is_err());
+        assert!(g.parse("{{a b}}").is_err());
+
+        // hidden atoms are only skipped in the modes they are active in
+        let mut g = g;
+        g.atoms.push(Atom::Matched {
+            name: "gap".into(),
+            m: Box::new(Regex::new(r" +").unwrap()),
+        });
+        g.hidden.push("gap".into());
+        g.modes[1].atoms.push(ModeAtom {
+            name: "gap".into(),
+            action: None,
+        });
+        let tokens = g.tokenize("hi {{ name }} bye").unwrap();
+        assert_eq!(
+            tokens.iter().map(|l| l.raw.as_str()).collect::<Vec<_>>(),
+            vec!["hi ", "{{", "name", "}}", " bye"]
+        );
+    }
+    #[test]
+    fn skip_comments() {
         let g = Grammar {
             options: ParseOptions {
                 ignore_whitespace: true,
-                ignore_newline: true,
-                bubble_intermediate: false,
+                ..ParseOptions::default()
             },
-            rules: vec![],
+            rules: vec![Rule {
+                name: "START".into(),
+                order: 0,
+                label: None,
+                production: SymbolType::seq(vec![
+                    SymbolType::lexem("("),
+                    SymbolType::leaf("NUMBER"),
+                    SymbolType::lexem(")"),
+                ]),
+            }],
             atoms: vec![
                 Atom::Simple { name: "(".into() },
                 Atom::Simple { name: ")".into() },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            skip: vec![
+                Skip::Delimited {
+                    start: "/*".into(),
+                    end: "*/".into(),
                 },
+                Skip::Matched(Regex::new(r"//[^\n]*").unwrap()),
             ],
+            ..Grammar::default()
         };
-        let input = "(\n1234 )".into();
-        let mut lexem_iter = Lexem::iter(&g, &input);
-        assert_eq!(lexem_iter.next().unwrap().t, "(");
+        let tokens = g.tokenize("( /* note */ 1/**/) // end").unwrap();
+        assert_eq!(
+            tokens.iter().map(|l| l.t.as_str()).collect::<Vec<_>>(),
+            vec!["(", "NUMBER", ")"]
+        );
+        assert!(g.parse("(/* a */ /* b */1)").is_ok());
+        match g.parse("(1 /* note )") {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 3);
+                assert_eq!(msg, "unterminated '/*', expected '*/'");
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn empty_matching_atom() {
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                production: SymbolType::rep(SymbolType::leaf("x")),
+                order: 0,
+                label: None,
+            }],
+            atoms: vec![Atom::Matched {
+                name: "x".into(),
+                m: Box::new(Regex::new(r"\d*").unwrap()),
+            }],
+            ..Grammar::default()
+        };
+        assert_eq!(
+            g.validate(),
+            vec!["atom 'x' can match an empty string, empty matches are ignored"]
+        );
+        let tokens = g.tokenize("12").unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].raw, "12");
+        match g.parse("12a") {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 2);
+                assert_eq!(msg, "no atom matches the input near 'a'");
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn trailing_separators() {
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                production: SymbolType::seq(vec![
+                    SymbolType::lexem("["),
+                    SymbolType::opt(SymbolType::separated(SymbolType::leaf("num"), ",")),
+                    SymbolType::lexem("]"),
+                ]),
+                order: 0,
+                label: None,
+            }],
+            atoms: vec![
+                Atom::Simple { name: "[".into() },
+                Atom::Simple { name: "]".into() },
+                Atom::Simple { name: ",".into() },
+                Atom::Integer {
+                    name: "num".into(),
+                    min: 0,
+                    max: 9,
+                },
+            ],
+            ..Grammar::default()
+        };
+        assert!(g.parse("[1,2,3]").is_ok());
+        assert!(g.parse("[1,2,3,]").is_err());
 
-        let n = lexem_iter.next().unwrap();
-        assert_eq!(n.t, "NUMBER");
-        assert_eq!(n.raw, "1234");
+        let g = Grammar {
+            trailing_separators: vec!["START".into()],
+            ..g
+        };
+        let ast = g.parse("[1,2,3,]").unwrap();
+        assert_eq!(
+            ast.leaves().map(|(_, raw)| raw).collect::<Vec<_>>(),
+            vec!["1", "2", "3"]
+        );
+        assert!(g.parse("[1,2,3]").is_ok());
+        assert!(g.parse("[]").is_ok());
+        assert!(g.parse("[1,,]").is_err());
+        assert!(g.parse("[1,2,,3]").is_err());
+        assert!(g.parse("[,]").is_err());
 
-        assert_eq!(lexem_iter.next().unwrap().t, ")");
-        assert!(lexem_iter.next().is_none());
+        // only repetitions in the rules opting in accept a trailing separator
+        let g = Grammar::compile(
+            r#"
+            @trailing_separators LIST
+            START -> ( lbr ( LIST )? rbr SUM )
+            LIST -> ( num ( comma num )* )
+            SUM -> ( num ( pluss num )* )
+            >lbr -> '['
+            >rbr -> ']'
+            >comma -> ','
+            >pluss -> '+'
+            >num -> r'\d+'
+            "#,
+        )
+        .unwrap();
+        assert!(g.parse("[1,2,]3+4").is_ok());
+        assert!(g.parse("[1,2,]3+").is_err());
+        assert!(g.to_string().starts_with("@trailing_separators LIST\n"));
     }
     #[test]
-    fn parse_simple() {
-        let g = Grammar {
+    fn stray_character_snippet() {
+        let g = mini_json_grammar().with_options(ParseOptions {
+            ignore_newline: true,
+            ..ParseOptions::default()
+        });
+        let input = "[1,\n2,@3,4,5,6,7,8]".to_string();
+        match g.tokenize(&input) {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(line_column(&input, pos), (2, 3));
+                assert_eq!(msg, "no atom matches the input near '@3,4,5,6,7'");
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+        match g.parse("[1,@]\n") {
+            Err(ParseError::Lexem(3, msg)) => {
+                assert_eq!(msg, "no atom matches the input near '@]'")
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+    }
+    fn par_grammar() -> Grammar {
+        Grammar {
             options: ParseOptions::default(),
             rules: vec![
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("PAR".into())),
                 },
                 Rule {
                     name: "PAR".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "(".into(),
@@ -478,15 +3252,255 @@ mod tests {
                 Atom::Simple { name: ")".into() },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            ..Grammar::default()
+        }
+    }
+    #[test]
+    fn parse_simple() {
+        let g = par_grammar();
+        assert!(g.parse("(1424)").is_ok());
+        assert!(g.parse("(()").is_err());
+        assert!(g.parse("1424)").is_err());
+        match g.parse("()") {
+            Err(ParseError::Token { start, len, msg }) => {
+                assert_eq!((start, len), (1, 1));
+                assert_eq!(msg, "expected token 'NUMBER' found ')'");
+            }
+            r => panic!("expected token error, got {:?}", r),
+        }
+        match g.parse("(1424") {
+            Err(ParseError::Input(pos, msg)) => {
+                assert_eq!(pos, 5);
+                assert_eq!(msg, "expected token ')' found 'EOF'");
+            }
+            r => panic!("expected input error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn first_conflicts() {
+        assert!(par_grammar().first_conflicts().is_empty());
+        let g = Grammar {
+            rules: vec![
+                Rule {
+                    name: "START".into(),
+                    order: 0,
+                    label: None,
+                    production: SymbolType::seq(vec![SymbolType::leaf("("), SymbolType::leaf("(")]),
+                },
+                Rule {
+                    name: "START".into(),
+                    order: 0,
+                    label: None,
+                    production: SymbolType::leaf(")"),
+                },
+                Rule {
+                    name: "START".into(),
+                    order: 0,
+                    label: None,
+                    production: SymbolType::seq(vec![
+                        SymbolType::opt(SymbolType::leaf(")")),
+                        SymbolType::leaf("("),
+                    ]),
+                },
+            ],
+            atoms: vec![
+                Atom::Simple { name: "(".into() },
+                Atom::Simple { name: ")".into() },
+            ],
+            ..Grammar::default()
+        };
+        assert_eq!(
+            g.first_conflicts(),
+            vec![
+                FirstConflict {
+                    rule: "START".into(),
+                    token: "(".into(),
+                    production_indices: (0, 2),
+                },
+                FirstConflict {
+                    rule: "START".into(),
+                    token: ")".into(),
+                    production_indices: (1, 2),
+                },
+            ]
+        );
+    }
+    #[test]
+    fn parse_best_effort() {
+        let g = par_grammar();
+        match g.parse_best_effort("(1424") {
+            (Some(ast), Some(ParseError::Input(pos, msg))) => {
+                assert_eq!(
+                    serde_json::to_string(&ast).unwrap(),
+                    r#"{"type":"START","children":[{"type":"PAR","children":[]}]}"#
+                );
+                assert_eq!((pos, msg.as_str()), (5, "expected token ')' found 'EOF'"));
+            }
+            r => panic!("expected partial tree and input error, got {:?}", r),
+        }
+        assert!(matches!(g.parse_best_effort("(1)"), (Some(_), None)));
+        assert!(matches!(g.parse_best_effort(")"), (None, Some(_))));
+
+        let (ast, err) = mini_json_grammar_with_leaves().parse_best_effort("[1,2,}");
+        assert!(err.is_some());
+        let raws = ast
+            .unwrap()
+            .leaves()
+            .map(|(_, raw)| raw.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(raws, vec!["1", "2"]);
+    }
+    #[test]
+    fn parse_pre_tokenized() {
+        let g = par_grammar();
+        let tokens = vec![
+            Token::new("(", "("),
+            Token::new("NUMBER", "1424"),
+            Token::new(")", ")"),
+        ];
+        let ast = g.parse_tokens(&tokens).unwrap();
+        assert_eq!(
+            serde_json::to_string(&ast).unwrap(),
+            serde_json::to_string(&g.parse("(1424)").unwrap()).unwrap()
+        );
+        match g.parse_tokens(&tokens[..2]) {
+            Err(ParseError::Input(pos, msg)) => {
+                assert_eq!(pos, 5);
+                assert_eq!(msg, "expected token ')' found 'EOF'");
+            }
+            r => panic!("expected input error, got {:?}", r),
+        }
+        // the atoms are not used, so tokens don't have to match them
+        assert!(g
+            .parse_tokens(&[
+                Token::new("(", "<"),
+                Token::new("NUMBER", "one"),
+                Token::new(")", ">"),
+            ])
+            .is_ok());
+    }
+    #[test]
+    fn lexer_cache() {
+        let g = mini_json_grammar();
+        let mut cache = LexerCache::new();
+        assert!(g.parse_cached("[1,22,3]", &mut cache).is_ok());
+        assert_eq!(cache.reused(), 0);
+
+        // `[`, `1`, `,` and `22` are followed by lexems ending before the change
+        let lexems = g.tokenize_cached("[1,22,4]", &mut cache).unwrap();
+        assert_eq!(cache.reused(), 4);
+        assert_eq!(
+            lexems.iter().map(|l| l.raw.as_str()).collect::<Vec<_>>(),
+            vec!["[", "1", ",", "22", ",", "4", "]"]
+        );
+        assert_eq!(lexems[5].start, 6);
+
+        // changing `22` to `2` invalidates it and everything after
+        let ast = g.parse_cached("[1,2,4]", &mut cache).unwrap();
+        assert_eq!(cache.reused(), 2);
+        assert_eq!(ast, g.parse("[1,2,4]").unwrap());
+        // a lexem ending at the change might continue into it
+        assert_eq!(
+            g.tokenize_cached("[1,23,4]", &mut cache).unwrap()[3].raw,
+            "23"
+        );
+
+        assert!(g.parse_cached("[1,2,?]", &mut cache).is_err());
+        assert!(g.parse_cached("[1,2]", &mut cache).is_ok());
+        assert_eq!(cache.reused(), 0);
+    }
+    #[test]
+    fn sourcemap() {
+        let g = crate::grammar! {
+            START -> ( SUM );
+            SUM -> ( NUMBER pluss NUMBER );
+            NUMBER -> ( num );
+            >pluss -> r"\+";
+            >num -> r"\d+";
+        };
+        let (ast, map) = g.parse_with_sourcemap("1+22").unwrap();
+        assert_eq!(
+            map,
+            vec![
+                (vec![0, 0, 0], 0, 1),
+                (vec![0, 1], 1, 2),
+                (vec![0, 2, 0], 2, 4)
+            ]
+        );
+        assert_eq!(ast, g.parse("1+22").unwrap());
+
+        let g = Grammar {
+            options: ParseOptions {
+                bubble_intermediate: true,
+                ..g.options.clone()
+            },
+            ..g
+        };
+        let (ast, map) = g.parse_with_sourcemap("1+2").unwrap();
+        assert_eq!(map, vec![(vec![0], 0, 1), (vec![1], 1, 2), (vec![2], 2, 3)]);
+        for ((path, start, end), (_, raw)) in map.iter().zip(ast.leaves()) {
+            assert_eq!(path.len(), 1);
+            assert_eq!(&"1+2"[*start..*end], raw);
+        }
+    }
+    #[test]
+    fn max_input_bytes() {
+        let g = mini_json_grammar().with_options(ParseOptions {
+            max_input_bytes: 8,
+            ..ParseOptions::default()
+        });
+        assert!(g.parse("[1,2,3]").is_ok());
+        match g.parse("[1,2,3,4]") {
+            Err(ParseError::InputTooLarge { size, max }) => assert_eq!((size, max), (9, 8)),
+            r => panic!("expected input too large error, got {:?}", r),
+        }
+        assert!(matches!(
+            g.tokenize("[1,2,3,4]"),
+            Err(ParseError::InputTooLarge { .. })
+        ));
+    }
+    #[test]
+    fn custom_atom_matcher() {
+        let hex = |input: &str| {
+            let digits = input
+                .strip_prefix("0x")?
+                .bytes()
+                .take_while(|b| b.is_ascii_hexdigit())
+                .count();
+            Some(2 + digits).filter(|_| digits > 0)
+        };
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                order: 0,
+                label: None,
+                production: SymbolType::Group(vec![
+                    SymbolType::leaf("hex"),
+                    SymbolType::rep(SymbolType::Group(vec![
+                        SymbolType::leaf(","),
+                        SymbolType::leaf("hex"),
+                    ])),
+                ]),
+            }],
+            atoms: vec![
+                Atom::Simple { name: ",".into() },
+                Atom::Matched {
+                    name: "hex".into(),
+                    m: Box::new(hex),
                 },
             ],
+            ..Grammar::default()
         };
-        assert!(g.parse(&"(1424)".into()).is_ok());
-        assert!(g.parse(&"(()".into()).is_err());
-        assert!(g.parse(&"()".into()).is_err());
-        assert!(g.parse(&"1424)".into()).is_err());
-        assert!(g.parse(&"(1424".into()).is_err());
+        let ast = g.parse("0x1f,0xA0").unwrap();
+        assert_eq!(
+            ast.leaves().collect::<Vec<_>>(),
+            vec![("hex", "0x1f"), (",", ","), ("hex", "0xA0")]
+        );
+        assert!(g.parse("0x,0x1").is_err());
+        assert!(g.validate().is_empty());
     }
     #[test]
     fn parse_optional() {
@@ -495,10 +3509,14 @@ mod tests {
             rules: vec![
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("FLOAT".into())),
                 },
                 Rule {
                     name: "FLOAT".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "NUMBER".into(),
@@ -521,13 +3539,14 @@ mod tests {
                 Atom::Simple { name: ".".into() },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
                 },
             ],
+            ..Grammar::default()
         };
-        assert!(g.parse(&"12.34".into()).is_ok());
-        assert!(g.parse(&"12".into()).is_ok());
-        assert!(g.parse(&"12.".into()).is_err());
+        assert!(g.parse("12.34").is_ok());
+        assert!(g.parse("12").is_ok());
+        assert!(g.parse("12.").is_err());
     }
     #[test]
     fn parse_multiple() {
@@ -536,10 +3555,14 @@ mod tests {
             rules: vec![
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("PARS".into())),
                 },
                 Rule {
                     name: "PARS".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Repeated(Box::new(SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "(".into(),
@@ -556,12 +3579,13 @@ mod tests {
                 Atom::Simple { name: "(".into() },
                 Atom::Simple { name: ")".into() },
             ],
+            ..Grammar::default()
         };
-        assert!(g.parse(&"()".into()).is_ok());
-        assert!(g.parse(&"()()".into()).is_ok());
-        assert!(g.parse(&"()()()".into()).is_ok());
-        assert!(g.parse(&"()(".into()).is_err());
-        assert!(g.parse(&"()()(".into()).is_err());
+        assert!(g.parse("()").is_ok());
+        assert!(g.parse("()()").is_ok());
+        assert!(g.parse("()()()").is_ok());
+        assert!(g.parse("()(").is_err());
+        assert!(g.parse("()()(").is_err());
     }
     #[test]
     fn parse_multiple_matching_rules() {
@@ -570,14 +3594,20 @@ mod tests {
             rules: vec![
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("LIST".into())),
                 },
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("OBJ".into())),
                 },
                 Rule {
                     name: "LIST".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "[".into(),
@@ -591,6 +3621,8 @@ mod tests {
                 },
                 Rule {
                     name: "OBJ".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "{".into(),
@@ -610,13 +3642,14 @@ mod tests {
                 Atom::Simple { name: "}".into() },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
                 },
             ],
+            ..Grammar::default()
         };
-        assert!(g.parse(&"[]".into()).is_ok());
-        assert!(g.parse(&"{}".into()).is_ok());
-        assert!(g.parse(&"[}".into()).is_err());
+        assert!(g.parse("[]").is_ok());
+        assert!(g.parse("{}").is_ok());
+        assert!(g.parse("[}").is_err());
     }
     #[test]
     fn parse_switch() {
@@ -625,10 +3658,14 @@ mod tests {
             rules: vec![
                 Rule {
                     name: "START".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Symbol(Symbol::AST("COMP".into())),
                 },
                 Rule {
                     name: "COMP".into(),
+                    order: 0,
+                    label: None,
                     production: SymbolType::Group(vec![
                         SymbolType::Symbol(Symbol::Lexem {
                             t: "NUMBER".into(),
@@ -656,104 +3693,80 @@ mod tests {
                 Atom::Simple { name: ">".into() },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
                 },
             ],
+            ..Grammar::default()
         };
-        assert!(g.parse(&"12<9".into()).is_ok());
-        assert!(g.parse(&"12>9".into()).is_ok());
-        assert!(g.parse(&"12".into()).is_err());
+        assert!(g.parse("12<9").is_ok());
+        assert!(g.parse("12>9").is_ok());
+        assert!(g.parse("12").is_err());
     }
-    #[test]
-    fn parse_mini_json() {
-        let g = Grammar {
+    fn mini_json_grammar() -> Grammar {
+        type ST = SymbolType;
+        Grammar {
             options: ParseOptions::default(),
             rules: vec![
                 Rule {
                     name: "START".into(),
-                    production: SymbolType::Symbol(Symbol::AST("ITEM".into())),
+                    order: 0,
+                    label: None,
+                    production: ST::rule("ITEM"),
                 },
                 Rule {
                     name: "ITEM".into(),
-                    production: SymbolType::Symbol(Symbol::AST("OBJ".into())),
+                    order: 0,
+                    label: None,
+                    production: ST::rule("OBJ"),
                 },
                 Rule {
                     name: "ITEM".into(),
-                    production: SymbolType::Symbol(Symbol::AST("LIST".into())),
+                    order: 0,
+                    label: None,
+                    production: ST::rule("LIST"),
                 },
                 Rule {
                     name: "ITEM".into(),
-                    production: SymbolType::Symbol(Symbol::Lexem {
-                        t: "NUMBER".into(),
-                        include_raw: false,
-                    }),
+                    order: 0,
+                    label: None,
+                    production: ST::lexem("NUMBER"),
                 },
                 Rule {
                     name: "OBJ".into(),
-                    production: SymbolType::Group(vec![
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "{".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Optional(Box::new(SymbolType::Group(vec![
-                            SymbolType::Symbol(Symbol::AST("KV".into())),
-                            SymbolType::Repeated(Box::new(SymbolType::Group(vec![
-                                SymbolType::Symbol(Symbol::Lexem {
-                                    t: ",".into(),
-                                    include_raw: false,
-                                }),
-                                SymbolType::Symbol(Symbol::AST("KV".into())),
-                            ]))),
-                        ]))),
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "}".into(),
-                            include_raw: false,
-                        }),
+                    order: 0,
+                    label: None,
+                    production: ST::seq(vec![
+                        ST::lexem("{"),
+                        ST::opt(ST::seq(vec![
+                            ST::rule("KV"),
+                            ST::rep(ST::seq(vec![ST::lexem(","), ST::rule("KV")])),
+                        ])),
+                        ST::lexem("}"),
                     ]),
                 },
                 Rule {
                     name: "KV".into(),
-                    production: SymbolType::Group(vec![
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "\"".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "STRING".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "\"".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: ":".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Symbol(Symbol::AST("ITEM".into())),
+                    order: 0,
+                    label: None,
+                    production: ST::seq(vec![
+                        ST::lexem("\""),
+                        ST::lexem("STRING"),
+                        ST::lexem("\""),
+                        ST::lexem(":"),
+                        ST::rule("ITEM"),
                     ]),
                 },
                 Rule {
                     name: "LIST".into(),
-                    production: SymbolType::Group(vec![
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "[".into(),
-                            include_raw: false,
-                        }),
-                        SymbolType::Optional(Box::new(SymbolType::Group(vec![
-                            SymbolType::Symbol(Symbol::AST("ITEM".into())),
-                            SymbolType::Repeated(Box::new(SymbolType::Group(vec![
-                                SymbolType::Symbol(Symbol::Lexem {
-                                    t: ",".into(),
-                                    include_raw: false,
-                                }),
-                                SymbolType::Symbol(Symbol::AST("ITEM".into())),
-                            ]))),
-                        ]))),
-                        SymbolType::Symbol(Symbol::Lexem {
-                            t: "]".into(),
-                            include_raw: false,
-                        }),
+                    order: 0,
+                    label: None,
+                    production: ST::seq(vec![
+                        ST::lexem("["),
+                        ST::opt(ST::seq(vec![
+                            ST::rule("ITEM"),
+                            ST::rep(ST::seq(vec![ST::lexem(","), ST::rule("ITEM")])),
+                        ])),
+                        ST::lexem("]"),
                     ]),
                 },
             ],
@@ -767,21 +3780,508 @@ mod tests {
                 Atom::Simple { name: "\"".into() },
                 Atom::Matched {
                     name: "STRING".into(),
-                    m: Regex::new(r"\p{Alphabetic}+").unwrap(),
+                    m: Box::new(Regex::new(r"\p{Alphabetic}+").unwrap()),
                 },
                 Atom::Matched {
                     name: "NUMBER".into(),
-                    m: Regex::new(r"\d+").unwrap(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            ..Grammar::default()
+        }
+    }
+    /// `mini_json_grammar` with the numbers kept as leaves in the syntax tree
+    fn mini_json_grammar_with_leaves() -> Grammar {
+        let mut g = mini_json_grammar();
+        for rule in g.rules.iter_mut() {
+            if rule.production == SymbolType::lexem("NUMBER") {
+                rule.production = SymbolType::leaf("NUMBER");
+            }
+        }
+        g
+    }
+    #[test]
+    fn clone_grammar() {
+        let original = mini_json_grammar();
+        let mut copy = original.clone();
+        assert!(copy.structurally_equal(&original));
+        copy.rules.retain(|r| r.name != "LIST");
+        // before `STRING`, which would match the same text
+        copy.atoms.insert(
+            0,
+            Atom::Matched {
+                name: "BOOL".into(),
+                m: Box::new(|s: &str| {
+                    ["true", "false"]
+                        .iter()
+                        .find(|b| s.starts_with(*b))
+                        .map(|b| b.len())
+                }),
+            },
+        );
+        copy.rules.push(Rule {
+            name: "ITEM".into(),
+            order: 0,
+            label: None,
+            production: SymbolType::leaf("BOOL"),
+        });
+
+        assert!(original.structurally_equal(&mini_json_grammar()));
+        assert!(original.parse(r#"{"a":[1,2]}"#).is_ok());
+        assert!(original.parse(r#"{"a":true}"#).is_err());
+        assert!(copy.parse(r#"{"a":[1,2]}"#).is_err());
+        assert!(copy.clone().parse(r#"{"a":true}"#).is_ok());
+    }
+    #[test]
+    fn parse_mini_json() {
+        let g = mini_json_grammar();
+        assert!(g.parse("{}").is_ok());
+        assert!(g.parse("[]").is_ok());
+        assert!(g.parse(r#"{"field":12}"#).is_ok());
+        assert!(g.parse(r#"{"fieldA":[1,2,3],"fieldB":{}}"#).is_ok());
+        assert!(g.parse("[{},12,[[]]]").is_ok());
+        assert!(g.parse("[").is_err());
+        assert!(g.parse("[{{}}]").is_err());
+        assert!(g.parse(r#"{"field"}"#).is_err());
+    }
+    #[test]
+    fn iterate_leaves() {
+        let g = mini_json_grammar_with_leaves();
+        let ast = g.parse("[1,2,3]").unwrap();
+        let raws = ast.leaves().map(|(_, raw)| raw).collect::<Vec<_>>();
+        assert_eq!(raws, vec!["1", "2", "3"]);
+        assert_eq!(ast.descendants().next().unwrap().get_t(), "START");
+        assert_eq!(
+            ast.descendants().count(),
+            ast.descendants()
+                .filter(|a| matches!(a, AST::Node { .. }))
+                .count()
+                + raws.len()
+        );
+    }
+    #[test]
+    fn project_tree() {
+        let g = mini_json_grammar_with_leaves();
+        let ast = g.parse(r#"[1,[2,{"a":3}],[]]"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&ast.project(&["NUMBER", "LIST"])).unwrap(),
+            concat!(
+                r#"{"type":"START","children":[{"type":"LIST","children":["#,
+                r#"{"type":"NUMBER","raw":"1"},{"type":"LIST","children":["#,
+                r#"{"type":"NUMBER","raw":"2"},{"type":"NUMBER","raw":"3"}]},"#,
+                r#"{"type":"LIST","children":[]}]}]}"#
+            )
+        );
+        assert_eq!(
+            serde_json::to_string(&ast.project(&[])).unwrap(),
+            r#"{"type":"START","children":[]}"#
+        );
+    }
+    #[test]
+    fn depth_and_hash() {
+        let g = mini_json_grammar_with_leaves();
+        let ast = g.parse("[[1],2]").unwrap();
+        // START, ITEM, LIST, ITEM, LIST, ITEM and the NUMBER leaf
+        assert_eq!(ast.depth(), 7);
+        assert_eq!(AST::leaf("NUMBER", "1").depth(), 1);
+        assert_eq!(AST::node("LIST", vec![]).depth(), 1);
+
+        let same = g.parse("[[1],2]").unwrap();
+        assert_eq!(ast, same);
+        assert_eq!(ast.structural_hash(), same.structural_hash());
+        for other in ["[[1],3]", "[[1,2]]", "[[],1,2]"].iter() {
+            let other = g.parse(other).unwrap();
+            assert_ne!(ast.structural_hash(), other.structural_hash());
+        }
+
+        // deep, but still shallow enough for the recursive drop at the end of the test
+        let deep = (0..10_000).fold(AST::leaf("NUMBER", "1"), |ast, _| {
+            AST::node("LIST", vec![ast])
+        });
+        assert_eq!(deep.depth(), 10_001);
+        assert_eq!(deep.structural_hash(), deep.structural_hash());
+    }
+    #[test]
+    fn parse_fragment() {
+        let g = mini_json_grammar_with_leaves();
+        assert_eq!(
+            serde_json::to_string(&g.parse_rule_public("KV", r#""x":1"#).unwrap()).unwrap(),
+            r#"{"type":"KV","children":[{"type":"ITEM","children":[{"type":"NUMBER","raw":"1"}]}]}"#,
+        );
+        assert!(g.parse(r#""x":1"#).is_err());
+        match g.parse_rule_public("KV", r#""x":1,"#) {
+            Err(ParseError::Token { start, msg, .. }) => {
+                assert_eq!((start, msg.as_str()), (5, "expected EOF"))
+            }
+            r => panic!("expected token error, got {:?}", r),
+        }
+        match g.parse_rule_public("VALUE", "1") {
+            Err(ParseError::Grammar(msg)) => assert_eq!(msg, "no rule named 'VALUE'"),
+            r => panic!("expected grammar error, got {:?}", r),
+        }
+    }
+    #[test]
+    fn complete_partial_input() {
+        let g = mini_json_grammar();
+        assert_eq!(g.complete("[1,").unwrap(), vec!["NUMBER", "[", "{"]);
+        assert_eq!(g.complete("[1").unwrap(), vec![",", "]"]);
+        assert_eq!(g.complete(r#"{"a""#).unwrap(), vec![":"]);
+        assert!(g.complete("[1]").unwrap().is_empty());
+        assert!(g.complete("[1]]").is_err());
+        assert!(g.complete("[?").is_err());
+    }
+    #[test]
+    fn expected_at_offset() {
+        let g = mini_json_grammar();
+        let input = r#"{"a":[1,2],"b":3}"#;
+        assert_eq!(g.expected_at(input, 1), vec!["\"", "}"]);
+        assert_eq!(g.expected_at(input, 0), vec!["NUMBER", "[", "{"]);
+        assert_eq!(g.expected_at(input, 5), vec!["NUMBER", "[", "{"]);
+        assert_eq!(g.expected_at(input, 7), vec![",", "]"]);
+        assert!(g.expected_at(input, input.len()).is_empty());
+        // the rest of the input doesn't have to be valid
+        assert_eq!(g.expected_at("{?", 1), vec!["\"", "}"]);
+        assert!(g.expected_at("{]", 2).is_empty());
+        assert!(g.expected_at("?{", 2).is_empty());
+    }
+    #[test]
+    fn multibyte_positions() {
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                production: SymbolType::seq(vec![
+                    SymbolType::rep(SymbolType::leaf("word")),
+                    SymbolType::eof(),
+                ]),
+                order: 0,
+                label: None,
+            }],
+            atoms: vec![
+                Atom::Matched {
+                    name: "word".into(),
+                    m: Box::new(Regex::new(r"\p{Alphabetic}+").unwrap()),
+                },
+                Atom::Matched {
+                    name: "num".into(),
+                    m: Box::new(Regex::new(r"\d+").unwrap()),
+                },
+            ],
+            options: ParseOptions {
+                ignore_whitespace: true,
+                ignore_newline: true,
+                ..ParseOptions::default()
+            },
+            ..Grammar::default()
+        };
+        let starts = g
+            .tokenize("héllo wörld\n42")
+            .unwrap()
+            .iter()
+            .map(|l| l.start)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 7, 14]);
+
+        let input = "héllo wörld\n🦀 42";
+        match g.parse(input) {
+            Err(ParseError::Lexem(pos, msg)) => {
+                assert_eq!(pos, 14);
+                assert_eq!(msg, "no atom matches the input near '🦀 42'");
+                assert_eq!(byte_to_char_offset(input, pos), 12);
+                assert_eq!(line_column(input, pos), (2, 1));
+            }
+            r => panic!("expected lexem error, got {:?}", r),
+        }
+        let err = g.parse("wörld 42").unwrap_err();
+        assert_eq!(err.position(), Some(7));
+        assert_eq!(byte_to_char_offset("wörld 42", 7), 6);
+        assert_eq!(line_column("wörld 42", 7), (1, 7));
+
+        assert_eq!(byte_to_char_offset(input, 0), 0);
+        assert_eq!(byte_to_char_offset(input, 3), 2);
+        assert_eq!(
+            byte_to_char_offset(input, input.len()),
+            input.chars().count()
+        );
+    }
+    #[test]
+    fn line_column_tab_stops() {
+        let input = "a\tb\n\t\tc\tx";
+        assert_eq!(line_column(input, 2), (1, 3));
+        assert_eq!(line_column_with_tabs(input, 2, 4), (1, 5));
+        assert_eq!(line_column_with_tabs(input, 2, 8), (1, 9));
+        let x = input.find('x').unwrap();
+        assert_eq!(line_column(input, x), (2, 5));
+        assert_eq!(line_column_with_tabs(input, x, 4), (2, 13));
+        assert_eq!(line_column_with_tabs(input, x, 0), (2, 5));
+    }
+    #[test]
+    fn line_column_with_crlf() {
+        let input = "ab\r\ncd\r\né x";
+        assert_eq!(line_column(input, 0), (1, 1));
+        assert_eq!(line_column(input, input.find('d').unwrap()), (2, 2));
+        assert_eq!(line_column(input, input.find('x').unwrap()), (3, 3));
+        assert_eq!(line_column(input, 2), (1, 3));
+        assert_eq!(line_column(input, 3), (1, 3));
+    }
+    #[cfg(all(feature = "std", feature = "tracing"))]
+    #[test]
+    fn nested_trace_spans() {
+        use std::sync::{Arc, Mutex};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{field, Event, Metadata};
+
+        /// Writes every span as an indented line with its field values
+        #[derive(Default)]
+        struct Indented {
+            spans: Mutex<Vec<String>>,
+            depth: Mutex<usize>,
+            lines: Arc<Mutex<Vec<String>>>,
+        }
+        struct Fields<'a>(&'a mut String);
+        impl field::Visit for Fields<'_> {
+            fn record_debug(&mut self, _: &field::Field, value: &dyn fmt::Debug) {
+                self.0.push_str(&format!(" {:?}", value));
+            }
+            fn record_str(&mut self, _: &field::Field, value: &str) {
+                self.0.push_str(&format!(" {}", value));
+            }
+        }
+        impl tracing::Subscriber for Indented {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut line = span.metadata().name().to_string();
+                span.record(&mut Fields(&mut line));
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(line);
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, span: &Id) {
+                let mut depth = self.depth.lock().unwrap();
+                let line = &self.spans.lock().unwrap()[span.into_u64() as usize - 1];
+                let indented = format!("{}{}", "  ".repeat(*depth), line);
+                self.lines.lock().unwrap().push(indented);
+                *depth += 1;
+            }
+            fn exit(&self, _: &Id) {
+                *self.depth.lock().unwrap() -= 1;
+            }
+        }
+
+        let g = crate::grammar! {
+            START -> ( num ( pluss num )* );
+            >pluss -> r"\+";
+            >num -> r"\d+";
+        };
+        let subscriber = Indented::default();
+        let lines = subscriber.lines.clone();
+        tracing::subscriber::with_default(subscriber, || g.parse("1+2").unwrap());
+        assert_eq!(
+            lines.lock().unwrap().join("\n"),
+            "rule START\n\
+             \x20 symbol ( )\n\
+             \x20   symbol num\n\
+             \x20   symbol ( )*\n\
+             \x20     symbol ( )\n\
+             \x20       symbol pluss\n\
+             \x20       symbol num"
+        );
+    }
+    #[test]
+    fn productions_for_rule() {
+        let g = mini_json_grammar();
+        assert_eq!(
+            g.productions_for("ITEM"),
+            vec![
+                &SymbolType::rule("OBJ"),
+                &SymbolType::rule("LIST"),
+                &SymbolType::lexem("NUMBER")
+            ]
+        );
+        assert_eq!(g.productions_for("START"), vec![&SymbolType::rule("ITEM")]);
+        assert!(g.productions_for("MISSING").is_empty());
+    }
+    #[test]
+    fn single_char_fast_path() {
+        let tokens = |g: &Grammar, input: &str, fast: bool| {
+            let mut lexems = Lexem::iter(g, input);
+            if fast {
+                assert!(!lexems.char_atoms.is_empty());
+            } else {
+                lexems.char_atoms.to_mut().clear();
+            }
+            let tokens = lexems
+                .by_ref()
+                .map(|l| (l.t, l.raw, l.start))
+                .collect::<Vec<_>>();
+            (tokens, lexems.ok.map_err(|e| e.to_string()))
+        };
+        let brainfuck = crate::grammar! {
+            START -> ( OP ( START )? );
+            OP -> ( lb START rb );
+            OP -> ( ( pluss ) | ( minus ) );
+            OP -> ( ( dot ) | ( comma ) );
+            OP -> ( ( left ) | ( right ) );
+            >lb -> r"\[";
+            >rb -> r"\]";
+            >pluss -> r"\+";
+            >minus -> r"\-";
+            >dot -> r"\.";
+            >comma -> ",";
+            >left -> "[<]";
+            >right -> "[>]";
+        };
+        assert_eq!(brainfuck.char_atoms().keys().collect::<String>(), "+,-.[]");
+        let input = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.\
+                     >>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(
+            tokens(&brainfuck, input, true),
+            tokens(&brainfuck, input, false)
+        );
+        assert_eq!(
+            tokens(&brainfuck, "+x", true),
+            tokens(&brainfuck, "+x", false)
+        );
+
+        // atoms declared before a single character can still match first
+        let g = crate::grammar! {
+            START -> ( num )*;
+            >num -> r"[+-]?\d+";
+            >inc -> r"\+\+";
+            >pluss -> r"\+";
+            >minus -> r"\-";
+        };
+        assert_eq!(g.char_atoms().get(&'+'), Some(&vec![0, 1, 2]));
+        assert_eq!(g.char_atoms().get(&'-'), Some(&vec![0, 3]));
+        let input = "+++1-2--";
+        let (fast, _) = tokens(&g, input, true);
+        assert_eq!(
+            fast.iter().map(|(t, _, _)| t.as_str()).collect::<Vec<_>>(),
+            vec!["inc", "num", "num", "minus", "minus"]
+        );
+        assert_eq!(tokens(&g, input, true), tokens(&g, input, false));
+
+        // built once, and again for changed atoms
+        assert!(matches!(Lexem::iter(&g, "").char_atoms, Cow::Borrowed(_)));
+        let mut g = g;
+        g.atoms.insert(0, Atom::Simple { name: "-".into() });
+        let lexems = Lexem::iter(&g, "");
+        assert!(matches!(lexems.char_atoms, Cow::Owned(_)));
+        assert_eq!(lexems.char_atoms.get(&'-'), Some(&vec![0]));
+
+        // an atom changed under the same name
+        let mut g = crate::grammar! {
+            START -> ( ( pluss ) | ( inc ) )*;
+            >pluss -> r"\+";
+            >inc -> r"\+\+";
+        };
+        let types = |g: &Grammar| {
+            g.tokenize("++")
+                .map(|l| l.into_iter().map(|l| l.t).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            types(&g),
+            Ok(vec!["pluss".to_string(), "pluss".to_string()])
+        );
+        g.atoms[0] = crate::macros::__grammar_atom("pluss", r"\+x");
+        assert_eq!(types(&g), Ok(vec!["inc".to_string()]));
+    }
+    #[test]
+    fn counted_repetitions() {
+        type ST = SymbolType;
+        let g = Grammar {
+            options: ParseOptions {
+                ignore_whitespace: true,
+                bubble_intermediate: true,
+                ..ParseOptions::default()
+            },
+            rules: vec![
+                Rule {
+                    name: "START".into(),
+                    order: 0,
+                    label: None,
+                    production: ST::seq(vec![ST::rule("LIST"), ST::lexem(".")]),
+                },
+                Rule {
+                    name: "LIST".into(),
+                    order: 0,
+                    label: None,
+                    production: ST::rep(ST::seq(vec![ST::leaf("item"), ST::lexem(";")])),
+                },
+            ],
+            atoms: vec![
+                Atom::Matched {
+                    name: "item".into(),
+                    m: Box::new(Regex::new("[a-z]+").unwrap()),
                 },
+                Atom::Simple { name: ";".into() },
+                Atom::Simple { name: ".".into() },
             ],
+            counted: vec!["LIST".into()],
+            ..Grammar::default()
+        };
+        assert!(g.validate().is_empty());
+        assert!(g.to_string().lines().any(|l| l == "@count LIST"));
+        // the counted node is kept when bubbling up, START isn't
+        match g.parse("a; b; c; .").unwrap() {
+            AST::Node {
+                t, children, count, ..
+            } => {
+                assert_eq!(t, "LIST");
+                assert_eq!(children.len(), 3);
+                assert_eq!(count, Some(3));
+            }
+            l => panic!("expected a node, found {:?}", l),
+        }
+        assert_eq!(
+            serde_json::to_string(&g.parse("a; .").unwrap()).unwrap(),
+            r#"{"type":"LIST","children":[{"type":"item","raw":"a"}],"count":1}"#
+        );
+        // counted rules are kept by `minimize`
+        let mut minimized = g.clone();
+        minimized.minimize();
+        assert_eq!(minimized.rule_names(), vec!["START", "LIST"]);
+        assert_eq!(
+            minimized.parse("a; b; .").unwrap(),
+            g.parse("a; b; .").unwrap()
+        );
+
+        let g = Grammar {
+            counted: vec!["LIST".into(), "OTHER".into()],
+            ..g
         };
-        assert!(g.parse(&"{}".into()).is_ok());
-        assert!(g.parse(&"[]".into()).is_ok());
-        assert!(g.parse(&r#"{"field":12}"#.into()).is_ok());
-        assert!(g.parse(&r#"{"fieldA":[1,2,3],"fieldB":{}}"#.into()).is_ok());
-        assert!(g.parse(&"[{},12,[[]]]".into()).is_ok());
-        assert!(g.parse(&"[".into()).is_err());
-        assert!(g.parse(&"[{{}}]".into()).is_err());
-        assert!(g.parse(&r#"{"field"}"#.into()).is_err());
+        assert_eq!(g.validate(), vec!["undefined rule 'OTHER' is counted"]);
+    }
+    #[test]
+    fn production_indices() {
+        let g = mini_json_grammar_with_leaves();
+        let items = |g: &Grammar, input: &str| {
+            g.parse(input)
+                .unwrap()
+                .descendants()
+                .filter_map(|ast| match ast {
+                    AST::Node { t, production, .. } if t == "ITEM" => Some(*production),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let input = r#"[{"a":1},[]]"#;
+        assert_eq!(items(&g, input), vec![None; 4]);
+
+        let g = g.with_options(ParseOptions {
+            record_production_index: true,
+            ..ParseOptions::default()
+        });
+        // the outer LIST, the OBJ and the NUMBER in it, then the empty LIST
+        assert_eq!(items(&g, input), vec![Some(1), Some(0), Some(2), Some(1)]);
+        assert_eq!(
+            serde_json::to_string(&g.parse("1").unwrap()).unwrap(),
+            r#"{"type":"START","children":[{"type":"ITEM","children":[{"type":"NUMBER","raw":"1"}],"production":2}],"production":0}"#
+        );
     }
 }
@@ -0,0 +1,266 @@
+use crate::grammar::unescape_literal;
+use crate::parsing::*;
+use std::collections::HashMap;
+
+/// Texts tried as samples of regex atoms, the first one lexed as the atom is used
+const PLACEHOLDERS: &[&str] = &[
+    "0", "1", "42", "a", "x", "foo", "A", "Foo", "_", "0.5", "1e3", "true", "false", "null",
+    "\"a\"", "'a'", " ", "\n", "\t",
+];
+
+impl Grammar {
+    /// Generate a random input accepted by the grammar, for property testing code using it.
+    /// `rng(n)` should return a random number below `n`. Productions, optionals and
+    /// repetitions are chosen at random until `max_depth` rules are nested, after that the
+    /// shortest alternatives are used.
+    ///
+    /// Lexems are separated by a space if `ignore_whitespace` is set, and written back to back
+    /// otherwise. Regex atoms are sampled from a fixed list of common texts, atoms matching
+    /// none of them give an error.
+    pub fn sample(
+        &self,
+        rng: &mut dyn FnMut(usize) -> usize,
+        max_depth: usize,
+    ) -> ParseResult<String> {
+        let mut sampler = Sampler {
+            grammar: self,
+            rng,
+            max_depth,
+            heights: self.heights(),
+            lexems: Vec::new(),
+        };
+        sampler.rule("START", 0)?;
+        let separator = if self.options.ignore_whitespace {
+            " "
+        } else {
+            ""
+        };
+        Ok(sampler.lexems.join(separator))
+    }
+    /// Smallest number of nested rules needed to derive only lexems from each rule
+    fn heights(&self) -> HashMap<&str, usize> {
+        let mut heights: HashMap<&str, usize> = HashMap::new();
+        loop {
+            let mut changed = false;
+            for rule in self.rules.iter() {
+                if let Some(h) = height(&rule.production, &heights) {
+                    let current = heights.entry(&rule.name).or_insert(usize::MAX);
+                    if h + 1 < *current {
+                        *current = h + 1;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return heights;
+            }
+        }
+    }
+}
+
+/// Nested rules needed to derive only lexems from `s`, if it is known from `heights`
+fn height(s: &SymbolType, heights: &HashMap<&str, usize>) -> Option<usize> {
+    match s {
+        SymbolType::Symbol(Symbol::AST(rule)) => heights.get(rule.as_str()).copied(),
+        SymbolType::Symbol(_) => Some(0),
+        SymbolType::Group(g) => g
+            .iter()
+            .map(|s| height(s, heights))
+            .try_fold(0, |max, h| h.map(|h| max.max(h))),
+        SymbolType::Switch(a, b) => match (height(a, heights), height(b, heights)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        },
+        SymbolType::Optional(_) | SymbolType::Repeated(_) => Some(0),
+    }
+}
+
+struct Sampler<'a> {
+    grammar: &'a Grammar,
+    rng: &'a mut dyn FnMut(usize) -> usize,
+    max_depth: usize,
+    heights: HashMap<&'a str, usize>,
+    lexems: Vec<String>,
+}
+
+impl Sampler<'_> {
+    fn rule(&mut self, name: &str, depth: usize) -> ParseResult<()> {
+        let rules = self.grammar.rules_named(name);
+        if rules.is_empty() {
+            return Err(ParseError::NoMatch(format!("no rule named '{}'", name)));
+        }
+        let production = if depth < self.max_depth {
+            &rules[(self.rng)(rules.len())].production
+        } else {
+            let heights = &self.heights;
+            &rules
+                .iter()
+                .min_by_key(|r| height(&r.production, heights).unwrap_or(usize::MAX))
+                .unwrap()
+                .production
+        };
+        self.symbol(production, depth + 1)
+    }
+    fn symbol(&mut self, s: &SymbolType, depth: usize) -> ParseResult<()> {
+        let deep = depth >= self.max_depth;
+        match s {
+            SymbolType::Symbol(Symbol::AST(rule)) => self.rule(rule, depth)?,
+            SymbolType::Symbol(Symbol::Lexem { t, .. }) => {
+                let text = self.lexem(t)?;
+                self.lexems.push(text);
+            }
+            SymbolType::Symbol(Symbol::Any) => {
+                let atoms = &self.grammar.atoms;
+                let t = atoms[(self.rng)(atoms.len())].name().clone();
+                let text = self.lexem(&t)?;
+                self.lexems.push(text);
+            }
+            SymbolType::Symbol(Symbol::EOF) => {}
+            SymbolType::Group(g) => {
+                for s in g.iter() {
+                    self.symbol(s, depth)?;
+                }
+            }
+            SymbolType::Switch(a, b) => {
+                let heights = &self.heights;
+                let first = if deep {
+                    height(a, heights).unwrap_or(usize::MAX)
+                        <= height(b, heights).unwrap_or(usize::MAX)
+                } else {
+                    (self.rng)(2) == 0
+                };
+                self.symbol(if first { a } else { b }, depth)?;
+            }
+            SymbolType::Optional(o) => {
+                if !deep && (self.rng)(2) == 0 {
+                    self.symbol(o, depth)?;
+                }
+            }
+            SymbolType::Repeated(r) => {
+                let times = if deep { 0 } else { (self.rng)(3) };
+                for _ in 0..times {
+                    self.symbol(r, depth)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn lexem(&mut self, t: &str) -> ParseResult<String> {
+        let atom = self
+            .grammar
+            .atoms
+            .iter()
+            .find(|a| a.name() == t)
+            .ok_or_else(|| ParseError::NoMatch(format!("no atom named '{}'", t)))?;
+        match atom {
+            Atom::Simple { name } => Ok(name.clone()),
+            Atom::Balanced { open, close, .. } => Ok(format!("{}{}", open, close)),
+            Atom::Integer { min, max, .. } => {
+                // the distance between any two i128 fits in a u128
+                let span = max.abs_diff(*min).min(100) as usize;
+                Ok((min + (self.rng)(span + 1) as i128).to_string())
+            }
+            Atom::Matched { m, .. } => match m.pattern().and_then(unescape_literal) {
+                Some(literal) => Ok(literal),
+                None => self.placeholder(t),
+            },
+        }
+    }
+    fn placeholder(&self, t: &str) -> ParseResult<String> {
+        PLACEHOLDERS
+            .iter()
+            .find(|p| {
+                self.grammar
+                    .match_input(p, None)
+                    .map(|(l, i)| l.t == t && i == p.len())
+                    .unwrap_or(false)
+            })
+            .map(|p| p.to_string())
+            .ok_or_else(|| ParseError::Grammar(format!("can't generate text for atom '{}'", t)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::RAW_GRAMMAR_SUM;
+
+    /// Deterministic xorshift generator, to not depend on a random number crate
+    fn rng(mut state: u64) -> impl FnMut(usize) -> usize {
+        move |n| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn samples_parse() {
+        // with parentheses, so samples nest
+        let g = Grammar::compile(&format!(
+            "@options ignore_whitespace\n{}\nNUMBER -> ( lp SUM rp )\n>lp -> '('\n>rp -> ')'",
+            RAW_GRAMMAR_SUM
+        ))
+        .unwrap();
+        let mut rng = rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..100 {
+            let sample = g.sample(&mut rng, 5).unwrap();
+            assert!(
+                g.parse(&sample).is_ok(),
+                "could not parse sample '{}'",
+                sample
+            );
+        }
+        assert_eq!(g.sample(&mut rng, 0).unwrap(), "0");
+    }
+    #[test]
+    fn samples_of_recursive_rules_terminate() {
+        let g = Grammar::compile(
+            r#"
+            START -> ( OP ( START )? )
+            OP -> ( lb START rb )
+            OP -> ( pluss )
+            >lb -> '['
+            >rb -> ']'
+            >pluss -> '+'
+            "#,
+        )
+        .unwrap();
+        let mut rng = rng(7);
+        for _ in 0..100 {
+            let sample = g.sample(&mut rng, 8).unwrap();
+            assert!(
+                g.parse(&sample).is_ok(),
+                "could not parse sample '{}'",
+                sample
+            );
+        }
+    }
+    #[test]
+    fn samples_of_full_range_integers() {
+        let g = Grammar {
+            rules: vec![Rule {
+                name: "START".into(),
+                order: 0,
+                label: None,
+                production: SymbolType::leaf("n"),
+            }],
+            atoms: vec![Atom::Integer {
+                name: "n".into(),
+                min: i128::MIN,
+                max: i128::MAX,
+            }],
+            ..Grammar::default()
+        };
+        let mut rng = rng(3);
+        for _ in 0..10 {
+            let sample = g.sample(&mut rng, 1).unwrap();
+            assert!(
+                g.parse(&sample).is_ok(),
+                "could not parse sample '{}'",
+                sample
+            );
+        }
+    }
+}